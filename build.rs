@@ -1,4 +1,32 @@
+use std::{fs, path::Path, process::Command};
+
 fn main() {
     tonic_build::compile_protos("proto/axidraw_over_http.proto")
         .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
+
+    // Empty when built without the repository, as in Docker builds, where
+    // .dockerignore leaves .git out of the build context.
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash.trim());
+
+    // HEAD only changes when switching branches, so also watch the branch it
+    // points to, and the reflog, which every commit and checkout appends to.
+    let mut watched = vec![".git/HEAD".to_string(), ".git/logs/HEAD".to_string()];
+    if let Ok(head) = fs::read_to_string(".git/HEAD") {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            watched.push(format!(".git/{}", reference));
+        }
+    }
+
+    // Cargo reruns on every build if a watched file is missing.
+    for path in watched.iter().filter(|path| Path::new(path).exists()) {
+        println!("cargo:rerun-if-changed={}", path);
+    }
 }
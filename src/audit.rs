@@ -0,0 +1,110 @@
+use crate::{auth, axidraw_over_http::AuditEntry};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tonic::Request;
+
+/// Number of entries kept in memory for GetAuditLog.
+const RETAINED_ENTRIES: usize = 1000;
+
+/// Who a request came from, kept separately so that it outlives the request.
+#[derive(Clone, Debug, Serialize)]
+pub struct Origin {
+    pub caller: String,
+    pub peer: Option<String>,
+}
+
+impl Origin {
+    pub fn of<T>(request: &Request<T>) -> Origin {
+        Origin {
+            caller: auth::caller(request)
+                .map(|caller| caller.name.clone())
+                .unwrap_or_else(|| "unknown".to_string()),
+            peer: request.remote_addr().map(|address| address.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct Record {
+    timestamp: SystemTime,
+    action: String,
+    caller: String,
+    peer: Option<String>,
+    denied: bool,
+}
+
+/// Records control calls, appending them as JSON lines to the audit file if
+/// there is one.
+pub struct AuditRecorder {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    records: VecDeque<Record>,
+}
+
+impl AuditRecorder {
+    pub fn open(path: Option<&Path>) -> io::Result<AuditRecorder> {
+        let file = path.map(open_file).transpose()?;
+
+        Ok(AuditRecorder {
+            path: path.map(Path::to_path_buf),
+            file,
+            records: VecDeque::new(),
+        })
+    }
+
+    /// Reopens the audit file, after it's been moved by log rotation.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            self.file = Some(open_file(path)?);
+        }
+
+        Ok(())
+    }
+
+    pub fn record(&mut self, origin: &Origin, action: &str, denied: bool) {
+        let record = Record {
+            timestamp: SystemTime::now(),
+            action: action.to_string(),
+            caller: origin.caller.clone(),
+            peer: origin.peer.clone(),
+            denied,
+        };
+
+        if let Some(file) = &mut self.file {
+            let line = serde_json::to_string(&record).unwrap_or_default();
+
+            if let Err(error) = writeln!(file, "{}", line) {
+                println!("Failed to write audit log: {}", error);
+            }
+        }
+
+        if self.records.len() == RETAINED_ENTRIES {
+            self.records.pop_front();
+        }
+
+        self.records.push_back(record);
+    }
+
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.records
+            .iter()
+            .map(|record| AuditEntry {
+                timestamp: Some(record.timestamp.into()),
+                action: record.action.clone(),
+                caller: record.caller.clone(),
+                peer: record.peer.clone(),
+                denied: record.denied,
+            })
+            .collect()
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
@@ -0,0 +1,106 @@
+use std::sync::{Arc, RwLock};
+use tonic::{metadata::MetadataMap, Request, Status};
+
+/// What a client is allowed to do. Viewers can only read state, while
+/// operators can also control plotting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Viewer,
+    Operator,
+}
+
+/// Who made a request, attached to it by the interceptor.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub role: Role,
+    /// Name given to the token, or the role if it has none.
+    pub name: String,
+}
+
+/// Bearer tokens for each role, optionally prefixed with `NAME:` to identify
+/// their holder. Everyone is an operator if there are none.
+#[derive(Clone, Default)]
+pub struct Tokens {
+    pub operator: Vec<String>,
+    pub viewer: Vec<String>,
+}
+
+/// Tokens that can be replaced while the server is running.
+pub type SharedTokens = Arc<RwLock<Tokens>>;
+
+impl Tokens {
+    /// Returns the caller for an `authorization` header, or `None` if it isn't
+    /// accepted.
+    pub fn caller_for(&self, authorization: Option<&str>) -> Option<Caller> {
+        if self.operator.is_empty() && self.viewer.is_empty() {
+            return Some(Caller {
+                role: Role::Operator,
+                name: "anonymous".to_string(),
+            });
+        }
+
+        let token = authorization?.strip_prefix("Bearer ")?.trim();
+
+        let find = |configured: &[String], role: Role, default_name: &str| {
+            configured.iter().find_map(|configured| {
+                let (name, secret) = configured
+                    .split_once(':')
+                    .unwrap_or((default_name, configured));
+
+                (secret == token).then(|| Caller {
+                    role,
+                    name: name.to_string(),
+                })
+            })
+        };
+
+        find(&self.operator, Role::Operator, "operator")
+            .or_else(|| find(&self.viewer, Role::Viewer, "viewer"))
+    }
+
+    fn caller_from_metadata(&self, metadata: &MetadataMap) -> Option<Caller> {
+        let authorization = metadata
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+
+        self.caller_for(authorization)
+    }
+}
+
+/// Rejects requests without a valid token and tags the rest with their caller
+/// for [`require`].
+pub fn interceptor(
+    tokens: SharedTokens,
+) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |mut request| {
+        let caller = tokens
+            .read()
+            .unwrap()
+            .caller_from_metadata(request.metadata())
+            .ok_or_else(|| Status::unauthenticated("Missing or invalid token"))?;
+
+        request.extensions_mut().insert(caller);
+        Ok(request)
+    }
+}
+
+pub fn caller<T>(request: &Request<T>) -> Option<&Caller> {
+    request.extensions().get::<Caller>()
+}
+
+pub fn require<T>(request: &Request<T>, role: Role) -> Result<(), Status> {
+    match caller(request) {
+        Some(caller) if caller.role >= role => Ok(()),
+        _ => Err(Status::permission_denied(format!(
+            "Requires {:?} role",
+            role
+        ))),
+    }
+}
+
+/// Builds a request for calling the service directly on behalf of `caller`.
+pub fn request_as<T>(message: T, caller: Caller) -> Request<T> {
+    let mut request = Request::new(message);
+    request.extensions_mut().insert(caller);
+    request
+}
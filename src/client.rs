@@ -0,0 +1,212 @@
+use crate::{
+    axidraw_over_http::{
+        axidraw_over_http_client::AxidrawOverHttpClient, Empty, FileChunk, FilePath, JobId,
+        TerminalInput, UploadId, UploadProgress,
+    },
+    upload,
+};
+use std::{error::Error, fs, path::PathBuf};
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, BufReader, SeekFrom};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Code, Request};
+
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Connection details for the subcommands that talk to a running server.
+#[derive(clap::Args)]
+pub struct Connection {
+    /// Address of the server.
+    #[arg(long, default_value = "http://[::1]:7878")]
+    pub server: String,
+    /// Bearer token, if the server requires one.
+    #[arg(long)]
+    token: Option<String>,
+}
+
+impl Connection {
+    pub fn request<T>(&self, message: T) -> Result<Request<T>, Box<dyn Error>> {
+        let mut request = Request::new(message);
+
+        if let Some(token) = &self.token {
+            request
+                .metadata_mut()
+                .insert("authorization", format!("Bearer {}", token).parse()?);
+        }
+
+        Ok(request)
+    }
+}
+
+pub async fn export_buffer(
+    connection: Connection,
+    output: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let mut client = AxidrawOverHttpClient::connect(connection.server.clone()).await?;
+    let commands = client
+        .export_buffer(connection.request(Empty {})?)
+        .await?
+        .into_inner()
+        .commands;
+
+    match output {
+        Some(output) => fs::write(output, commands)?,
+        None => print!("{}", commands),
+    }
+
+    Ok(())
+}
+
+pub async fn load_file(connection: Connection, path: String) -> Result<(), Box<dyn Error>> {
+    let mut client = AxidrawOverHttpClient::connect(connection.server.clone()).await?;
+    let id = client
+        .load_file(connection.request(FilePath { path })?)
+        .await?
+        .into_inner()
+        .id;
+
+    println!("Queued job {}", id);
+
+    Ok(())
+}
+
+/// Uploads a local command file, continuing an earlier upload of the same
+/// file if there was one.
+pub async fn upload(connection: Connection, path: PathBuf) -> Result<(), Box<dyn Error>> {
+    let mut client = AxidrawOverHttpClient::connect(connection.server.clone()).await?;
+
+    let name = path
+        .file_name()
+        .ok_or("Expected a file")?
+        .to_string_lossy()
+        .into_owned();
+    let total_size = fs::metadata(&path)?.len();
+    let sha256 = upload::sha256(&path).await?;
+    // Named after the contents, so running again picks up where it stopped.
+    let upload_id = sha256[..32].to_string();
+
+    let offset = match client
+        .get_upload(connection.request(UploadId {
+            upload_id: upload_id.clone(),
+        })?)
+        .await
+    {
+        Ok(progress) => {
+            let progress = progress.into_inner();
+
+            if progress.complete {
+                println!("Already uploaded");
+                print_uploaded(progress);
+                return Ok(());
+            }
+
+            progress.received
+        }
+        Err(status) if status.code() == Code::NotFound => 0,
+        Err(status) => return Err(status.into()),
+    };
+
+    let mut file = tokio::fs::File::open(&path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let (sender, receiver) = mpsc::channel(4);
+
+    tokio::spawn(async move {
+        let mut offset = offset;
+        let mut data = vec![0; UPLOAD_CHUNK_SIZE];
+
+        loop {
+            let length = match file.read(&mut data).await {
+                Ok(length) if length > 0 || offset == 0 => length,
+                _ => break,
+            };
+
+            let chunk = FileChunk {
+                upload_id: upload_id.clone(),
+                name: name.clone(),
+                total_size,
+                sha256: sha256.clone(),
+                offset,
+                data: data[..length].to_vec(),
+            };
+            offset += length as u64;
+
+            if sender.send(chunk).await.is_err() || length == 0 {
+                break;
+            }
+        }
+    });
+
+    let mut progress = client
+        .upload_file(connection.request(ReceiverStream::new(receiver))?)
+        .await?
+        .into_inner();
+
+    while let Some(progress) = progress.message().await? {
+        if progress.complete {
+            eprintln!();
+            print_uploaded(progress);
+            return Ok(());
+        }
+
+        eprint!("\r{} / {} bytes", progress.received, progress.total_size);
+    }
+
+    Err("Upload stopped before it finished; run again to continue".into())
+}
+
+fn print_uploaded(progress: UploadProgress) {
+    println!(
+        "Saved {} commands to {}",
+        progress.command_count,
+        progress.path.unwrap_or_default()
+    );
+
+    if let Some(job_id) = progress.job_id {
+        println!(
+            "Held as job {}; run release-job {} to plot it",
+            job_id, job_id
+        );
+    }
+}
+
+pub async fn release_job(connection: Connection, id: u64) -> Result<(), Box<dyn Error>> {
+    let mut client = AxidrawOverHttpClient::connect(connection.server.clone()).await?;
+    client
+        .release_job(connection.request(JobId { id })?)
+        .await?;
+
+    println!("Queued job {}", id);
+
+    Ok(())
+}
+
+/// Sends lines from stdin to the EBB, printing each response.
+pub async fn terminal(connection: Connection) -> Result<(), Box<dyn Error>> {
+    let mut client = AxidrawOverHttpClient::connect(connection.server.clone()).await?;
+
+    let (sender, receiver) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(io::stdin()).lines();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            if sender.send(TerminalInput { line }).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut output = client
+        .terminal(connection.request(ReceiverStream::new(receiver))?)
+        .await?
+        .into_inner();
+
+    while let Some(output) = output.message().await? {
+        for line in output.response {
+            println!("{}", line);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use crate::{
+    auth::Tokens,
+    ebb::{self, Point},
+    servo::ServoProfile,
+    toolchange::ToolDefinition,
+    validation::MotionLimits,
+    workarea::WorkArea,
+};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+/// Settings that can be changed by reloading the config file, without
+/// restarting the server.
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub operator_tokens: Vec<String>,
+    pub viewer_tokens: Vec<String>,
+    pub max_buffer: Option<usize>,
+    pub high_watermark: Option<usize>,
+    pub low_watermark: Option<usize>,
+    pub webhooks: Vec<String>,
+    /// Work area presets, by name.
+    pub work_areas: BTreeMap<String, WorkArea>,
+    pub servo_profile: ServoProfile,
+    /// Pens that tool changes switch between, by name.
+    pub tools: BTreeMap<String, ToolDefinition>,
+    /// Where the carriage parks for tool changes, in mm from home.
+    pub tool_change_position: Point,
+    /// Whether moves are checked against the motion limits.
+    pub validate_motion: bool,
+    pub max_step_rate: Option<f64>,
+    pub max_acceleration: Option<f64>,
+}
+
+/// Options that are only read at startup, which the config file can't change.
+const RESTART_ONLY: &[&str] = &["log_file", "log_max_size", "log_rotate_hours", "log_keep"];
+
+/// JSON config file. Settings given here replace the ones from the command
+/// line.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    operator_tokens: Option<Vec<String>>,
+    viewer_tokens: Option<Vec<String>>,
+    max_buffer: Option<usize>,
+    high_watermark: Option<usize>,
+    low_watermark: Option<usize>,
+    webhooks: Option<Vec<String>>,
+    work_areas: Option<BTreeMap<String, WorkArea>>,
+    servo_profile: Option<ServoProfile>,
+    tools: Option<BTreeMap<String, ToolDefinition>>,
+    tool_change_position: Option<Point>,
+    validate_motion: Option<bool>,
+    max_step_rate: Option<f64>,
+    max_acceleration: Option<f64>,
+}
+
+impl Settings {
+    /// Applies the config file at `path` over these settings.
+    pub fn with_file(self, path: &Path) -> io::Result<Settings> {
+        let file: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+        if let Some(key) = RESTART_ONLY.iter().find(|key| file.get(key).is_some()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} requires a restart, with --{}",
+                    key,
+                    key.replace('_', "-")
+                ),
+            ));
+        }
+
+        let file: ConfigFile = serde_json::from_value(file)?;
+
+        if let Some(servo_profile) = &file.servo_profile {
+            servo_profile
+                .validate()
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        }
+
+        for (name, tool) in file.tools.iter().flatten() {
+            tool.validate().map_err(|error| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", name, error))
+            })?;
+        }
+
+        Ok(Settings {
+            operator_tokens: file.operator_tokens.unwrap_or(self.operator_tokens),
+            viewer_tokens: file.viewer_tokens.unwrap_or(self.viewer_tokens),
+            max_buffer: file.max_buffer.or(self.max_buffer),
+            high_watermark: file.high_watermark.or(self.high_watermark),
+            low_watermark: file.low_watermark.or(self.low_watermark),
+            webhooks: file.webhooks.unwrap_or(self.webhooks),
+            work_areas: file.work_areas.unwrap_or(self.work_areas),
+            servo_profile: file.servo_profile.unwrap_or(self.servo_profile),
+            tools: file.tools.unwrap_or(self.tools),
+            tool_change_position: file
+                .tool_change_position
+                .unwrap_or(self.tool_change_position),
+            validate_motion: file.validate_motion.unwrap_or(self.validate_motion),
+            max_step_rate: file.max_step_rate.or(self.max_step_rate),
+            max_acceleration: file.max_acceleration.or(self.max_acceleration),
+        })
+    }
+
+    /// Limits to check moves against, if validate_motion is on.
+    pub fn motion_limits(&self) -> Option<MotionLimits> {
+        self.validate_motion.then(|| MotionLimits {
+            max_step_rate: self.max_step_rate.unwrap_or(ebb::MAX_STEP_RATE),
+            max_acceleration: self.max_acceleration,
+        })
+    }
+
+    pub fn tokens(&self) -> Tokens {
+        Tokens {
+            operator: self.operator_tokens.clone(),
+            viewer: self.viewer_tokens.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn with_config(name: &str, contents: &str) -> io::Result<Settings> {
+        let path = std::env::temp_dir().join(format!("axidraw-config-{}-{}", name, process::id()));
+        fs::write(&path, contents).unwrap();
+
+        let settings = Settings::default().with_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        settings
+    }
+
+    #[test]
+    fn motion_limits_come_from_the_file() {
+        let settings = with_config(
+            "motion",
+            r#"{"validate_motion": true, "max_step_rate": 10000}"#,
+        )
+        .unwrap();
+        let motion_limits = settings.motion_limits().unwrap();
+
+        assert_eq!(motion_limits.max_step_rate, 10000.0);
+        assert_eq!(motion_limits.max_acceleration, None);
+    }
+
+    #[test]
+    fn startup_options_are_rejected() {
+        let Err(error) = with_config("logging", r#"{"log_keep": 3}"#) else {
+            panic!("log_keep was reloaded");
+        };
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "log_keep requires a restart, with --log-keep"
+        );
+    }
+}
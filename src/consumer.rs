@@ -0,0 +1,885 @@
+use crate::{
+    axidraw_over_http::{InFlightCommand, JobState, RunningStatus},
+    device::{Capabilities, DeviceStatus},
+    ebb, estimate,
+    events::Event,
+    interlock::Interlock,
+    jobs::Jobs,
+    preview::PathTracer,
+    servo::ServoProfile,
+    statistics::PlotStatistics,
+    toolchange::ToolChanges,
+    watermarks::Watermarks,
+    BufferEntry, ControlMessage, Layers, QueuedCommand, UrgentCommand, TRAVEL_SPEED,
+};
+use serialport::SerialPort;
+use std::{
+    collections::VecDeque,
+    io::{self, prelude::*, BufWriter, ErrorKind},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::{
+    broadcast::Sender,
+    mpsc::{UnboundedReceiver, UnboundedSender},
+    Mutex,
+};
+use tracing::instrument;
+
+const BUTTON_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the button is checked while waiting to be armed.
+const ARMING_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+pub const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lets the service interrupt a move with `ES` while the consumer is blocked
+/// waiting for the EBB. The consumer only writes commands with the lock held
+/// and no stop pending, so the `ES` response can't be mistaken for the
+/// response to another command.
+pub struct ImmediateStop {
+    writer: std::sync::Mutex<StopWriter>,
+}
+
+struct StopWriter {
+    serial_port: Box<dyn SerialPort>,
+    pending: bool,
+}
+
+impl ImmediateStop {
+    pub fn new(serial_port: Box<dyn SerialPort>) -> ImmediateStop {
+        ImmediateStop {
+            writer: std::sync::Mutex::new(StopWriter {
+                serial_port,
+                pending: false,
+            }),
+        }
+    }
+
+    pub fn request(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+
+        write_command(&*writer.serial_port, "ES")?;
+        writer.pending = true;
+
+        Ok(())
+    }
+}
+
+pub struct Consumer {
+    pub serial_port: Box<dyn SerialPort>,
+    pub command_buffer: Arc<Mutex<VecDeque<BufferEntry>>>,
+    pub urgent_buffer: Arc<Mutex<VecDeque<UrgentCommand>>>,
+    pub running_status: Arc<Mutex<RunningStatus>>,
+    pub breakpoint: Arc<Mutex<Option<String>>>,
+    pub layers: Arc<Mutex<Layers>>,
+    pub jobs: Arc<Mutex<Jobs>>,
+    pub plotted: Arc<Mutex<PathTracer>>,
+    pub events: Sender<Event>,
+    pub watermarks: Arc<Watermarks>,
+    pub device_status: Arc<Mutex<DeviceStatus>>,
+    pub capabilities: Capabilities,
+    pub statistics: Arc<Mutex<PlotStatistics>>,
+    /// Motor supply voltage below which plotting is paused.
+    pub low_voltage_threshold: Option<f64>,
+    /// How long to wait between jobs before starting the next by itself.
+    pub job_advance_delay: Option<Duration>,
+    pub motors_off_between_jobs: bool,
+    pub control_message_sender: UnboundedSender<ControlMessage>,
+    pub immediate_stop: Arc<ImmediateStop>,
+    /// The last two commands sent, which may still be moving or queued in the
+    /// EBB's FIFO when an `ES` arrives.
+    pub in_flight: VecDeque<String>,
+    /// Number of commands executed since startup.
+    pub sequence_number: u64,
+    /// Multiplier applied to the speed of moves from the buffer.
+    pub speed_factor: Arc<Mutex<f64>>,
+    pub interlock: Arc<Interlock>,
+    /// How long the buffer must be idle before the pen is raised and the
+    /// motors turned off.
+    pub idle_timeout: Option<Duration>,
+    /// Also cut servo power when parking.
+    pub idle_servo_off: bool,
+    pub last_activity: Instant,
+    /// Whether the pen was down before parking, while parked.
+    pub parked: Option<bool>,
+    /// The last `EM` command that enabled the motors, to restore after parking.
+    pub motor_mode: Option<String>,
+    /// When the moves sent so far should be finished.
+    pub motion_ends_at: Instant,
+    /// Round trip above which a command is logged as slow.
+    pub slow_command_threshold: Duration,
+    /// How long to wait for a response, beyond earlier moves finishing for
+    /// motion commands, unless the command has its own maximum.
+    pub fast_command_timeout: Option<Duration>,
+    pub motion_command_timeout: Option<Duration>,
+    /// Set by Step to run the next command while paused.
+    pub step: Arc<AtomicBool>,
+    /// The command last sent from the buffer, while the buffer is draining.
+    pub current_command: Arc<Mutex<Option<InFlightCommand>>>,
+    /// How the pen is raised and lowered when parking.
+    pub servo_profile: Arc<Mutex<ServoProfile>>,
+    pub tool_changes: Arc<Mutex<ToolChanges>>,
+}
+
+impl Consumer {
+    pub fn run(mut self, mut control_message_receiver: UnboundedReceiver<ControlMessage>) {
+        loop {
+            let control_message = control_message_receiver.blocking_recv().unwrap();
+
+            match control_message {
+                ControlMessage::CheckBuffer => self.drain_buffer(),
+                ControlMessage::CheckPower => {
+                    self.check_power_if_due();
+                    self.park_if_idle();
+                }
+                ControlMessage::SwitchPort(serial_port) => self.switch_port(serial_port),
+            }
+        }
+    }
+
+    fn drain_buffer(&mut self) {
+        let mut last_button_check = None;
+        let mut executed_command = false;
+
+        loop {
+            if let Err(error) = self.finish_immediate_stop() {
+                self.pause_with_error(format!("Serial error while stopping: {}", error));
+                break;
+            }
+
+            let urgent_command = self.urgent_buffer.blocking_lock().pop_front();
+
+            if let Some(UrgentCommand { command, responder }) = urgent_command {
+                match (self.execute(&command, 1.0), responder) {
+                    (result, Some(responder)) => {
+                        let error = result.as_ref().err().map(|error| error.to_string());
+                        let _ = responder.send(result);
+
+                        if let Some(error) = error {
+                            self.pause_with_error(format!(
+                                "Serial error while executing {}: {}",
+                                command.contents, error
+                            ));
+                            break;
+                        }
+                    }
+                    (Ok(_), None) => {}
+                    (Err(error), None) => {
+                        let message = format!(
+                            "Serial error while sending urgent {}: {}",
+                            command.contents, error
+                        );
+
+                        self.urgent_buffer
+                            .blocking_lock()
+                            .push_front(UrgentCommand {
+                                command,
+                                responder: None,
+                            });
+                        self.pause_with_error(message);
+                        break;
+                    }
+                }
+
+                continue;
+            }
+
+            let mut state = self.running_status.blocking_lock();
+            let mut buffer = self.command_buffer.clone().blocking_lock_owned();
+
+            let stepping = self.step.load(Ordering::SeqCst);
+
+            if (*state != RunningStatus::Running && !stepping) || buffer.is_empty() {
+                if executed_command && buffer.is_empty() {
+                    let _ = self.events.send(Event::BufferEmpty);
+                }
+
+                self.step.store(false, Ordering::SeqCst);
+                break;
+            }
+
+            if let Some(BufferEntry::JobStart(id)) = buffer.front() {
+                let jobs = self.jobs.blocking_lock();
+
+                if jobs
+                    .get(*id)
+                    .is_some_and(|job| job.is_waiting_for_start_time())
+                {
+                    break;
+                }
+            }
+
+            if let Some(BufferEntry::Command(command)) = buffer.front() {
+                if !self.interlock.allows(&command.contents) {
+                    drop(buffer);
+                    drop(state);
+
+                    // Wait here, so that a button press can arm the machine.
+                    if last_button_check.is_none() {
+                        let _ = self.is_button_pressed();
+                        last_button_check = Some(Instant::now());
+                    } else if self.is_button_pressed() {
+                        println!("Armed by button press");
+                        self.interlock.arm();
+                    } else {
+                        self.check_power_if_due();
+                        sleep(ARMING_CHECK_INTERVAL);
+                    }
+
+                    continue;
+                }
+            }
+
+            let entry = buffer.pop_front().unwrap();
+            self.watermarks.update(buffer.len());
+            drop(buffer);
+
+            match entry {
+                BufferEntry::Command(command) => {
+                    drop(state);
+                    self.step.store(false, Ordering::SeqCst);
+
+                    // The button state latches until queried, so clear any press
+                    // from before plotting started.
+                    if last_button_check.is_none() {
+                        let _ = self.is_button_pressed();
+                        last_button_check = Some(Instant::now());
+                    }
+
+                    // Commands are checked when queued, but the port may have
+                    // been switched to older firmware since.
+                    let name = ebb::parse(&command.contents).0;
+                    if name != "HM" && !self.capabilities.supports(&name) {
+                        let message =
+                            format!("{} is not supported by this firmware", command.contents);
+
+                        self.command_buffer
+                            .blocking_lock()
+                            .push_front(BufferEntry::Command(command));
+                        self.pause_with_error(message);
+                        break;
+                    }
+
+                    executed_command = true;
+
+                    *self.current_command.blocking_lock() = Some(InFlightCommand {
+                        contents: command.contents.clone(),
+                        label: command.label.clone(),
+                        sequence_id: command.sequence_id,
+                    });
+
+                    let speed_factor = *self.speed_factor.blocking_lock();
+
+                    if let Err(error) = self.execute(&command, speed_factor) {
+                        let message =
+                            format!("Serial error while sending {}: {}", command.contents, error);
+
+                        self.command_buffer
+                            .blocking_lock()
+                            .push_front(BufferEntry::Command(command));
+                        self.pause_with_error(message);
+                        break;
+                    }
+
+                    if let Some(job) = self.jobs.blocking_lock().current_mut() {
+                        job.executed_count += 1;
+                    }
+
+                    self.check_power_if_due();
+
+                    if last_button_check.is_some_and(|last_button_check| {
+                        last_button_check.elapsed() >= BUTTON_CHECK_INTERVAL
+                    }) {
+                        last_button_check = Some(Instant::now());
+
+                        if self.is_button_pressed() {
+                            println!("Pause button pressed");
+
+                            *self.running_status.blocking_lock() = RunningStatus::Paused;
+                            let _ = self.events.send(Event::ButtonPressed);
+                        }
+                    }
+                }
+                BufferEntry::Breakpoint(label) => {
+                    println!("Reached breakpoint: {}", label);
+
+                    *state = RunningStatus::Paused;
+                    *self.breakpoint.blocking_lock() = Some(label);
+                }
+                BufferEntry::Layer(name) => {
+                    println!("Reached layer: {}", name);
+
+                    *state = RunningStatus::Paused;
+                    self.layers.blocking_lock().pending = Some(name);
+                }
+                BufferEntry::ToolChange { tool, resume } => {
+                    println!("Changing to tool {}", tool);
+
+                    *state = RunningStatus::Paused;
+                    drop(state);
+                    self.step.store(false, Ordering::SeqCst);
+
+                    self.start_tool_change(&tool, resume);
+                }
+                BufferEntry::JobStart(id) => {
+                    println!("Starting job {}", id);
+
+                    let start = self.plotted.blocking_lock().simulator().clone();
+
+                    let mut jobs = self.jobs.blocking_lock();
+                    jobs.set_state(id, JobState::Started);
+                    // Resumed jobs keep where they first started.
+                    if let Some(job) = jobs.get_mut(id) {
+                        job.start.get_or_insert(start);
+                    }
+
+                    let name = jobs.get(id).map(|job| job.name.clone()).unwrap_or_default();
+                    let _ = self.events.send(Event::JobStarted { id, name });
+                    drop(jobs);
+
+                    self.plotted.blocking_lock().clear_paths();
+                }
+                BufferEntry::JobEnd(id) => {
+                    println!("Completed job {}", id);
+
+                    let mut jobs = self.jobs.blocking_lock();
+                    jobs.set_state(id, JobState::Complete);
+
+                    let name = jobs.get(id).map(|job| job.name.clone()).unwrap_or_default();
+                    let _ = self.events.send(Event::JobComplete { id, name });
+                    drop(jobs);
+
+                    let next_job_queued = self
+                        .command_buffer
+                        .blocking_lock()
+                        .iter()
+                        .any(|entry| matches!(entry, BufferEntry::JobStart(_)));
+
+                    if next_job_queued {
+                        println!("Waiting to start next job");
+
+                        *state = RunningStatus::Paused;
+                        self.jobs.blocking_lock().awaiting_next_after = Some(id);
+                        drop(state);
+
+                        self.wait_for_next_job(id);
+                    }
+                }
+            }
+        }
+
+        *self.current_command.blocking_lock() = None;
+    }
+
+    fn execute(&mut self, command: &QueuedCommand, speed_factor: f64) -> io::Result<Vec<String>> {
+        let _entered = command.span.enter();
+
+        let (name, arguments) = ebb::parse(&command.contents);
+
+        if self.parked.is_some() && ebb::is_motion(&command.contents) {
+            self.unpark()?;
+        }
+        self.last_activity = Instant::now();
+
+        let contents = Some(speed_factor)
+            .filter(|&speed_factor| speed_factor != 1.0)
+            .and_then(|speed_factor| ebb::scale_speed(&command.contents, speed_factor))
+            .unwrap_or_else(|| command.contents.clone());
+        let contents = self.limit_speed(contents);
+
+        let response = if name == "HM" && !self.capabilities.home_move {
+            // Older firmware has no HM, but the same move can be made with SM
+            // from the tracked position.
+            let argument = |index: usize| arguments.get(index).copied().unwrap_or(0);
+            let contents = self
+                .plotted
+                .blocking_lock()
+                .simulator()
+                .home_move(argument(0), (argument(1), argument(2)));
+
+            self.transact_within(&contents, command.max_duration)?
+        } else {
+            self.transact_within(&contents, command.max_duration)?
+        };
+
+        if let Some(error) = response.iter().find(|line| line.starts_with('!')) {
+            let _ = self.events.send(Event::Error {
+                message: format!("{} failed: {}", command.contents, error),
+            });
+        }
+
+        self.plotted.blocking_lock().trace(&contents);
+
+        self.statistics.blocking_lock().record(&contents);
+
+        // Keep the unscaled move, since anything requeued after an `ES` is
+        // scaled again when it's sent.
+        if self.in_flight.len() == 2 {
+            self.in_flight.pop_front();
+        }
+        self.in_flight.push_back(command.contents.clone());
+
+        if name == "EM" && arguments.iter().any(|&mode| mode != 0) {
+            self.motor_mode = Some(contents.clone());
+        }
+
+        if let ("SC", [4, position, ..]) = (name.as_str(), arguments.as_slice()) {
+            self.device_status.blocking_lock().pen_up_position = Some(*position);
+        } else if let ("SC", [5, position, ..]) = (name.as_str(), arguments.as_slice()) {
+            self.device_status.blocking_lock().pen_down_position = Some(*position);
+        }
+        self.sequence_number += 1;
+
+        let _ = self.events.send(Event::CommandExecuted {
+            sequence_number: self.sequence_number,
+            sequence_id: command.sequence_id,
+            command: contents,
+            response: response.clone(),
+            timestamp: SystemTime::now(),
+            label: command.label.clone(),
+        });
+
+        Ok(response)
+    }
+
+    /// Parks the machine between jobs so the paper can be changed, resuming by
+    /// itself after the auto-advance delay if there is one.
+    fn wait_for_next_job(&mut self, finished_id: u64) {
+        let mut commands = vec![self.servo_profile.blocking_lock().pen(false)];
+        if self.motors_off_between_jobs {
+            commands.push("EM,0,0".to_string());
+        }
+
+        for contents in commands {
+            if let Err(error) = self.execute(&QueuedCommand::new(contents.clone()), 1.0) {
+                self.pause_with_error(format!(
+                    "Serial error while sending {}: {}",
+                    contents, error
+                ));
+                return;
+            }
+        }
+
+        let Some(delay) = self.job_advance_delay else {
+            return;
+        };
+
+        let running_status = self.running_status.clone();
+        let jobs = self.jobs.clone();
+        let control_message_sender = self.control_message_sender.clone();
+
+        spawn(move || {
+            sleep(delay);
+
+            let mut running_status = running_status.blocking_lock();
+            let mut jobs = jobs.blocking_lock();
+
+            if jobs.awaiting_next_after == Some(finished_id) {
+                jobs.awaiting_next_after = None;
+                *running_status = RunningStatus::Running;
+                let _ = control_message_sender.send(ControlMessage::CheckBuffer);
+            }
+        });
+    }
+
+    /// Raises the pen and parks for a tool change marker, leaving plotting
+    /// paused until the change is confirmed.
+    fn start_tool_change(&mut self, tool: &str, resume: bool) {
+        let simulator = self.plotted.blocking_lock().simulator().clone();
+        let servo_profile = self.servo_profile.blocking_lock().clone();
+
+        let commands = self.tool_changes.blocking_lock().start(
+            tool,
+            &simulator,
+            &servo_profile,
+            TRAVEL_SPEED,
+            resume,
+        );
+
+        let commands = match commands {
+            Ok(commands) => commands,
+            Err(error) => {
+                self.pause_with_error(error);
+                return;
+            }
+        };
+
+        for contents in commands {
+            if let Err(error) = self.execute(&QueuedCommand::new(contents.clone()), 1.0) {
+                self.pause_with_error(format!(
+                    "Serial error while sending {}: {}",
+                    contents, error
+                ));
+                return;
+            }
+        }
+    }
+
+    /// Slows pen-down moves to the active tool's speed limit.
+    fn limit_speed(&self, contents: String) -> String {
+        let Some(max_speed) = self
+            .tool_changes
+            .blocking_lock()
+            .active_tool()
+            .and_then(|tool| tool.max_speed)
+        else {
+            return contents;
+        };
+
+        let plotted = self.plotted.blocking_lock();
+
+        if !plotted.simulator().is_pen_down() {
+            return contents;
+        }
+
+        match plotted.simulator().speed_of(&contents) {
+            Some(speed) if speed > max_speed => {
+                ebb::scale_speed(&contents, max_speed / speed).unwrap_or(contents)
+            }
+            _ => contents,
+        }
+    }
+
+    fn switch_port(&mut self, serial_port: Box<dyn SerialPort>) {
+        match serial_port.try_clone() {
+            Ok(stop_port) => {
+                let mut writer = self.immediate_stop.writer.lock().unwrap();
+                writer.serial_port = stop_port;
+                writer.pending = false;
+            }
+            Err(error) => {
+                self.pause_with_error(format!("Could not switch serial port: {}", error));
+                return;
+            }
+        }
+
+        self.serial_port = serial_port;
+        self.capabilities = self.device_status.blocking_lock().capabilities;
+        self.in_flight.clear();
+        self.parked = None;
+        self.motion_ends_at = Instant::now();
+    }
+
+    /// Raises the pen and turns the motors off once the buffer has been idle
+    /// for the idle timeout.
+    fn park_if_idle(&mut self) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+
+        if self.parked.is_some() || self.last_activity.elapsed() < idle_timeout {
+            return;
+        }
+
+        if !self.command_buffer.blocking_lock().is_empty()
+            || !self.urgent_buffer.blocking_lock().is_empty()
+        {
+            return;
+        }
+
+        println!("Idle, raising pen and turning motors off");
+
+        let pen_down = self.plotted.blocking_lock().simulator().is_pen_down();
+        let pen_up = self.servo_profile.blocking_lock().pen(false);
+        let mut commands = vec![pen_up.as_str(), "EM,0,0"];
+        if self.idle_servo_off && self.capabilities.servo_power {
+            // Cuts power now, keeping the EBB's default power timeout.
+            commands.push("SR,60000,0");
+        }
+
+        for contents in commands.iter().copied() {
+            if let Err(error) = self.transact(contents) {
+                self.pause_with_error(format!(
+                    "Serial error while sending {}: {}",
+                    contents, error
+                ));
+                return;
+            }
+        }
+
+        self.plotted.blocking_lock().trace(&pen_up);
+        self.statistics.blocking_lock().record("EM,0,0");
+        self.parked = Some(pen_down);
+    }
+
+    /// Re-enables the motors and puts the pen back where it was before parking.
+    fn unpark(&mut self) -> io::Result<()> {
+        let pen_down = self.parked.take().unwrap_or(false);
+        let motor_mode = self.motor_mode.clone().unwrap_or("EM,1,1".to_string());
+
+        println!("Leaving idle");
+
+        self.transact(&motor_mode)?;
+        self.statistics.blocking_lock().record(&motor_mode);
+
+        if pen_down {
+            let pen_down = self.servo_profile.blocking_lock().pen(true);
+
+            self.transact(&pen_down)?;
+            self.plotted.blocking_lock().trace(&pen_down);
+            self.statistics.blocking_lock().record(&pen_down);
+        }
+
+        Ok(())
+    }
+
+    fn is_button_pressed(&mut self) -> bool {
+        self.transact("QB")
+            .ok()
+            .and_then(|response| ebb::parse_flag(&response))
+            .unwrap_or(false)
+    }
+
+    fn check_power_if_due(&mut self) {
+        let last_power_check = self.device_status.blocking_lock().last_power_check;
+
+        if last_power_check
+            .is_some_and(|last_power_check| last_power_check.elapsed() < POWER_CHECK_INTERVAL)
+        {
+            return;
+        }
+
+        let motor_voltage = self
+            .transact("QC")
+            .ok()
+            .and_then(|response| ebb::parse_motor_voltage(&response));
+        let servo_powered = if self.capabilities.servo_power {
+            self.transact("QR")
+                .ok()
+                .and_then(|response| ebb::parse_flag(&response))
+        } else {
+            None
+        };
+
+        let mut device_status = self.device_status.blocking_lock();
+        device_status.motor_voltage = motor_voltage;
+        device_status.servo_powered = servo_powered;
+        device_status.last_power_check = Some(Instant::now());
+
+        let low_voltage = match (motor_voltage, self.low_voltage_threshold) {
+            (Some(voltage), Some(threshold)) if voltage < threshold => Some((voltage, threshold)),
+            _ => None,
+        };
+        let newly_low_voltage = low_voltage.is_some() && !device_status.low_voltage;
+        device_status.low_voltage = low_voltage.is_some();
+        drop(device_status);
+
+        if let (Some((voltage, threshold)), true) = (low_voltage, newly_low_voltage) {
+            self.pause_with_error(format!(
+                "Motor supply voltage {:.1} V is below {:.1} V",
+                voltage, threshold
+            ));
+        }
+    }
+
+    /// Sends a command once any pending `ES` has been dealt with.
+    fn transact(&mut self, command: &str) -> io::Result<Vec<String>> {
+        self.transact_within(command, None)
+    }
+
+    /// Sends `command` and reads its response, failing if it takes longer than
+    /// `max_duration`, or the configured timeout, after earlier moves finish.
+    fn transact_within(
+        &mut self,
+        command: &str,
+        max_duration: Option<Duration>,
+    ) -> io::Result<Vec<String>> {
+        let immediate_stop = self.immediate_stop.clone();
+
+        let sent_at = loop {
+            self.finish_immediate_stop()?;
+
+            let writer = immediate_stop.writer.lock().unwrap();
+
+            if !writer.pending {
+                let sent_at = Instant::now();
+                write_command(&*self.serial_port, command)?;
+                break sent_at;
+            }
+        };
+
+        let deadline = if ebb::is_motion(command) {
+            max_duration
+                .or(self.motion_command_timeout)
+                .map(|timeout| sent_at.max(self.motion_ends_at) + timeout)
+        } else {
+            max_duration
+                .or(self.fast_command_timeout)
+                .map(|timeout| sent_at + timeout)
+        };
+
+        let response = read_response(&*self.serial_port, command, deadline)?;
+        self.record_latency(command, sent_at);
+
+        Ok(response)
+    }
+
+    fn record_latency(&mut self, command: &str, sent_at: Instant) {
+        let round_trip = sent_at.elapsed();
+
+        let latency = if ebb::is_motion(command) {
+            // Moves are only acknowledged once there's room in the FIFO, so
+            // leave out time spent waiting for earlier moves to finish.
+            let waited = self.motion_ends_at.saturating_duration_since(sent_at);
+            let simulator = self.plotted.blocking_lock().simulator().clone();
+            let duration = estimate::estimate([command], simulator).duration;
+            self.motion_ends_at = Instant::now().max(self.motion_ends_at) + duration;
+
+            round_trip.saturating_sub(waited)
+        } else {
+            round_trip
+        };
+
+        self.statistics.blocking_lock().record_latency(latency);
+
+        if latency > self.slow_command_threshold {
+            println!("Slow response to {}: {} ms", command, latency.as_millis());
+        }
+    }
+
+    /// Reads the response to a pending `ES` and queues what's needed to carry
+    /// on from where the machine actually stopped.
+    fn finish_immediate_stop(&mut self) -> io::Result<()> {
+        if !std::mem::take(&mut self.immediate_stop.writer.lock().unwrap().pending) {
+            return Ok(());
+        }
+
+        let response = read_response(&*self.serial_port, "ES", None)?;
+        let in_flight = std::mem::take(&mut self.in_flight);
+        self.motion_ends_at = Instant::now();
+
+        let Some(stop) = ebb::parse_stop(&response) else {
+            self.pause_with_error(format!(
+                "Unexpected ES response {}, position may be lost",
+                response.join(" ")
+            ));
+            return Ok(());
+        };
+
+        // A move still waiting in the FIFO is discarded, and the one before it
+        // was the one interrupted.
+        let mut newest_first = in_flight.into_iter().rev();
+        let mut requeue = VecDeque::new();
+        let mut rewind = (0, 0);
+
+        if stop.fifo_steps != (0, 0) {
+            if let Some(discarded) = newest_first.next() {
+                if let Some((_, steps1, steps2)) = ebb::motor_move(&discarded) {
+                    rewind = (rewind.0 - steps1, rewind.1 - steps2);
+                }
+                requeue.push_front(discarded);
+            }
+        }
+
+        let interrupted = newest_first.next();
+
+        if stop.interrupted {
+            match interrupted.and_then(|command| ebb::remainder(&command, stop.remaining_steps)) {
+                Some((segment, (steps1, steps2))) => {
+                    rewind = (rewind.0 - steps1, rewind.1 - steps2);
+                    requeue.push_front(segment);
+                }
+                None => {
+                    let _ = self.events.send(Event::Error {
+                        message: "Could not work out the rest of the interrupted move".to_string(),
+                    });
+                }
+            }
+        }
+
+        self.plotted.blocking_lock().rewind(rewind.0, rewind.1);
+
+        let mut buffer = self.command_buffer.blocking_lock();
+        for contents in requeue.into_iter().rev() {
+            buffer.push_front(BufferEntry::Command(QueuedCommand::new(contents)));
+        }
+        self.watermarks.update(buffer.len());
+
+        Ok(())
+    }
+
+    fn pause_with_error(&self, message: String) {
+        println!("{}", message);
+
+        *self.running_status.blocking_lock() = RunningStatus::Paused;
+        self.interlock.disarm();
+        let _ = self.events.send(Event::Error { message });
+    }
+}
+
+/// Sends a command to the EBB and collects response lines up to and including
+/// the final `OK` or error line.
+#[instrument(skip(serial_port))]
+pub fn transact(serial_port: &dyn SerialPort, command: &str) -> io::Result<Vec<String>> {
+    write_command(serial_port, command)?;
+    read_response(serial_port, command, None)
+}
+
+fn write_command(serial_port: &dyn SerialPort, command: &str) -> io::Result<()> {
+    println!("Writing to serial port: {}", command);
+
+    let mut serial_writer = BufWriter::new(serial_port.try_clone()?);
+    serial_writer.write_all(format!("{}\r", command).as_bytes())?;
+    serial_writer.flush()
+}
+
+fn read_response(
+    serial_port: &dyn SerialPort,
+    command: &str,
+    deadline: Option<Instant>,
+) -> io::Result<Vec<String>> {
+    // Read unbuffered, so that nothing after this response is consumed and
+    // lost, such as an `ES` response sent straight after.
+    let mut serial_reader = serial_port.try_clone()?;
+
+    // The version query is the only command not terminated by `OK`.
+    let single_line = command.eq_ignore_ascii_case("V");
+    let mut response = Vec::new();
+    let mut line = Vec::new();
+
+    loop {
+        let mut byte = [0];
+
+        match serial_reader.read(&mut byte) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {}
+            Err(error) if error.kind() == ErrorKind::TimedOut => {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("no response to {} in time", command),
+                    ));
+                }
+
+                continue;
+            }
+            Err(error) => return Err(error),
+        }
+
+        if byte[0] != b'\n' {
+            line.push(byte[0]);
+            continue;
+        }
+
+        let text = String::from_utf8_lossy(&line).trim().to_string();
+        line.clear();
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let done = single_line || text == "OK" || text.starts_with('!');
+        response.push(text);
+
+        if done {
+            break;
+        }
+    }
+
+    println!("Response from serial port: {}", response.join(" "));
+
+    Ok(response)
+}
@@ -0,0 +1,464 @@
+use crate::{
+    auth::{self, Caller, Role, SharedTokens},
+    axidraw_over_http::{
+        axidraw_over_http_server::AxidrawOverHttp, Command, Empty, Job, JobId, PauseRequest,
+        PreviewRequest, RunningStatus,
+    },
+    events::Event,
+    metrics, AxidrawService,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    Mutex,
+};
+use tonic::{Code, Status};
+use warp::{
+    filters::BoxedFilter,
+    http::StatusCode,
+    reply::{Reply, Response},
+    Filter, Rejection,
+};
+
+const INDEX_HTML: &str = include_str!("dashboard/index.html");
+const HISTORY_LENGTH: usize = 50;
+
+#[derive(Serialize)]
+struct DashboardState {
+    running_status: String,
+    buffer_length: u64,
+    breakpoint: Option<String>,
+    current_layer: Option<String>,
+    upcoming_layer: Option<String>,
+    job: Option<DashboardJob>,
+}
+
+#[derive(Serialize)]
+struct DashboardJob {
+    id: u64,
+    name: String,
+    progress: f64,
+}
+
+#[derive(Serialize)]
+struct RestJob {
+    id: u64,
+    name: String,
+    state: String,
+    command_count: u64,
+    executed_count: u64,
+}
+
+#[derive(Deserialize)]
+struct JobSubmission {
+    name: String,
+    /// EBB commands, one per entry.
+    commands: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct RestJobId {
+    id: u64,
+}
+
+#[derive(Serialize)]
+struct RestError {
+    error: String,
+}
+
+#[derive(Clone, Serialize)]
+struct HistoryEntry {
+    command: String,
+    response: Vec<String>,
+}
+
+type History = Arc<Mutex<VecDeque<HistoryEntry>>>;
+
+/// Cross-origin access to the API, for web clients hosted elsewhere.
+pub struct Cors {
+    /// Allowed origins, or `*` for any. CORS is disabled if empty.
+    pub origins: Vec<String>,
+    /// Request headers allowed in addition to `authorization` and `content-type`.
+    pub headers: Vec<String>,
+    /// Seconds browsers may cache preflight responses for.
+    pub max_age: Option<u32>,
+}
+
+impl Cors {
+    fn to_filter(&self) -> Option<warp::cors::Builder> {
+        if self.origins.is_empty() {
+            return None;
+        }
+
+        let mut builder = warp::cors()
+            .allow_methods(["GET", "POST"])
+            .allow_headers(["authorization", "content-type"])
+            .allow_headers(self.headers.iter().map(String::as_str));
+
+        builder = if self.origins.iter().any(|origin| origin == "*") {
+            builder.allow_any_origin()
+        } else {
+            builder.allow_origins(self.origins.iter().map(String::as_str))
+        };
+
+        if let Some(max_age) = self.max_age {
+            builder = builder.max_age(max_age);
+        }
+
+        Some(builder)
+    }
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Accepts requests whose bearer token grants at least `role`, extracting the
+/// caller. The token can
+/// also be given as a `token` query parameter, since the page can't set
+/// headers on images.
+fn authorized(
+    tokens: SharedTokens,
+    role: Role,
+) -> impl Filter<Extract = (Caller,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(
+            move |authorization: Option<String>, query: HashMap<String, String>| {
+                let authorization = authorization
+                    .or_else(|| query.get("token").map(|token| format!("Bearer {}", token)));
+                let caller = tokens.read().unwrap().caller_for(authorization.as_deref());
+
+                async move {
+                    match caller {
+                        Some(caller) if caller.role >= role => Ok(caller),
+                        _ => Err(warp::reject::custom(Unauthorized)),
+                    }
+                }
+            },
+        )
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(StatusCode::UNAUTHORIZED)
+    } else {
+        Err(rejection)
+    }
+}
+
+/// Builds the dashboard page, its JSON API and the Prometheus metrics.
+pub fn routes(
+    service: Arc<AxidrawService>,
+    tokens: SharedTokens,
+    cors: Cors,
+    events: Receiver<Event>,
+) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let history = History::default();
+
+    tokio::spawn(record_history(history.clone(), events));
+
+    let with_service = warp::any().map(move || service.clone());
+    let with_history = warp::any().map(move || history.clone());
+
+    let index = warp::path::end()
+        .and(warp::get())
+        .map(|| warp::reply::html(INDEX_HTML));
+
+    let viewer = authorized(tokens.clone(), Role::Viewer);
+    let operator = authorized(tokens, Role::Operator);
+
+    let state = warp::path!("api" / "state")
+        .and(warp::get())
+        .and(viewer.clone())
+        .and(with_service.clone())
+        .and_then(get_state);
+
+    let preview = warp::path!("api" / "preview")
+        .and(warp::get())
+        .and(viewer.clone())
+        .and(with_service.clone())
+        .and_then(get_preview);
+
+    let history = warp::path!("api" / "history")
+        .and(warp::get())
+        .and(viewer.clone())
+        .and(with_history)
+        .and_then(get_history);
+
+    let jobs = warp::path!("api" / "jobs")
+        .and(warp::get())
+        .and(viewer.clone())
+        .and(with_service.clone())
+        .and_then(list_jobs);
+
+    let submit_job = warp::path!("api" / "jobs")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(warp::body::json())
+        .and(with_service.clone())
+        .and_then(submit_job);
+
+    let release_job = warp::path!("api" / "jobs" / u64 / "release")
+        .and(warp::post())
+        .and(operator.clone())
+        .and(with_service.clone())
+        .and_then(release_job);
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(viewer)
+        .and(with_service.clone())
+        .and_then(get_metrics);
+
+    let control = warp::path!("api" / String)
+        .and(warp::post())
+        .and(operator)
+        .and(with_service)
+        .and_then(control);
+
+    let routes = index
+        .or(state)
+        .or(preview)
+        .or(history)
+        .or(jobs)
+        .or(submit_job)
+        .or(release_job)
+        .or(metrics)
+        .or(control)
+        .recover(handle_rejection);
+
+    match cors.to_filter() {
+        Some(cors) => boxed(routes.with(cors)),
+        None => boxed(routes),
+    }
+}
+
+fn boxed<R: Reply + 'static>(
+    filter: impl Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+) -> BoxedFilter<(Box<dyn Reply>,)> {
+    filter
+        .map(|reply| Box::new(reply) as Box<dyn Reply>)
+        .boxed()
+}
+
+/// Serves the dashboard on its own port.
+pub fn spawn(port: u16, routes: BoxedFilter<(Box<dyn Reply>,)>) {
+    tokio::spawn(warp::serve(routes).run((IpAddr::from_str("::").unwrap(), port)));
+}
+
+async fn record_history(history: History, mut events: Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(Event::CommandExecuted {
+                command, response, ..
+            }) => {
+                let mut history = history.lock().await;
+
+                if history.len() == HISTORY_LENGTH {
+                    history.pop_front();
+                }
+
+                history.push_back(HistoryEntry { command, response });
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn get_state(caller: Caller, service: Arc<AxidrawService>) -> Result<impl Reply, Infallible> {
+    let state = service
+        .get_state(auth::request_as(Empty {}, caller))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let job = service.jobs.lock().await.current().map(|job| DashboardJob {
+        id: job.id,
+        name: job.name.clone(),
+        progress: job.progress(),
+    });
+
+    Ok(warp::reply::json(&DashboardState {
+        running_status: RunningStatus::try_from(state.running_status)
+            .unwrap_or_default()
+            .as_str_name()
+            .to_lowercase(),
+        buffer_length: state.buffer_length,
+        breakpoint: state.breakpoint,
+        current_layer: state.current_layer,
+        upcoming_layer: state.upcoming_layer,
+        job,
+    }))
+}
+
+async fn get_preview(
+    caller: Caller,
+    service: Arc<AxidrawService>,
+) -> Result<impl Reply, Infallible> {
+    let preview = service
+        .render_preview(auth::request_as(
+            PreviewRequest {
+                include_plotted: true,
+            },
+            caller,
+        ))
+        .await
+        .unwrap()
+        .into_inner();
+
+    Ok(warp::reply::with_header(
+        preview.svg,
+        "content-type",
+        "image/svg+xml",
+    ))
+}
+
+async fn get_history(_caller: Caller, history: History) -> Result<impl Reply, Infallible> {
+    let history = history.lock().await;
+
+    Ok(warp::reply::json(&history.iter().rev().collect::<Vec<_>>()))
+}
+
+async fn list_jobs(caller: Caller, service: Arc<AxidrawService>) -> Result<Response, Infallible> {
+    let jobs = match service.list_jobs(auth::request_as(Empty {}, caller)).await {
+        Ok(jobs) => jobs.into_inner().jobs,
+        Err(status) => return Ok(error_reply(status)),
+    };
+
+    let jobs = jobs
+        .into_iter()
+        .map(|job| RestJob {
+            state: job.state().as_str_name().to_lowercase(),
+            id: job.id,
+            name: job.name,
+            command_count: job.command_count,
+            executed_count: job.executed_count,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(warp::reply::json(&jobs).into_response())
+}
+
+async fn submit_job(
+    caller: Caller,
+    submission: JobSubmission,
+    service: Arc<AxidrawService>,
+) -> Result<Response, Infallible> {
+    let job = Job {
+        name: submission.name,
+        commands: submission
+            .commands
+            .into_iter()
+            .map(|contents| Command {
+                contents,
+                ..Default::default()
+            })
+            .collect(),
+        start_at: None,
+    };
+
+    Ok(
+        match service.submit_job(auth::request_as(job, caller)).await {
+            Ok(id) => warp::reply::with_status(
+                warp::reply::json(&RestJobId {
+                    id: id.into_inner().id,
+                }),
+                StatusCode::CREATED,
+            )
+            .into_response(),
+            Err(status) => error_reply(status),
+        },
+    )
+}
+
+async fn release_job(
+    id: u64,
+    caller: Caller,
+    service: Arc<AxidrawService>,
+) -> Result<Response, Infallible> {
+    Ok(
+        match service
+            .release_job(auth::request_as(JobId { id }, caller))
+            .await
+        {
+            Ok(_) => StatusCode::NO_CONTENT.into_response(),
+            Err(status) => error_reply(status),
+        },
+    )
+}
+
+async fn get_metrics(caller: Caller, service: Arc<AxidrawService>) -> Result<Response, Infallible> {
+    let state = service
+        .get_state(auth::request_as(Empty {}, caller.clone()))
+        .await
+        .unwrap()
+        .into_inner();
+    let statistics = service.statistics.lock().await.to_proto();
+    let jobs = service
+        .jobs
+        .lock()
+        .await
+        .iter()
+        .map(|job| job.to_info())
+        .collect::<Vec<_>>();
+
+    let metrics = metrics::render(&state, &statistics, &jobs, service.started_at.elapsed());
+
+    Ok(
+        warp::reply::with_header(metrics, "content-type", "text/plain; version=0.0.4")
+            .into_response(),
+    )
+}
+
+/// Converts a failed RPC to a JSON error with the closest HTTP status.
+fn error_reply(status: Status) -> Response {
+    let code = match status.code() {
+        Code::InvalidArgument | Code::OutOfRange => StatusCode::BAD_REQUEST,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted | Code::FailedPrecondition => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    warp::reply::with_status(
+        warp::reply::json(&RestError {
+            error: status.message().to_string(),
+        }),
+        code,
+    )
+    .into_response()
+}
+
+async fn control(
+    action: String,
+    caller: Caller,
+    service: Arc<AxidrawService>,
+) -> Result<impl Reply, Rejection> {
+    let result = match action.as_str() {
+        "pause" => {
+            service
+                .pause(auth::request_as(PauseRequest::default(), caller))
+                .await
+        }
+        "resume" => service.resume(auth::request_as(Empty {}, caller)).await,
+        "clear" => service.clear(auth::request_as(Empty {}, caller)).await,
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    Ok(match result {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    })
+}
@@ -0,0 +1,568 @@
+use crate::axidraw_over_http::{DeviceInfo, FirmwareCapabilities, MachineConfig, SerialDevice};
+use crate::{consumer, ebb};
+use serde::{Deserialize, Serialize};
+use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+use std::{str::FromStr, time::Instant};
+
+/// What is known about the attached EBB, refreshed by the consumer.
+#[derive(Default)]
+pub struct DeviceStatus {
+    pub port_name: String,
+    pub firmware_version: Option<String>,
+    pub nickname: Option<String>,
+    pub capabilities: Capabilities,
+    pub motor_voltage: Option<f64>,
+    pub servo_powered: Option<bool>,
+    /// Set while the motor voltage is below the configured threshold.
+    pub low_voltage: bool,
+    pub last_power_check: Option<Instant>,
+    /// Servo positions last set with `SC,4` and `SC,5`.
+    pub pen_up_position: Option<i64>,
+    pub pen_down_position: Option<i64>,
+    /// Options set with ConfigureMachine.
+    pub machine_settings: MachineSettings,
+}
+
+impl DeviceStatus {
+    pub fn to_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            port_name: self.port_name.clone(),
+            firmware_version: self.firmware_version.clone(),
+            nickname: self.nickname.clone(),
+            capabilities: Some(self.capabilities.to_proto()),
+            motor_voltage: self.motor_voltage,
+            servo_powered: self.servo_powered,
+            simulated_time: None,
+        }
+    }
+}
+
+/// Records the firmware and nickname of a newly opened EBB, forgetting what was
+/// known about the previous one, and restores the pen heights set on it.
+pub fn connect(serial_port: &dyn SerialPort, status: &mut DeviceStatus) {
+    let version_response = consumer::transact(serial_port, "V").unwrap_or_default();
+
+    *status = DeviceStatus {
+        port_name: serial_port.name().unwrap_or_default(),
+        firmware_version: version_response.first().cloned(),
+        nickname: read_nickname(serial_port),
+        capabilities: Capabilities::for_version(ebb::parse_firmware_version(&version_response)),
+        pen_up_position: status.pen_up_position,
+        pen_down_position: status.pen_down_position,
+        machine_settings: status.machine_settings.clone(),
+        ..Default::default()
+    };
+
+    for (parameter, position) in [(4, status.pen_up_position), (5, status.pen_down_position)] {
+        if let Some(position) = position {
+            let _ = consumer::transact(serial_port, &format!("SC,{},{}", parameter, position));
+        }
+    }
+
+    for command in status.machine_settings.commands() {
+        if status.capabilities.supports(&ebb::parse(&command).0) {
+            let _ = consumer::transact(serial_port, &command);
+        }
+    }
+}
+
+/// EBB options that are lost when it resets, so are applied on every connect.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MachineSettings {
+    pub microstep_mode: Option<u32>,
+    pub pen_raise_rate: Option<u32>,
+    pub pen_lower_rate: Option<u32>,
+    pub servo_timeout_ms: Option<u32>,
+    pub limit_checking: Option<bool>,
+    pub fifo_empty_led: Option<bool>,
+}
+
+impl MachineSettings {
+    pub fn from_proto(config: MachineConfig) -> MachineSettings {
+        MachineSettings {
+            microstep_mode: config.microstep_mode,
+            pen_raise_rate: config.pen_raise_rate,
+            pen_lower_rate: config.pen_lower_rate,
+            servo_timeout_ms: config.servo_timeout_ms,
+            limit_checking: config.limit_checking,
+            fifo_empty_led: config.fifo_empty_led,
+        }
+    }
+
+    pub fn to_proto(&self) -> MachineConfig {
+        MachineConfig {
+            microstep_mode: self.microstep_mode,
+            pen_raise_rate: self.pen_raise_rate,
+            pen_lower_rate: self.pen_lower_rate,
+            servo_timeout_ms: self.servo_timeout_ms,
+            limit_checking: self.limit_checking,
+            fifo_empty_led: self.fifo_empty_led,
+        }
+    }
+
+    /// Replaces the settings that are set in `other`.
+    pub fn merge(&mut self, other: &MachineSettings) {
+        let MachineSettings {
+            microstep_mode,
+            pen_raise_rate,
+            pen_lower_rate,
+            servo_timeout_ms,
+            limit_checking,
+            fifo_empty_led,
+        } = other.clone();
+
+        self.microstep_mode = microstep_mode.or(self.microstep_mode);
+        self.pen_raise_rate = pen_raise_rate.or(self.pen_raise_rate);
+        self.pen_lower_rate = pen_lower_rate.or(self.pen_lower_rate);
+        self.servo_timeout_ms = servo_timeout_ms.or(self.servo_timeout_ms);
+        self.limit_checking = limit_checking.or(self.limit_checking);
+        self.fifo_empty_led = fifo_empty_led.or(self.fifo_empty_led);
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .microstep_mode
+            .is_some_and(|mode| !(1..=5).contains(&mode))
+        {
+            return Err("Microstep mode must be from 1 to 5".to_string());
+        }
+
+        for rate in [self.pen_raise_rate, self.pen_lower_rate]
+            .into_iter()
+            .flatten()
+        {
+            if rate > u16::MAX as u32 {
+                return Err(format!("Pen rates must be at most {}", u16::MAX));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The EBB commands that apply these settings.
+    pub fn commands(&self) -> Vec<String> {
+        let flag = |enabled: bool| enabled as u8;
+
+        [
+            self.microstep_mode
+                .map(|mode| format!("EM,{},{}", mode, mode)),
+            self.pen_raise_rate.map(|rate| format!("SC,11,{}", rate)),
+            self.pen_lower_rate.map(|rate| format!("SC,12,{}", rate)),
+            self.servo_timeout_ms
+                .map(|timeout| format!("SR,{}", timeout)),
+            self.limit_checking
+                .map(|enabled| format!("CU,2,{}", flag(enabled))),
+            self.fifo_empty_led
+                .map(|enabled| format!("CU,3,{}", flag(enabled))),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Longest nickname the EBB will store with `ST`.
+pub const MAX_NICKNAME_LENGTH: usize = 16;
+
+/// Reads the nickname stored on the EBB with `QT`, if one is set.
+pub fn read_nickname(serial_port: &dyn SerialPort) -> Option<String> {
+    let response = consumer::transact(serial_port, "QT").ok()?;
+    let nickname = response.first()?;
+
+    (nickname != "OK" && !nickname.starts_with('!')).then(|| nickname.clone())
+}
+
+type Version = (u32, u32, u32);
+
+/// Commands added since the 2.0 firmware, with the version that added them.
+const ADDED_IN: &[(&str, Version)] = &[
+    ("CS", (2, 4, 3)),
+    ("QS", (2, 4, 3)),
+    ("QM", (2, 4, 4)),
+    ("QT", (2, 5, 4)),
+    ("ST", (2, 5, 5)),
+    ("SR", (2, 6, 0)),
+    ("QR", (2, 6, 0)),
+    ("HM", (2, 6, 2)),
+    ("QG", (2, 6, 2)),
+    ("LM", (2, 7, 0)),
+    ("LT", (2, 7, 0)),
+    ("QE", (2, 8, 0)),
+    ("L3", (3, 0, 0)),
+    ("T3", (3, 0, 0)),
+    ("QU", (3, 0, 0)),
+];
+
+/// EBB firmware features that vary between versions.
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities {
+    pub low_level_move: bool,
+    pub low_level_move_3: bool,
+    pub home_move: bool,
+    pub servo_power: bool,
+    version: Option<Version>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::for_version(None)
+    }
+}
+
+impl Capabilities {
+    /// Assumes everything is supported if the version couldn't be read.
+    pub fn for_version(version: Option<Version>) -> Self {
+        let supports = |command_name| supports(version, command_name);
+
+        Capabilities {
+            low_level_move: supports("LM"),
+            low_level_move_3: supports("L3"),
+            home_move: supports("HM"),
+            servo_power: supports("SR"),
+            version,
+        }
+    }
+
+    pub fn supports(&self, command_name: &str) -> bool {
+        supports(self.version, command_name)
+    }
+
+    pub fn to_proto(self) -> FirmwareCapabilities {
+        FirmwareCapabilities {
+            low_level_move: self.low_level_move,
+            low_level_move_3: self.low_level_move_3,
+            home_move: self.home_move,
+            servo_power: self.servo_power,
+        }
+    }
+}
+
+fn supports(version: Option<Version>, command_name: &str) -> bool {
+    let minimum = ADDED_IN
+        .iter()
+        .find(|(name, _)| *name == command_name)
+        .map(|&(_, minimum)| minimum);
+
+    match (version, minimum) {
+        (Some(version), Some(minimum)) => version >= minimum,
+        _ => true,
+    }
+}
+
+/// A rule for recognising an EBB during auto-detection.
+#[derive(Clone, Debug)]
+pub enum MatchRule {
+    UsbId { vid: u16, pid: u16 },
+    Product(String),
+    SerialNumber(String),
+}
+
+impl MatchRule {
+    pub fn defaults() -> Vec<MatchRule> {
+        vec![
+            MatchRule::UsbId {
+                vid: 0x04d8,
+                pid: 0xfd92,
+            },
+            MatchRule::Product("EiBotBoard".to_string()),
+        ]
+    }
+
+    pub fn matches(&self, port_info: &SerialPortInfo) -> bool {
+        let SerialPortType::UsbPort(usb_port_info) = &port_info.port_type else {
+            return false;
+        };
+
+        match self {
+            MatchRule::UsbId { vid, pid } => usb_port_info.vid == *vid && usb_port_info.pid == *pid,
+            MatchRule::Product(product) => usb_port_info
+                .product
+                .as_ref()
+                .is_some_and(|port_product| port_product.contains(product.as_str())),
+            MatchRule::SerialNumber(serial_number) => {
+                usb_port_info.serial_number.as_ref() == Some(serial_number)
+            }
+        }
+    }
+}
+
+/// Parses `VID:PID` in hex, `product=SUBSTRING` or `serial=NUMBER`.
+impl FromStr for MatchRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        if let Some(product) = rule.strip_prefix("product=") {
+            return Ok(MatchRule::Product(product.to_string()));
+        }
+
+        if let Some(serial_number) = rule.strip_prefix("serial=") {
+            return Ok(MatchRule::SerialNumber(serial_number.to_string()));
+        }
+
+        let (vid, pid) = rule
+            .split_once(':')
+            .ok_or_else(|| format!("Expected VID:PID, product=... or serial=..., got {}", rule))?;
+        let parse_id =
+            |id: &str| u16::from_str_radix(id, 16).map_err(|_| format!("Invalid USB ID {}", id));
+
+        Ok(MatchRule::UsbId {
+            vid: parse_id(vid)?,
+            pid: parse_id(pid)?,
+        })
+    }
+}
+
+/// Lists serial ports. On Windows, ports that serialport couldn't identify as
+/// USB are filled in from the registry, so VID/PID rules still match them.
+pub fn available_ports() -> Vec<SerialPortInfo> {
+    let ports = serialport::available_ports().unwrap_or_default();
+
+    #[cfg(windows)]
+    let ports = registry::identify_usb_ports(ports);
+
+    ports
+}
+
+pub fn is_eibotboard(port_info: &SerialPortInfo, match_rules: &[MatchRule]) -> bool {
+    match_rules.iter().any(|rule| rule.matches(port_info))
+}
+
+pub fn list_serial_devices(match_rules: &[MatchRule]) -> Vec<SerialDevice> {
+    available_ports()
+        .iter()
+        .map(|port_info| {
+            let mut device = SerialDevice {
+                port_name: port_info.port_name.clone(),
+                is_eibotboard: is_eibotboard(port_info, match_rules),
+                ..Default::default()
+            };
+
+            if let SerialPortType::UsbPort(usb_port_info) = &port_info.port_type {
+                device.vid = Some(usb_port_info.vid.into());
+                device.pid = Some(usb_port_info.pid.into());
+                device.serial_number = usb_port_info.serial_number.clone();
+                device.manufacturer = usb_port_info.manufacturer.clone();
+                device.product = usb_port_info.product.clone();
+            }
+
+            device
+        })
+        .collect()
+}
+
+#[cfg(windows)]
+mod registry {
+    use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+    use std::{ffi::c_void, ptr};
+    use windows_sys::Win32::{
+        Foundation::ERROR_SUCCESS,
+        System::Registry::{
+            RegCloseKey, RegEnumKeyExW, RegGetValueW, RegOpenKeyExW, HKEY, HKEY_LOCAL_MACHINE,
+            KEY_READ, RRF_RT_REG_SZ,
+        },
+    };
+
+    const USB_ENUM_KEY: &str = "SYSTEM\\CurrentControlSet\\Enum\\USB";
+
+    /// Gives ports of unknown type the USB IDs, serial number and friendly name
+    /// recorded for their COM port under `Enum\USB`.
+    pub fn identify_usb_ports(mut ports: Vec<SerialPortInfo>) -> Vec<SerialPortInfo> {
+        if ports
+            .iter()
+            .all(|port| matches!(port.port_type, SerialPortType::UsbPort(_)))
+        {
+            return ports;
+        }
+
+        let usb_ports = usb_serial_ports();
+
+        for port in &mut ports {
+            if matches!(port.port_type, SerialPortType::UsbPort(_)) {
+                continue;
+            }
+
+            if let Some((_, info)) = usb_ports
+                .iter()
+                .find(|(port_name, _)| port_name.eq_ignore_ascii_case(&port.port_name))
+            {
+                port.port_type = SerialPortType::UsbPort(info.clone());
+            }
+        }
+
+        ports
+    }
+
+    /// Returns the COM port name and USB details of every USB serial device
+    /// Windows has seen.
+    fn usb_serial_ports() -> Vec<(String, UsbPortInfo)> {
+        let mut usb_ports = Vec::new();
+        let Some(usb) = Key::open(HKEY_LOCAL_MACHINE, USB_ENUM_KEY) else {
+            return usb_ports;
+        };
+
+        for device_id in usb.subkeys() {
+            let Some((vid, pid)) = parse_device_id(&device_id) else {
+                continue;
+            };
+            let Some(device) = Key::open(usb.0, &device_id) else {
+                continue;
+            };
+
+            for instance_id in device.subkeys() {
+                let Some(port_name) =
+                    device.string_value(&format!("{}\\Device Parameters", instance_id), "PortName")
+                else {
+                    continue;
+                };
+
+                usb_ports.push((
+                    port_name,
+                    UsbPortInfo {
+                        vid,
+                        pid,
+                        // Composite and serial-less devices get generated
+                        // instance IDs containing `&`.
+                        serial_number: (!instance_id.contains('&')).then_some(instance_id.clone()),
+                        manufacturer: device
+                            .string_value(&instance_id, "Mfg")
+                            .map(strip_inf_prefix),
+                        product: device.string_value(&instance_id, "FriendlyName"),
+                    },
+                ));
+            }
+        }
+
+        usb_ports
+    }
+
+    /// Parses a device key such as `VID_04D8&PID_FD92` or `VID_04D8&PID_FD92&MI_00`.
+    fn parse_device_id(device_id: &str) -> Option<(u16, u16)> {
+        let mut parts = device_id.split('&');
+        let vid = parts.next()?.strip_prefix("VID_")?;
+        let pid = parts.next()?.strip_prefix("PID_")?;
+
+        Some((
+            u16::from_str_radix(vid, 16).ok()?,
+            u16::from_str_radix(pid, 16).ok()?,
+        ))
+    }
+
+    /// Driver-supplied strings look like `@oem12.inf,%mfgname%;SchmalzHaus`.
+    fn strip_inf_prefix(value: String) -> String {
+        match value.rsplit_once(';') {
+            Some((_, value)) => value.to_string(),
+            None => value,
+        }
+    }
+
+    fn wide(value: &str) -> Vec<u16> {
+        value.encode_utf16().chain(Some(0)).collect()
+    }
+
+    struct Key(HKEY);
+
+    impl Key {
+        fn open(parent: HKEY, path: &str) -> Option<Key> {
+            let mut key = 0;
+            let result =
+                unsafe { RegOpenKeyExW(parent, wide(path).as_ptr(), 0, KEY_READ, &mut key) };
+
+            if result == ERROR_SUCCESS {
+                Some(Key(key))
+            } else {
+                None
+            }
+        }
+
+        fn subkeys(&self) -> Vec<String> {
+            let mut subkeys = Vec::new();
+            let mut name = [0u16; 256];
+
+            for index in 0.. {
+                let mut length = name.len() as u32;
+                let result = unsafe {
+                    RegEnumKeyExW(
+                        self.0,
+                        index,
+                        name.as_mut_ptr(),
+                        &mut length,
+                        ptr::null(),
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                    )
+                };
+
+                if result != ERROR_SUCCESS {
+                    break;
+                }
+
+                subkeys.push(String::from_utf16_lossy(&name[..length as usize]));
+            }
+
+            subkeys
+        }
+
+        fn string_value(&self, subkey: &str, name: &str) -> Option<String> {
+            let mut value = [0u16; 512];
+            let mut size = (value.len() * 2) as u32;
+            let result = unsafe {
+                RegGetValueW(
+                    self.0,
+                    wide(subkey).as_ptr(),
+                    wide(name).as_ptr(),
+                    RRF_RT_REG_SZ,
+                    ptr::null_mut(),
+                    value.as_mut_ptr() as *mut c_void,
+                    &mut size,
+                )
+            };
+
+            (result == ERROR_SUCCESS).then(|| {
+                String::from_utf16_lossy(&value[..size as usize / 2])
+                    .trim_end_matches('\0')
+                    .to_string()
+            })
+        }
+    }
+
+    impl Drop for Key {
+        fn drop(&mut self) {
+            unsafe {
+                RegCloseKey(self.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_gated_on_the_version_that_added_them() {
+        let capabilities = Capabilities::for_version(Some((2, 8, 1)));
+
+        assert!(capabilities.supports("LM"));
+        assert!(capabilities.supports("QE"));
+        assert!(capabilities.supports("SM"));
+        assert!(!capabilities.supports("T3"));
+        assert!(!capabilities.supports("L3"));
+        assert!(!capabilities.low_level_move_3);
+
+        let capabilities = Capabilities::for_version(Some((2, 6, 0)));
+
+        assert!(capabilities.supports("SR"));
+        assert!(!capabilities.supports("HM"));
+        assert!(!capabilities.supports("LT"));
+    }
+
+    #[test]
+    fn everything_is_supported_on_an_unknown_version() {
+        let capabilities = Capabilities::for_version(None);
+
+        assert!(capabilities.supports("T3"));
+        assert!(capabilities.low_level_move_3);
+    }
+}
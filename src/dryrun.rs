@@ -0,0 +1,294 @@
+use crate::{
+    ebb::{self, Simulator},
+    estimate,
+};
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// Firmware version the simulated EBB reports.
+const FIRMWARE_VERSION: &str = "EBBv13_and_above EB Firmware Version 3.0.2";
+
+/// Tracks how long the simulated moves would take on a real machine, while
+/// running them `speed` times faster, or instantly if `speed` is 0.
+pub struct VirtualClock {
+    speed: f64,
+    elapsed: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new(speed: f64) -> Self {
+        VirtualClock {
+            speed,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Machine time simulated so far.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    /// Advances by `duration` of machine time, returning how long that takes.
+    fn advance(&self, duration: Duration) -> Duration {
+        *self.elapsed.lock().unwrap() += duration;
+
+        if self.speed > 0.0 {
+            duration.div_f64(self.speed)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[derive(Default)]
+struct Machine {
+    /// Bytes written since the last complete command.
+    input: Vec<u8>,
+    /// Responses not yet read, with when they become available.
+    output: VecDeque<(Instant, Vec<u8>)>,
+    /// When each unfinished move ends: one running and one in the FIFO.
+    moves: VecDeque<Instant>,
+    simulator: Simulator,
+}
+
+impl Machine {
+    fn handle(&mut self, command: &str, clock: &VirtualClock) {
+        let now = Instant::now();
+        let (name, _) = ebb::parse(command);
+
+        self.moves.retain(|&end| end > now);
+
+        let mut ready_at = self
+            .output
+            .back()
+            .map_or(now, |&(ready_at, _)| ready_at.max(now));
+
+        let lines: &[&str] = match name.as_str() {
+            "V" => &[FIRMWARE_VERSION],
+            "QB" | "QP" => &["0", "OK"],
+            "QR" => &["1", "OK"],
+            "QC" => &["0394,0300", "OK"],
+            "ES" => {
+                self.moves.clear();
+                &["0,0,0,0,0", "OK"]
+            }
+            _ => &["OK"],
+        };
+
+        if ebb::is_motion(command) {
+            // The EBB only acknowledges a move once there's room in its FIFO.
+            if self.moves.len() >= 2 {
+                ready_at = ready_at.max(self.moves.pop_front().unwrap());
+            }
+
+            let duration = estimate::estimate([command], self.simulator.clone()).duration;
+            let starts_at = self.moves.back().map_or(ready_at, |&end| end.max(ready_at));
+            self.moves.push_back(starts_at + clock.advance(duration));
+        }
+
+        self.simulator.apply(command);
+
+        for line in lines {
+            self.output
+                .push_back((ready_at, format!("{}\r\n", line).into_bytes()));
+        }
+    }
+}
+
+/// A simulated EBB, for running the server without a machine attached.
+#[derive(Clone)]
+pub struct VirtualEbb {
+    machine: Arc<Mutex<Machine>>,
+    clock: Arc<VirtualClock>,
+    timeout: Duration,
+}
+
+impl VirtualEbb {
+    pub fn new(clock: Arc<VirtualClock>) -> Self {
+        VirtualEbb {
+            machine: Arc::default(),
+            clock,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Read for VirtualEbb {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.timeout;
+
+        loop {
+            let mut machine = self.machine.lock().unwrap();
+            let now = Instant::now();
+
+            let wait = match machine.output.front_mut() {
+                Some((ready_at, bytes)) if *ready_at <= now => {
+                    let length = bytes.len().min(buffer.len());
+                    buffer[..length].copy_from_slice(&bytes[..length]);
+                    bytes.drain(..length);
+
+                    if bytes.is_empty() {
+                        machine.output.pop_front();
+                    }
+
+                    return Ok(length);
+                }
+                Some((ready_at, _)) => *ready_at - now,
+                None => self.timeout,
+            };
+
+            drop(machine);
+
+            if now >= deadline {
+                return Err(ErrorKind::TimedOut.into());
+            }
+
+            sleep(wait.min(deadline - now).min(Duration::from_millis(10)));
+        }
+    }
+}
+
+impl Write for VirtualEbb {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let mut machine = self.machine.lock().unwrap();
+
+        for &byte in bytes {
+            if byte == b'\r' {
+                let command = String::from_utf8_lossy(&machine.input).trim().to_string();
+                machine.input.clear();
+                machine.handle(&command, &self.clock);
+            } else {
+                machine.input.push(byte);
+            }
+        }
+
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for VirtualEbb {
+    fn name(&self) -> Option<String> {
+        Some("dry-run".to_string())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(9600)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        let now = Instant::now();
+        let machine = self.machine.lock().unwrap();
+
+        Ok(machine
+            .output
+            .iter()
+            .take_while(|(ready_at, _)| *ready_at <= now)
+            .map(|(_, bytes)| bytes.len() as u32)
+            .sum())
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        let mut machine = self.machine.lock().unwrap();
+
+        if matches!(buffer_to_clear, ClearBuffer::Input | ClearBuffer::All) {
+            machine.output.clear();
+        }
+
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
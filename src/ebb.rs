@@ -0,0 +1,374 @@
+use serde::{Deserialize, Serialize};
+
+/// Motor steps per millimetre at the default 16x microstepping.
+pub const STEPS_PER_MM: f64 = 80.0;
+
+/// Highest step rate the EBB supports, in steps per second.
+pub const MAX_STEP_RATE: f64 = 25000.0;
+
+/// Fastest carriage speed in mm/s, for a diagonal move driving one motor at
+/// the maximum step rate.
+pub const MAX_SPEED: f64 = MAX_STEP_RATE / STEPS_PER_MM / std::f64::consts::SQRT_2;
+
+/// Rate of the EBB's motion interrupt, which `LM` rates and accelerations are
+/// expressed against.
+pub const TICKS_PER_SECOND: f64 = 25000.0;
+
+pub type Point = (f64, f64);
+
+/// Splits a command into its upper-cased name and numeric arguments.
+pub fn parse(command: &str) -> (String, Vec<i64>) {
+    let mut parts = command.split(',');
+    let name = parts.next().unwrap_or_default().trim().to_ascii_uppercase();
+    let arguments = parts.map_while(|part| part.trim().parse().ok()).collect();
+
+    (name, arguments)
+}
+
+/// Whether `command` moves the carriage.
+pub fn moves_carriage(command: &str) -> bool {
+    let (name, _) = parse(command);
+
+    matches!(name.as_str(), "SM" | "XM" | "LM" | "L3" | "HM" | "T3")
+}
+
+/// Whether `command` moves the carriage or the pen.
+pub fn is_motion(command: &str) -> bool {
+    let (name, _) = parse(command);
+
+    matches!(
+        name.as_str(),
+        "SM" | "XM" | "LM" | "L3" | "HM" | "T3" | "SP" | "TP" | "S2"
+    )
+}
+
+/// Reads a `0`/`1` query response, such as from `QB` or `QR`.
+pub fn parse_flag(response: &[String]) -> Option<bool> {
+    match response.first()?.as_str() {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+/// Converts a `QC` response to the motor supply voltage, allowing for the
+/// EBB's input divider and protection diode.
+pub fn parse_motor_voltage(response: &[String]) -> Option<f64> {
+    let (_, value) = response.first()?.split_once(',')?;
+    let value: f64 = value.trim().parse().ok()?;
+
+    Some(value / 1023.0 * 3.3 * 9.2 + 0.3)
+}
+
+/// Extracts the version number from a `V` response such as
+/// `EBBv13_and_above EB Firmware Version 2.8.1`.
+pub fn parse_firmware_version(response: &[String]) -> Option<(u32, u32, u32)> {
+    let version = response.first()?.split_whitespace().last()?;
+    let mut parts = version.split('.').map(|part| part.parse().ok());
+
+    Some((
+        parts.next()??,
+        parts.next()??,
+        parts.next().flatten().unwrap_or(0),
+    ))
+}
+
+/// What an `ES` command reports about the moves it stopped.
+pub struct StopReport {
+    pub interrupted: bool,
+    /// Steps of a move discarded from the FIFO.
+    pub fifo_steps: (i64, i64),
+    /// Steps the interrupted move had left to go, without sign.
+    pub remaining_steps: (i64, i64),
+}
+
+/// Parses an `ES` response such as `1,0,0,200,200`.
+pub fn parse_stop(response: &[String]) -> Option<StopReport> {
+    let values = response
+        .first()?
+        .split(',')
+        .map(|value| value.trim().parse().ok())
+        .collect::<Option<Vec<i64>>>()?;
+
+    match values[..] {
+        [interrupted, fifo1, fifo2, remaining1, remaining2] => Some(StopReport {
+            interrupted: interrupted != 0,
+            fifo_steps: (fifo1, fifo2),
+            remaining_steps: (remaining1, remaining2),
+        }),
+        _ => None,
+    }
+}
+
+/// Returns the duration and per-motor steps of an `SM` or `XM` move.
+pub fn motor_move(command: &str) -> Option<(i64, i64, i64)> {
+    let (name, arguments) = parse(command);
+    let argument = |index: usize| arguments.get(index).copied();
+
+    match name.as_str() {
+        "SM" => Some((argument(0)?, argument(1)?, argument(2).unwrap_or(0))),
+        "XM" => {
+            let (a, b) = (argument(1)?, argument(2).unwrap_or(0));
+            Some((argument(0)?, a + b, a - b))
+        }
+        _ => None,
+    }
+}
+
+/// Returns an `SM` command for the unfinished part of an interrupted move at
+/// its original speed, along with its signed steps.
+pub fn remainder(command: &str, remaining_steps: (i64, i64)) -> Option<(String, (i64, i64))> {
+    let (duration, steps1, steps2) = motor_move(command)?;
+    let remaining1 = remaining_steps.0.abs() * steps1.signum();
+    let remaining2 = remaining_steps.1.abs() * steps2.signum();
+
+    let longest = steps1.abs().max(steps2.abs()).max(1);
+    let remaining_duration =
+        ((duration * remaining1.abs().max(remaining2.abs()) + longest - 1) / longest).max(1);
+
+    Some((
+        format!("SM,{},{},{}", remaining_duration, remaining1, remaining2),
+        (remaining1, remaining2),
+    ))
+}
+
+/// Returns `command` with its timing rescaled to run at `factor` times its
+/// speed, or `None` if it isn't an `SM`, `XM` or `LM` move. `SM` and `XM`
+/// durations are kept within the maximum step rate.
+pub fn scale_speed(command: &str, factor: f64) -> Option<String> {
+    let (name, arguments) = parse(command);
+    let mut parts = command.split(',').map(str::to_string).collect::<Vec<_>>();
+
+    match name.as_str() {
+        "SM" | "XM" => {
+            let (duration, steps1, steps2) = motor_move(command)?;
+            let longest = steps1.abs().max(steps2.abs()) as f64;
+            let shortest_duration = (longest * 1000.0 / MAX_STEP_RATE).ceil().max(1.0);
+            let scaled = (duration as f64 / factor).round().max(shortest_duration);
+
+            parts[1] = (scaled as i64).to_string();
+        }
+        "LM" if arguments.len() >= 6 => {
+            // Rates scale with speed and accelerations with its square, so the
+            // move keeps its shape.
+            for (index, power) in [(0, 1), (2, 2), (3, 1), (5, 2)] {
+                let scaled = arguments[index] as f64 * factor.powi(power);
+                parts[index + 1] = (scaled.round() as i64)
+                    .clamp(-(i32::MAX as i64), i32::MAX as i64)
+                    .to_string();
+            }
+        }
+        _ => return None,
+    }
+
+    Some(parts.join(","))
+}
+
+/// Tracks carriage position and pen state by following the commands sent to
+/// the EBB, without talking to any hardware.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Simulator {
+    /// Motor positions, in steps at 16x microstepping.
+    motor1: i64,
+    motor2: i64,
+    /// Multiplier from steps at the current microstepping mode to 16x steps.
+    step_scale: i64,
+    pen_down: bool,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Simulator {
+            motor1: 0,
+            motor2: 0,
+            step_scale: 1,
+            pen_down: false,
+        }
+    }
+}
+
+impl Simulator {
+    pub fn position(&self) -> Point {
+        (
+            (self.motor1 + self.motor2) as f64 / 2.0 / STEPS_PER_MM,
+            (self.motor1 - self.motor2) as f64 / 2.0 / STEPS_PER_MM,
+        )
+    }
+
+    pub fn is_pen_down(&self) -> bool {
+        self.pen_down
+    }
+
+    /// Returns an `SM` command moving the carriage in a straight line to
+    /// `target` at `speed` mm/s, or `None` if it is already there.
+    pub fn move_to(&self, target: Point, speed: f64) -> Option<String> {
+        let (x, y) = self.position();
+        let (dx, dy) = (target.0 - x, target.1 - y);
+        let steps1 = ((dx + dy) * STEPS_PER_MM).round() as i64 / self.step_scale;
+        let steps2 = ((dx - dy) * STEPS_PER_MM).round() as i64 / self.step_scale;
+
+        if steps1 == 0 && steps2 == 0 {
+            return None;
+        }
+
+        let duration = (dx.hypot(dy) / speed * 1000.0).ceil().max(1.0);
+
+        Some(format!("SM,{},{},{}", duration, steps1, steps2))
+    }
+
+    /// Returns how fast an `SM` or `XM` move from here would go, in mm/s.
+    pub fn speed_of(&self, command: &str) -> Option<f64> {
+        let (duration, steps1, steps2) = motor_move(command)?;
+        let (steps1, steps2) = (steps1 * self.step_scale, steps2 * self.step_scale);
+        let dx = (steps1 + steps2) as f64 / 2.0 / STEPS_PER_MM;
+        let dy = (steps1 - steps2) as f64 / 2.0 / STEPS_PER_MM;
+
+        (duration > 0).then(|| dx.hypot(dy) * 1000.0 / duration as f64)
+    }
+
+    /// Returns an `SM` command with the same effect as `HM,step_rate,...`, for
+    /// firmware without `HM`.
+    pub fn home_move(&self, step_rate: i64, target: (i64, i64)) -> String {
+        let steps1 = target.0 - self.motor1 / self.step_scale;
+        let steps2 = target.1 - self.motor2 / self.step_scale;
+        let duration = (steps1.abs().max(steps2.abs()) * 1000 / step_rate.max(1)).max(1);
+
+        format!("SM,{},{},{}", duration, steps1, steps2)
+    }
+
+    /// Applies the effect of `command`, returning the start and end positions
+    /// if the carriage moved.
+    pub fn apply(&mut self, command: &str) -> Option<(Point, Point)> {
+        let (name, arguments) = parse(command);
+        let argument = |index: usize| arguments.get(index).copied();
+        let start = self.position();
+
+        match name.as_str() {
+            "SM" => self.move_motors(argument(1)?, argument(2).unwrap_or(0)),
+            "LM" => self.move_motors(argument(1)?, argument(4).unwrap_or(0)),
+            "XM" => {
+                let (a, b) = (argument(1)?, argument(2).unwrap_or(0));
+                self.move_motors(a + b, a - b);
+            }
+            "HM" => {
+                self.motor1 = argument(1).unwrap_or(0) * self.step_scale;
+                self.motor2 = argument(2).unwrap_or(0) * self.step_scale;
+            }
+            "SP" => self.pen_down = argument(0)? == 0,
+            "TP" => self.pen_down = !self.pen_down,
+            "EM" => {
+                if let Some(mode @ 1..=5) = argument(0) {
+                    self.step_scale = 1 << (mode - 1);
+                }
+            }
+            "CS" => {
+                self.motor1 = 0;
+                self.motor2 = 0;
+            }
+            "R" => *self = Simulator::default(),
+            _ => {}
+        }
+
+        let end = self.position();
+
+        (start != end).then_some((start, end))
+    }
+
+    /// Returns commands that take a carriage at home to this position and
+    /// microstepping, leaving the pen as it is.
+    pub fn travel_from_home(&self, speed: f64) -> Vec<String> {
+        let mode = self.step_scale.trailing_zeros() + 1;
+        let motor_mode = format!("EM,{},{}", mode, mode);
+
+        let mut home = Simulator::default();
+        home.apply(&motor_mode);
+
+        let mut commands = vec![motor_mode];
+        commands.extend(home.move_to(self.position(), speed));
+
+        commands
+    }
+
+    /// Moves the tracked position by motor steps at the current microstepping,
+    /// without a command.
+    pub fn offset(&mut self, steps1: i64, steps2: i64) {
+        self.move_motors(steps1, steps2);
+    }
+
+    fn move_motors(&mut self, steps1: i64, steps2: i64) {
+        self.motor1 += steps1 * self.step_scale;
+        self.motor2 += steps2 * self.step_scale;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remainder_keeps_the_original_speed() {
+        assert_eq!(
+            remainder("SM,1000,400,-200", (100, 50)),
+            Some(("SM,250,100,-50".to_string(), (100, -50)))
+        );
+    }
+
+    #[test]
+    fn remainder_rounds_the_duration_up() {
+        assert_eq!(
+            remainder("SM,1000,3,0", (1, 0)),
+            Some(("SM,334,1,0".to_string(), (1, 0)))
+        );
+        assert_eq!(
+            remainder("SM,1000,400,0", (0, 0)),
+            Some(("SM,1,0,0".to_string(), (0, 0)))
+        );
+    }
+
+    #[test]
+    fn remainder_of_a_mixed_axis_move_is_in_motor_steps() {
+        assert_eq!(
+            remainder("XM,1000,300,-100", (200, 100)),
+            Some(("SM,500,200,100".to_string(), (200, 100)))
+        );
+    }
+
+    #[test]
+    fn remainder_is_only_for_motor_moves() {
+        assert_eq!(remainder("SP,1", (0, 0)), None);
+    }
+
+    #[test]
+    fn scale_speed_divides_durations() {
+        assert_eq!(
+            scale_speed("SM,1000,400,-200", 2.0),
+            Some("SM,500,400,-200".to_string())
+        );
+        assert_eq!(
+            scale_speed("XM,1000,300,100", 0.5),
+            Some("XM,2000,300,100".to_string())
+        );
+    }
+
+    #[test]
+    fn scale_speed_keeps_within_the_maximum_step_rate() {
+        assert_eq!(
+            scale_speed("SM,400,5000,0", 4.0),
+            Some("SM,200,5000,0".to_string())
+        );
+    }
+
+    #[test]
+    fn scale_speed_scales_low_level_accelerations_by_the_square() {
+        assert_eq!(
+            scale_speed("LM,1000,200,10,2000,-100,5,3", 2.0),
+            Some("LM,2000,200,40,4000,-100,20,3".to_string())
+        );
+    }
+
+    #[test]
+    fn scale_speed_is_only_for_moves() {
+        assert_eq!(scale_speed("SP,1", 2.0), None);
+        assert_eq!(scale_speed("LM,1000,200", 2.0), None);
+    }
+}
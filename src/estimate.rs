@@ -0,0 +1,90 @@
+use crate::ebb::{self, Simulator, TICKS_PER_SECOND};
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct Estimate {
+    pub duration: Duration,
+    /// Distance travelled with the pen down, in mm.
+    pub pen_down_distance: f64,
+    pub pen_lifts: u64,
+}
+
+/// Works out how long `commands` will take to plot from the state in
+/// `simulator`, using the durations the EBB would spend on each.
+pub fn estimate<'a>(
+    commands: impl IntoIterator<Item = &'a str>,
+    mut simulator: Simulator,
+) -> Estimate {
+    let mut estimate = Estimate::default();
+
+    for command in commands {
+        estimate.duration += command_duration(command, &simulator);
+
+        let was_pen_down = simulator.is_pen_down();
+        let movement = simulator.apply(command);
+
+        if let (Some((start, end)), true) = (movement, simulator.is_pen_down()) {
+            estimate.pen_down_distance += (end.0 - start.0).hypot(end.1 - start.1);
+        }
+
+        if was_pen_down && !simulator.is_pen_down() {
+            estimate.pen_lifts += 1;
+        }
+    }
+
+    estimate
+}
+
+fn command_duration(command: &str, simulator: &Simulator) -> Duration {
+    let (name, arguments) = ebb::parse(command);
+    let argument = |index: usize| arguments.get(index).copied().unwrap_or(0);
+
+    let milliseconds = match name.as_str() {
+        "SM" | "XM" => argument(0),
+        "SP" => argument(1),
+        "TP" => argument(0),
+        "HM" => {
+            let sm = simulator.home_move(argument(0), (argument(1), argument(2)));
+            ebb::parse(&sm).1[0]
+        }
+        "LM" => {
+            let seconds = low_level_move_seconds(argument(0), argument(1), argument(2)).max(
+                low_level_move_seconds(argument(3), argument(4), argument(5)),
+            );
+
+            return Duration::from_secs_f64(seconds);
+        }
+        _ => 0,
+    };
+
+    Duration::from_millis(milliseconds.max(0) as u64)
+}
+
+/// Solves for the time one `LM` axis takes to cover `steps`, where the rate is
+/// added to a 31-bit accumulator every tick and the acceleration to the rate.
+fn low_level_move_seconds(rate: i64, steps: i64, acceleration: i64) -> f64 {
+    let (rate, acceleration) = (rate as f64, acceleration as f64);
+    let distance = steps.unsigned_abs() as f64 * 2f64.powi(31);
+
+    if steps == 0 {
+        return 0.0;
+    }
+
+    let ticks = if acceleration == 0.0 {
+        distance / rate
+    } else {
+        let discriminant = rate * rate + 2.0 * acceleration * distance;
+
+        if discriminant < 0.0 {
+            return 0.0;
+        }
+
+        (discriminant.sqrt() - rate) / acceleration
+    };
+
+    if ticks.is_finite() {
+        ticks.max(0.0) / TICKS_PER_SECOND
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    audit::Origin,
+    axidraw_over_http::{
+        server_event, ClientEvent, ErrorEvent, ExecutedCommand, JobEvent, RunningStatus,
+        ServerEvent,
+    },
+};
+use serde::{Serialize, Serializer};
+use std::{sync::Arc, time::Duration, time::SystemTime};
+use tokio::sync::{broadcast::Sender, Mutex};
+
+/// How often the running status is checked for StatusChanged events.
+const STATUS_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    CommandExecuted {
+        sequence_number: u64,
+        sequence_id: Option<u64>,
+        command: String,
+        response: Vec<String>,
+        timestamp: SystemTime,
+        label: Option<String>,
+    },
+    StatusChanged {
+        #[serde(serialize_with = "serialize_status")]
+        running_status: RunningStatus,
+    },
+    BufferEmpty,
+    JobQueued {
+        id: u64,
+        name: String,
+    },
+    JobStarted {
+        id: u64,
+        name: String,
+    },
+    JobComplete {
+        id: u64,
+        name: String,
+    },
+    ButtonPressed,
+    Error {
+        message: String,
+    },
+    /// A command stream was opened.
+    ClientConnected {
+        rpc: String,
+        origin: Origin,
+    },
+    /// A command stream was closed or dropped.
+    ClientDisconnected {
+        rpc: String,
+        origin: Origin,
+    },
+}
+
+fn serialize_status<S: Serializer>(
+    running_status: &RunningStatus,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&running_status.as_str_name().to_lowercase())
+}
+
+impl Event {
+    pub fn to_proto(&self) -> ServerEvent {
+        let (timestamp, event) = match self.clone() {
+            Event::CommandExecuted {
+                sequence_number,
+                sequence_id,
+                command,
+                response,
+                timestamp,
+                label,
+            } => (
+                timestamp,
+                server_event::Event::CommandExecuted(ExecutedCommand {
+                    sequence_number,
+                    sequence_id,
+                    command,
+                    response,
+                    timestamp: Some(timestamp.into()),
+                    label,
+                }),
+            ),
+            Event::StatusChanged { running_status } => (
+                SystemTime::now(),
+                server_event::Event::StatusChanged(running_status as i32),
+            ),
+            Event::BufferEmpty => (SystemTime::now(), server_event::Event::BufferEmpty(true)),
+            Event::JobQueued { id, name } => (
+                SystemTime::now(),
+                server_event::Event::JobQueued(JobEvent { id, name }),
+            ),
+            Event::JobStarted { id, name } => (
+                SystemTime::now(),
+                server_event::Event::JobStarted(JobEvent { id, name }),
+            ),
+            Event::JobComplete { id, name } => (
+                SystemTime::now(),
+                server_event::Event::JobComplete(JobEvent { id, name }),
+            ),
+            Event::ButtonPressed => (SystemTime::now(), server_event::Event::ButtonPressed(true)),
+            Event::Error { message } => (
+                SystemTime::now(),
+                server_event::Event::Error(ErrorEvent { message }),
+            ),
+            Event::ClientConnected { rpc, origin } => (
+                SystemTime::now(),
+                server_event::Event::ClientConnected(origin.to_client_event(rpc)),
+            ),
+            Event::ClientDisconnected { rpc, origin } => (
+                SystemTime::now(),
+                server_event::Event::ClientDisconnected(origin.to_client_event(rpc)),
+            ),
+        };
+
+        ServerEvent {
+            timestamp: Some(timestamp.into()),
+            event: Some(event),
+        }
+    }
+}
+
+impl Origin {
+    fn to_client_event(&self, rpc: String) -> ClientEvent {
+        ClientEvent {
+            rpc,
+            caller: self.caller.clone(),
+            peer: self.peer.clone(),
+        }
+    }
+}
+
+/// Sends a StatusChanged event whenever the running status changes.
+pub fn watch_status(running_status: Arc<Mutex<RunningStatus>>, events: Sender<Event>) {
+    tokio::spawn(async move {
+        let mut last = *running_status.lock().await;
+
+        loop {
+            tokio::time::sleep(STATUS_CHECK_INTERVAL).await;
+
+            let current = *running_status.lock().await;
+
+            if current != last {
+                let _ = events.send(Event::StatusChanged {
+                    running_status: current,
+                });
+                last = current;
+            }
+        }
+    });
+}
@@ -0,0 +1,118 @@
+use crate::{
+    axidraw_over_http::{Command, Job},
+    AxidrawService,
+};
+use std::{collections::HashMap, io, path::Path, path::PathBuf, sync::Arc, time::Duration};
+use tokio::fs;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reads a newline-delimited command file into a job named after the file.
+pub async fn read_job(path: &Path) -> io::Result<Job> {
+    let contents = fs::read_to_string(path).await?;
+
+    let commands = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| Command {
+            contents: line.to_string(),
+            ..Default::default()
+        })
+        .collect();
+
+    Ok(Job {
+        name: path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        commands,
+        start_at: None,
+    })
+}
+
+/// Watches `dir` for command files, submitting each as a job once its size
+/// has stopped changing and then moving it into `loaded/`, or `failed/` if it
+/// couldn't be read. Hidden files are ignored so uploads can be renamed into
+/// place.
+pub fn spawn(dir: PathBuf, service: Arc<AxidrawService>) {
+    tokio::spawn(async move {
+        let mut sizes = HashMap::new();
+
+        loop {
+            let mut seen = HashMap::new();
+
+            for (path, size) in list_files(&dir).await {
+                if sizes.get(&path) == Some(&size) {
+                    load(&dir, &path, &service).await;
+                } else {
+                    seen.insert(path, size);
+                }
+            }
+
+            sizes = seen;
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+async fn list_files(dir: &Path) -> Vec<(PathBuf, u64)> {
+    let mut files = Vec::new();
+
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(error) => {
+            println!(
+                "Could not read watch directory {}: {}",
+                dir.display(),
+                error
+            );
+            return files;
+        }
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        if let Ok(metadata) = entry.metadata().await {
+            if metadata.is_file() {
+                files.push((entry.path(), metadata.len()));
+            }
+        }
+    }
+
+    files
+}
+
+async fn load(dir: &Path, path: &Path, service: &AxidrawService) {
+    let result = match read_job(path).await {
+        Ok(job) => service
+            .add_job(job)
+            .await
+            .map_err(|status| status.message().to_string()),
+        Err(error) => Err(error.to_string()),
+    };
+
+    let destination = match result {
+        Ok(id) => {
+            println!("Loaded {} as job {}", path.display(), id);
+            dir.join("loaded")
+        }
+        Err(error) => {
+            println!("Could not load {}: {}", path.display(), error);
+            dir.join("failed")
+        }
+    };
+
+    let moved = match fs::create_dir_all(&destination).await {
+        Ok(()) => fs::rename(path, destination.join(path.file_name().unwrap_or_default())).await,
+        Err(error) => Err(error),
+    };
+
+    if let Err(error) = moved {
+        println!("Could not move {}: {}", path.display(), error);
+    }
+}
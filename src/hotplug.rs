@@ -0,0 +1,61 @@
+use std::{thread::sleep, time::Duration};
+
+/// How long to wait between scans when hotplug notifications are unavailable.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Calls `scan` until it finds something, rescanning whenever a serial device
+/// is attached or detached.
+#[cfg(target_os = "linux")]
+pub fn wait_for<T>(mut scan: impl FnMut() -> Option<T>) -> T {
+    use std::os::unix::io::AsRawFd;
+
+    // Listen before the first scan so that nothing attached in between is missed.
+    let mut socket = libudev::Context::new().ok().and_then(|context| {
+        let mut monitor = libudev::Monitor::new(&context).ok()?;
+        monitor.match_subsystem("tty").ok()?;
+        monitor.listen().ok()
+    });
+
+    if socket.is_none() {
+        println!("udev unavailable, polling for serial devices");
+    }
+
+    loop {
+        if let Some(found) = scan() {
+            return found;
+        }
+
+        let Some(socket) = &mut socket else {
+            sleep(POLL_INTERVAL);
+            continue;
+        };
+
+        let mut poll_fd = libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        // SAFETY: `poll_fd` is a single valid pollfd that outlives the call.
+        if unsafe { libc::poll(&mut poll_fd, 1, -1) } < 0 {
+            sleep(POLL_INTERVAL);
+        }
+
+        while socket.receive_event().is_some() {}
+    }
+}
+
+/// Hotplug notifications are only implemented with udev, so other platforms
+/// always poll.
+#[cfg(not(target_os = "linux"))]
+pub fn wait_for<T>(mut scan: impl FnMut() -> Option<T>) -> T {
+    println!("Hotplug notifications are only supported on Linux, polling for serial devices");
+
+    loop {
+        if let Some(found) = scan() {
+            return found;
+        }
+
+        sleep(POLL_INTERVAL);
+    }
+}
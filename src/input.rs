@@ -0,0 +1,76 @@
+use crate::{axidraw_over_http::Command, AxidrawService};
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    fs::File,
+    io::{self, AsyncBufReadExt, AsyncRead, BufReader},
+};
+
+/// Feeds newline-delimited commands from `path` into the buffer, or from stdin
+/// if `path` is `-`. A FIFO is reopened after each writer finishes.
+pub fn spawn(path: PathBuf, service: Arc<AxidrawService>) {
+    let is_stdin = path.as_os_str() == "-";
+    let is_fifo = !is_stdin && is_fifo(&path);
+
+    tokio::spawn(async move {
+        loop {
+            let reader: Box<dyn AsyncRead + Send + Unpin> = if is_stdin {
+                Box::new(io::stdin())
+            } else {
+                match File::open(&path).await {
+                    Ok(file) => Box::new(file),
+                    Err(error) => {
+                        println!("Could not open input {}: {}", path.display(), error);
+                        break;
+                    }
+                }
+            };
+
+            read_commands(reader, &service).await;
+
+            if !is_fifo {
+                break;
+            }
+        }
+    });
+}
+
+async fn read_commands(reader: impl AsyncRead + Unpin, service: &AxidrawService) {
+    let mut lines = BufReader::new(reader).lines();
+    let mut throttle = service.watermarks.subscribe();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let contents = line.trim();
+
+        if contents.is_empty() {
+            continue;
+        }
+
+        // Hold off reading while the buffer is above its high watermark.
+        while *throttle.borrow_and_update() {
+            if throttle.changed().await.is_err() {
+                break;
+            }
+        }
+
+        let command = Command {
+            contents: contents.to_string(),
+            ..Default::default()
+        };
+
+        if let Err(status) = service.enqueue_streamed(command).await {
+            println!("Rejected input {}: {}", contents, status.message());
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &std::path::Path) -> bool {
+    false
+}
@@ -0,0 +1,37 @@
+use crate::ebb;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Holds back motion until the machine is armed, after startup and after any
+/// error, when arming is required.
+pub struct Interlock {
+    required: bool,
+    armed: AtomicBool,
+}
+
+impl Interlock {
+    pub fn new(required: bool) -> Self {
+        Interlock {
+            required,
+            armed: AtomicBool::new(!required),
+        }
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed.load(Ordering::SeqCst)
+    }
+
+    pub fn arm(&self) {
+        self.armed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn disarm(&self) {
+        if self.required {
+            self.armed.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether `command` can be sent now. Queries are always allowed.
+    pub fn allows(&self, command: &str) -> bool {
+        self.is_armed() || !ebb::is_motion(command)
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{
+    axidraw_over_http::{Command, JobInfo, JobState},
+    ebb::Simulator,
+};
+use std::time::SystemTime;
+
+pub struct Job {
+    pub id: u64,
+    pub name: String,
+    pub state: JobState,
+    pub start_at: Option<SystemTime>,
+    /// The job's commands as submitted, kept so it can be resumed part way through.
+    pub commands: Vec<Command>,
+    pub executed_count: u64,
+    /// Where the carriage was when the job started, which its moves carry on
+    /// from.
+    pub start: Option<Simulator>,
+}
+
+impl Job {
+    pub fn is_waiting_for_start_time(&self) -> bool {
+        self.state == JobState::Scheduled
+            && self
+                .start_at
+                .is_some_and(|start_at| start_at > SystemTime::now())
+    }
+
+    pub fn progress(&self) -> f64 {
+        if self.commands.is_empty() {
+            return 100.0;
+        }
+
+        (self.executed_count as f64 / self.commands.len() as f64 * 100.0).min(100.0)
+    }
+
+    pub fn to_info(&self) -> JobInfo {
+        JobInfo {
+            id: self.id,
+            name: self.name.clone(),
+            state: self.state as i32,
+            start_at: self.start_at.map(Into::into),
+            command_count: self.commands.len() as u64,
+            executed_count: self.executed_count,
+        }
+    }
+}
+
+/// Whether the command is a breakpoint, layer or tool change rather than
+/// something sent to the EBB.
+fn is_marker(command: &Command) -> bool {
+    command.breakpoint.is_some() || command.layer.is_some() || command.tool_change.is_some()
+}
+
+/// Index into `commands` just after the last of the first `executed_count`
+/// commands sent, since markers aren't counted as executed.
+pub fn index_after(commands: &[Command], executed_count: u64) -> usize {
+    let Some(last) = (executed_count as usize).checked_sub(1) else {
+        return 0;
+    };
+
+    commands
+        .iter()
+        .enumerate()
+        .filter(|(_, command)| !is_marker(command))
+        .nth(last)
+        .map_or(commands.len(), |(index, _)| index + 1)
+}
+
+/// How many commands before `index` are sent, not counting markers.
+pub fn executed_before(commands: &[Command], index: usize) -> u64 {
+    commands[..index.min(commands.len())]
+        .iter()
+        .filter(|command| !is_marker(command))
+        .count() as u64
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    last_id: u64,
+    jobs: Vec<Job>,
+    /// ID of the job just finished, while paused before the next one.
+    pub awaiting_next_after: Option<u64>,
+}
+
+impl Jobs {
+    pub fn add(
+        &mut self,
+        name: String,
+        start_at: Option<SystemTime>,
+        commands: Vec<Command>,
+    ) -> u64 {
+        self.last_id += 1;
+
+        let state = if start_at.is_some_and(|start_at| start_at > SystemTime::now()) {
+            JobState::Scheduled
+        } else {
+            JobState::Queued
+        };
+
+        self.jobs.push(Job {
+            id: self.last_id,
+            name,
+            state,
+            start_at,
+            commands,
+            executed_count: 0,
+            start: None,
+        });
+
+        self.last_id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    pub fn current(&self) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.state == JobState::Started)
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut Job> {
+        self.jobs
+            .iter_mut()
+            .find(|job| job.state == JobState::Started)
+    }
+
+    pub fn set_state(&mut self, id: u64, state: JobState) {
+        if let Some(job) = self.get_mut(id) {
+            job.state = state;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+}
@@ -0,0 +1,167 @@
+use crate::events::Event;
+use prost_types::Timestamp;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+/// When to start a new log file, and how many old ones to keep.
+pub struct Rotation {
+    pub max_size: u64,
+    pub max_age: Option<Duration>,
+    /// Rotated files are named `<log-file>.1` (newest) to `<log-file>.<keep>`.
+    pub keep: usize,
+}
+
+/// Lets the log file be reopened after it's been moved, such as by logrotate.
+#[derive(Clone)]
+pub struct LogFileHandle {
+    reopen: Arc<AtomicBool>,
+}
+
+impl LogFileHandle {
+    /// Reopens the log file before the next line is written.
+    pub fn reopen(&self) {
+        self.reopen.store(true, Ordering::SeqCst);
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+    reopen: Arc<AtomicBool>,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, rotation: Rotation) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(LogFile {
+            path,
+            rotation,
+            file,
+            size,
+            opened_at: Instant::now(),
+            reopen: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.reopen.swap(false, Ordering::SeqCst) {
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.size = self.file.metadata()?.len();
+        }
+
+        let expired = self
+            .rotation
+            .max_age
+            .is_some_and(|max_age| self.opened_at.elapsed() >= max_age);
+
+        if self.size > 0 && (self.size + line.len() as u64 >= self.rotation.max_size || expired) {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |index: usize| numbered_path(&self.path, index);
+
+        let _ = fs::remove_file(numbered(self.rotation.keep));
+        for index in (1..self.rotation.keep).rev() {
+            let _ = fs::rename(numbered(index), numbered(index + 1));
+        }
+
+        if self.rotation.keep > 0 {
+            fs::rename(&self.path, numbered(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+
+        Ok(())
+    }
+}
+
+fn numbered_path(path: &Path, index: usize) -> PathBuf {
+    let mut numbered = path.as_os_str().to_owned();
+    numbered.push(format!(".{}", index));
+    numbered.into()
+}
+
+/// Writes executed commands, their responses and other events to `path`.
+pub fn spawn(
+    path: PathBuf,
+    rotation: Rotation,
+    mut events: Receiver<Event>,
+) -> io::Result<LogFileHandle> {
+    let mut log_file = LogFile::open(path, rotation)?;
+    let handle = LogFileHandle {
+        reopen: log_file.reopen.clone(),
+    };
+
+    thread::spawn(move || loop {
+        let event = match events.blocking_recv() {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                let _ = log_file.write_line(&format!("Skipped {} events", skipped));
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let line = match &event {
+            Event::CommandExecuted {
+                command,
+                response,
+                timestamp,
+                label,
+                ..
+            } => format!(
+                "{} {} -> {}{}",
+                Timestamp::from(*timestamp),
+                command,
+                response.join(" "),
+                label
+                    .as_ref()
+                    .map(|label| format!(" ({})", label))
+                    .unwrap_or_default()
+            ),
+            _ => format!(
+                "{} {}",
+                Timestamp::from(SystemTime::now()),
+                serde_json::to_string(&event).unwrap_or_default()
+            ),
+        };
+
+        if let Err(error) = log_file.write_line(&line) {
+            println!("Failed to write log file: {}", error);
+        }
+    });
+
+    Ok(handle)
+}
@@ -1,227 +1,3342 @@
+// tonic::Status is the error type of every RPC, and the internal helpers
+// return it so that `?` carries their errors straight to the client. It's
+// large, but boxing it in each helper only to unbox it at the RPC boundary
+// isn't worth it.
+#![allow(clippy::result_large_err)]
+
+use audit::{AuditRecorder, Origin};
+use auth::{Role, SharedTokens};
 use axidraw_over_http::{
     axidraw_over_http_server::{AxidrawOverHttp, AxidrawOverHttpServer},
-    BufferState, Command, Empty, RunningStatus,
+    estimate_request, work_area_request, AuditLog, BufferExport, BufferPage, BufferPageRequest,
+    BufferState, BufferedCommand, ClearedCount, Command, CommandBatch, CommandCategory,
+    CommandResponse, DeviceInfo, DeviceRequest, Empty, Estimate, EstimateRequest, ExecutedCommand,
+    FileChunk, FilePath, FlowControl, InFlightCommand, Job, JobId, JobList, JobOrder, JobState,
+    JogRequest, MachineConfig, Macro, MacroList, MacroName, Nickname, PauseMode, PauseRequest,
+    PenRequest, Position, Preview, PreviewRequest, RecoverJobRequest, RecoveryAction,
+    RecoveryState, RecoveryStep, ReplayRequest, ResumeFromRequest, RunningStatus, SequenceId,
+    SequenceRange, SerialDeviceList, ServerEvent, ServerInfo, SpeedFactor, Statistics, Tag,
+    TeleopDelta, TerminalInput, TerminalOutput, Tool, ToolList, ToolName, ToolPower, ToolRequest,
+    UploadId, UploadProgress, WorkAreaList, WorkAreaRequest,
 };
-use clap::Parser;
-use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+use clap::{Parser, Subcommand, ValueEnum};
+use config::Settings;
+use consumer::{Consumer, ImmediateStop};
+use dashboard::Cors;
+use device::{DeviceStatus, MachineSettings, MatchRule};
+use dryrun::{VirtualClock, VirtualEbb};
+use ebb::{Point, Simulator};
+use events::Event;
+use interlock::Interlock;
+use jobs::Jobs;
+use logfile::Rotation;
+use mqtt::MqttPublisher;
+use multiplex::MultiplexLayer;
+use preview::PathTracer;
+use recovery::Recovery;
+use serialport::SerialPort;
+use servo::ServoProfile;
+use statistics::PlotStatistics;
 use std::{
-    collections::VecDeque,
-    io::{prelude::*, BufRead, BufReader, BufWriter},
+    collections::{BTreeMap, VecDeque},
+    fs,
+    future::Future,
+    io,
     net::IpAddr,
+    path::PathBuf,
+    pin::Pin,
     str::FromStr,
-    sync::Arc,
-    thread::{sleep, spawn},
-    time::Duration,
-};
-use tokio::{
-    join,
     sync::{
-        mpsc::{unbounded_channel, UnboundedSender},
-        Mutex,
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
     },
+    thread::spawn,
+    time::{Duration, Instant, SystemTime},
+};
+use store::{PersistentState, Store};
+use tokio::sync::{
+    broadcast,
+    mpsc::{self, unbounded_channel, UnboundedSender},
+    oneshot, Mutex, OwnedMutexGuard,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, ReceiverStream},
+    Stream, StreamExt,
 };
-use tokio_stream::StreamExt;
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{
+    service::interceptor::InterceptedService, transport::Server, Request, Response, Status,
+};
+use tonic_web::GrpcWebLayer;
+use toolchange::ToolChanges;
+use tracing::{info_span, instrument, Span};
+use upload::Uploads;
+use validation::MotionLimits;
+use watermarks::Watermarks;
+use workarea::WorkArea;
+
+mod audit;
+mod auth;
+mod client;
+mod config;
+mod consumer;
+mod dashboard;
+mod device;
+mod dryrun;
+mod ebb;
+mod estimate;
+mod events;
+mod hotfolder;
+mod hotplug;
+mod input;
+mod interlock;
+mod jobs;
+mod logfile;
+mod metrics;
+mod monitor;
+mod mqtt;
+mod multiplex;
+mod preview;
+mod recovery;
+mod servo;
+mod session;
+mod signals;
+mod statistics;
+mod store;
+mod telemetry;
+mod teleop;
+mod template;
+mod terminal;
+mod tool;
+mod toolchange;
+mod upload;
+mod validation;
+mod watermarks;
+mod webhooks;
+mod workarea;
+
+mod axidraw_over_http {
+    tonic::include_proto!("axidraw_over_http");
+}
+
+/// Pen-up travel speed for generated moves, in mm/s.
+const TRAVEL_SPEED: f64 = 50.0;
+
+/// Bumped whenever RPCs are added or their behaviour changes, so clients can
+/// tell what an older server supports.
+const API_VERSION: u32 = 1;
+
+/// Page size for PeekBuffer when none is given, and the most it returns.
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+
+/// How long to wait for the current command to finish when shutting down.
+const PARK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Range accepted by SetSpeedFactor.
+const MIN_SPEED_FACTOR: f64 = 0.1;
+const MAX_SPEED_FACTOR: f64 = 2.0;
+
+enum ControlMessage {
+    CheckBuffer,
+    CheckPower,
+    /// Switch to a newly opened serial port, once any running command finishes.
+    SwitchPort(Box<dyn SerialPort>),
+}
+
+struct QueuedCommand {
+    contents: String,
+    sequence_id: Option<u64>,
+    tag: Option<String>,
+    label: Option<String>,
+    /// Longest to wait for the EBB's response, overriding the configured timeout.
+    max_duration: Option<Duration>,
+    /// Spans the command's time in the buffer through to its acknowledgement.
+    span: Span,
+}
+
+impl QueuedCommand {
+    fn new(contents: String) -> Self {
+        QueuedCommand {
+            span: info_span!("command", contents = %contents),
+            contents,
+            sequence_id: None,
+            tag: None,
+            label: None,
+            max_duration: None,
+        }
+    }
+}
+
+struct UrgentCommand {
+    command: QueuedCommand,
+    /// Receives the EBB's response, for commands run through `Execute`.
+    responder: Option<oneshot::Sender<io::Result<Vec<String>>>>,
+}
+
+enum BufferEntry {
+    Command(QueuedCommand),
+    Breakpoint(String),
+    Layer(String),
+    JobStart(u64),
+    JobEnd(u64),
+    /// Parks for a tool change, carrying on once it's confirmed if `resume`.
+    ToolChange {
+        tool: String,
+        resume: bool,
+    },
+}
+
+#[derive(Default)]
+struct Layers {
+    current: Option<String>,
+    /// Layer whose boundary paused plotting, becoming current on resume.
+    pending: Option<String>,
+}
+
+#[derive(Clone)]
+struct AxidrawService {
+    control_message_sender: UnboundedSender<ControlMessage>,
+    command_buffer: Arc<Mutex<VecDeque<BufferEntry>>>,
+    urgent_buffer: Arc<Mutex<VecDeque<UrgentCommand>>>,
+    /// Locked before the buffer, breakpoint and layers, as the consumer does.
+    running_status: Arc<Mutex<RunningStatus>>,
+    breakpoint: Arc<Mutex<Option<String>>>,
+    layers: Arc<Mutex<Layers>>,
+    jobs: Arc<Mutex<Jobs>>,
+    plotted: Arc<Mutex<PathTracer>>,
+    macros: Arc<Mutex<BTreeMap<String, Macro>>>,
+    last_sequence_id: Arc<Mutex<Option<u64>>>,
+    events: broadcast::Sender<Event>,
+    watermarks: Arc<Watermarks>,
+    /// Width and height of the machine's travel, in mm.
+    machine_size: Point,
+    device_status: Arc<Mutex<DeviceStatus>>,
+    match_rules: Arc<[MatchRule]>,
+    statistics: Arc<Mutex<PlotStatistics>>,
+    disconnect_policy: DisconnectPolicy,
+    audit: Arc<Mutex<AuditRecorder>>,
+    immediate_stop: Arc<ImmediateStop>,
+    max_buffer_length: Arc<Mutex<Option<usize>>>,
+    overflow_policy: OverflowPolicy,
+    started_at: Instant,
+    /// Optional features enabled on the command line, for GetServerInfo.
+    features: Arc<[String]>,
+    /// Hot folder, which relative LoadFile paths are resolved against.
+    watch_dir: Option<PathBuf>,
+    /// The running status from before the open terminal paused plotting, if
+    /// there is one.
+    terminal: Arc<Mutex<Option<RunningStatus>>>,
+    speed_factor: Arc<Mutex<f64>>,
+    interlock: Arc<Interlock>,
+    /// What SetToolPower switches.
+    tool: Arc<Mutex<Tool>>,
+    /// Limits moves are checked against, if validation is enabled.
+    motion_limits: Arc<RwLock<Option<MotionLimits>>>,
+    /// The sheet that jogs and positions are relative to.
+    work_area: Arc<Mutex<WorkArea>>,
+    work_area_presets: Arc<Mutex<BTreeMap<String, WorkArea>>>,
+    /// How the server's own pen moves drive the servo.
+    servo_profile: Arc<Mutex<ServoProfile>>,
+    tool_changes: Arc<Mutex<ToolChanges>>,
+    /// The interrupted job found in --data-dir at startup, until it's
+    /// recovered or abandoned.
+    recovery: Arc<Mutex<Option<Recovery>>>,
+    /// Where UploadFile saves files, if anywhere.
+    uploads: Option<Arc<Uploads>>,
+    /// Time simulated by the virtual EBB, with --dry-run.
+    dry_run_clock: Option<Arc<VirtualClock>>,
+    /// When a timed pause will resume.
+    resume_at: Arc<Mutex<Option<Instant>>>,
+    /// The command being sent from the buffer, for GetState.
+    in_flight: Arc<Mutex<Option<InFlightCommand>>>,
+    /// Device that FailOver switches to.
+    backup_device: Option<String>,
+    /// Set to run the next command while paused.
+    step: Arc<AtomicBool>,
+    tokens: SharedTokens,
+    webhooks: Arc<Mutex<Vec<String>>>,
+    /// Config file reloaded by ReloadConfig, over the command line settings.
+    config: Option<PathBuf>,
+    cli_settings: Settings,
+}
+
+impl AxidrawService {
+    /// Locks the buffer once there's room for `count` more entries, according
+    /// to the overflow policy.
+    async fn lock_buffer_for_push(
+        &self,
+        count: usize,
+    ) -> Result<OwnedMutexGuard<VecDeque<BufferEntry>>, Status> {
+        let mut length = self.watermarks.subscribe_length();
+
+        loop {
+            let max_length = *self.max_buffer_length.lock().await;
+            let mut buffer = self.command_buffer.clone().lock_owned().await;
+
+            let Some(max_length) = max_length else {
+                return Ok(buffer);
+            };
+
+            if buffer.len() + count <= max_length {
+                return Ok(buffer);
+            }
+
+            match self.overflow_policy {
+                OverflowPolicy::Block if count > max_length => {
+                    return Err(Status::resource_exhausted(format!(
+                        "{} entries won't fit in a buffer of {}",
+                        count, max_length
+                    )))
+                }
+                OverflowPolicy::Reject => {
+                    return Err(Status::resource_exhausted(format!(
+                        "Buffer is full at {} entries",
+                        max_length
+                    )))
+                }
+                OverflowPolicy::Block => {
+                    length.borrow_and_update();
+                    drop(buffer);
+
+                    let _ = length.changed().await;
+                }
+                OverflowPolicy::DropOldest => {
+                    // Markers are kept so that jobs and layers stay intact.
+                    while buffer.len() + count > max_length {
+                        let Some(index) = buffer
+                            .iter()
+                            .position(|entry| matches!(entry, BufferEntry::Command(_)))
+                        else {
+                            break;
+                        };
+
+                        buffer.remove(index);
+                    }
+
+                    return Ok(buffer);
+                }
+            }
+        }
+    }
+
+    /// Where the carriage will be once everything in the buffer has run.
+    async fn queued_end(&self) -> Simulator {
+        let buffer = self.command_buffer.lock().await;
+        let mut end = self.plotted.lock().await.simulator().clone();
+
+        for entry in buffer.iter() {
+            if let BufferEntry::Command(command) = entry {
+                end.apply(&command.contents);
+            }
+        }
+
+        end
+    }
+
+    /// Converts a command from a client, checking moves against the motion
+    /// limits and the connected firmware. If `end` is given, it is where the
+    /// carriage will be when the command runs: it's moved on by the command,
+    /// which mustn't take it out of the work area.
+    async fn accept_entry(
+        &self,
+        command: Command,
+        end: Option<&mut Simulator>,
+    ) -> Result<BufferEntry, Status> {
+        let entry = to_buffer_entry(command)?;
+
+        if let BufferEntry::Command(command) = &entry {
+            if let Some(motion_limits) = &*self.motion_limits.read().unwrap() {
+                motion_limits
+                    .check(&command.contents)
+                    .map_err(Status::invalid_argument)?;
+            }
+
+            if let Some(end) = end {
+                end.apply(&command.contents);
+
+                self.work_area
+                    .lock()
+                    .await
+                    .check_position(self.machine_size, end.position(), end.is_pen_down())
+                    .map_err(|error| {
+                        Status::out_of_range(format!("{}: {}", command.contents, error))
+                    })?;
+            }
+
+            // HM is sent as an SM on firmware without it.
+            let name = ebb::parse(&command.contents).0;
+            if name != "HM" && !self.device_status.lock().await.capabilities.supports(&name) {
+                return Err(Status::failed_precondition(format!(
+                    "{} is not supported by this firmware",
+                    name
+                )));
+            }
+        }
+
+        Ok(entry)
+    }
+
+    async fn accept_command(
+        &self,
+        command: Command,
+        end: &mut Simulator,
+    ) -> Result<QueuedCommand, Status> {
+        match self.accept_entry(command, Some(end)).await? {
+            BufferEntry::Command(command) => Ok(command),
+            _ => Err(Status::invalid_argument("Expected a command, not a marker")),
+        }
+    }
+
+    async fn enqueue_streamed(&self, command: Command) -> Result<(), Status> {
+        let sequence_id = command.sequence_id;
+        let mut end = self.queued_end().await;
+        let entry = self.accept_entry(command, Some(&mut end)).await?;
+
+        let mut buffer = self.lock_buffer_for_push(1).await?;
+        let mut last_sequence_id = self.last_sequence_id.lock().await;
+
+        if let Some(sequence_id) = sequence_id {
+            if last_sequence_id.is_some_and(|last| sequence_id <= last) {
+                return Ok(());
+            }
+
+            *last_sequence_id = Some(sequence_id);
+        }
+
+        buffer.push_back(entry);
+        self.watermarks.update(buffer.len());
+        drop(last_sequence_id);
+        drop(buffer);
+
+        self.check_buffer_if_running().await;
+
+        Ok(())
+    }
+
+    /// Queues a job, returning its ID.
+    async fn add_job(&self, job: Job) -> Result<u64, Status> {
+        let start_at = job
+            .start_at
+            .map(SystemTime::try_from)
+            .transpose()
+            .map_err(|_| Status::invalid_argument("Invalid start time"))?;
+
+        let mut end = self.queued_end().await;
+        let mut entries = Vec::with_capacity(job.commands.len());
+        for command in &job.commands {
+            entries.push(self.accept_entry(command.clone(), Some(&mut end)).await?);
+        }
+
+        // Room for the job's start and end markers too.
+        let mut buffer = self.lock_buffer_for_push(entries.len() + 2).await?;
+        let id = self
+            .jobs
+            .lock()
+            .await
+            .add(job.name.clone(), start_at, job.commands);
+        let _ = self.events.send(Event::JobQueued { id, name: job.name });
+
+        buffer.push_back(BufferEntry::JobStart(id));
+        buffer.extend(entries);
+        buffer.push_back(BufferEntry::JobEnd(id));
+        self.watermarks.update(buffer.len());
+        drop(buffer);
+
+        if let Some(delay) =
+            start_at.and_then(|start_at| start_at.duration_since(SystemTime::now()).ok())
+        {
+            let jobs = self.jobs.clone();
+            let control_message_sender = self.control_message_sender.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+
+                let mut jobs = jobs.lock().await;
+                if jobs.get(id).map(|job| job.state) == Some(JobState::Scheduled) {
+                    jobs.set_state(id, JobState::Queued);
+                }
+                drop(jobs);
+
+                let _ = control_message_sender.send(ControlMessage::CheckBuffer);
+            });
+        }
+
+        self.check_buffer_if_running().await;
+
+        Ok(id)
+    }
+
+    /// Holds the commands in a saved upload as a job until ReleaseJob,
+    /// returning its ID.
+    async fn hold_upload(&self, path: PathBuf) -> Result<u64, Status> {
+        let commands = upload::read_commands(&path)
+            .await
+            .map_err(|error| Status::internal(error.to_string()))?;
+
+        // Checked now so that a bad file is reported with the upload.
+        // Moves are checked against the work area once the job is released.
+        for command in &commands {
+            self.accept_entry(command.clone(), None).await?;
+        }
+
+        let name = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+
+        let mut jobs = self.jobs.lock().await;
+        let id = jobs.add(name.clone(), None, commands);
+        jobs.set_state(id, JobState::Held);
+
+        println!("Holding job {} from upload {}", id, name);
+
+        Ok(id)
+    }
+
+    /// Opens `device`, or `nickname:NAME`, and has the consumer use it from its
+    /// next command. Plotting should be paused.
+    async fn switch_device(&self, device: String) -> Result<DeviceInfo, Status> {
+        let match_rules = self.match_rules.clone();
+
+        let serial_port =
+            tokio::task::spawn_blocking(move || find_serial_port(&Some(device), &match_rules))
+                .await
+                .unwrap();
+
+        let serial_port = match serial_port {
+            Some(Ok(serial_port)) => serial_port,
+            Some(Err(error)) => {
+                return Err(Status::unavailable(format!(
+                    "Could not open port: {}",
+                    error
+                )));
+            }
+            None => return Err(Status::not_found("No such device")),
+        };
+
+        let device_status = self.device_status.clone();
+        let (serial_port, info) = tokio::task::spawn_blocking(move || {
+            let mut device_status = device_status.blocking_lock();
+            device::connect(&*serial_port, &mut device_status);
+
+            (serial_port, device_status.to_info())
+        })
+        .await
+        .unwrap();
+
+        println!("Switched to serial connection {}", info.port_name);
+
+        // The consumer handles messages in order, so it switches before it
+        // goes back to the buffer.
+        self.control_message_sender
+            .send(ControlMessage::SwitchPort(serial_port))
+            .unwrap();
+
+        Ok(info)
+    }
+
+    /// Pauses, then raises the pen and turns the motors off once the current
+    /// command has finished.
+    async fn park(&self) {
+        *self.running_status.lock().await = RunningStatus::Paused;
+
+        let pen_up = self.servo_profile.lock().await.pen(false);
+
+        let park = async {
+            for contents in [pen_up, "EM,0,0".to_string()] {
+                self.execute_now_unchecked(QueuedCommand::new(contents))
+                    .await?;
+            }
+
+            Ok::<_, Status>(())
+        };
+
+        match tokio::time::timeout(PARK_TIMEOUT, park).await {
+            Ok(Ok(())) => {}
+            Ok(Err(status)) => println!("Could not park: {}", status.message()),
+            Err(_) => println!("Timed out parking"),
+        }
+    }
+
+    /// Carries on from a pause, cancelling any timed resume.
+    async fn resume_plotting(&self) -> Result<(), Status> {
+        let mut running_status = self.running_status.clone().lock_owned().await;
+
+        if self.terminal.lock().await.is_some() {
+            return Err(Status::failed_precondition(
+                "Close the terminal before resuming",
+            ));
+        }
+
+        if self.tool_changes.lock().await.pending.is_some() {
+            return Err(Status::failed_precondition(
+                "Confirm the tool change before resuming",
+            ));
+        }
+
+        *self.resume_at.lock().await = None;
+
+        if *running_status == RunningStatus::Paused {
+            *running_status = RunningStatus::Running;
+            *self.breakpoint.lock().await = None;
+            self.jobs.lock().await.awaiting_next_after = None;
+
+            let mut layers = self.layers.lock().await;
+            if let Some(layer) = layers.pending.take() {
+                layers.current = Some(layer);
+            }
+            drop(layers);
+
+            self.control_message_sender
+                .send(ControlMessage::CheckBuffer)
+                .unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Re-reads the config file and applies its settings over the command line's.
+    pub async fn reload_config(&self) -> Result<(), Status> {
+        let Some(config) = &self.config else {
+            return Err(Status::failed_precondition("No config file was given"));
+        };
+
+        let settings = self
+            .cli_settings
+            .clone()
+            .with_file(config)
+            .map_err(|error| {
+                Status::invalid_argument(format!("Could not read {}: {}", config.display(), error))
+            })?;
+
+        *self.tokens.write().unwrap() = settings.tokens();
+        *self.motion_limits.write().unwrap() = settings.motion_limits();
+        *self.max_buffer_length.lock().await = settings.max_buffer;
+        self.watermarks
+            .set_levels(settings.high_watermark, settings.low_watermark);
+        *self.webhooks.lock().await = settings.webhooks;
+        *self.work_area_presets.lock().await = workarea::presets(&settings.work_areas);
+
+        let mut tool_changes = self.tool_changes.lock().await;
+        tool_changes.tools = settings.tools;
+        tool_changes.change_position = settings.tool_change_position;
+        drop(tool_changes);
+
+        let servo_commands = settings.servo_profile.commands();
+        *self.servo_profile.lock().await = settings.servo_profile;
+
+        for contents in servo_commands {
+            self.execute_now_unchecked(QueuedCommand::new(contents))
+                .await?;
+        }
+
+        println!("Reloaded {}", config.display());
+
+        Ok(())
+    }
+
+    fn check_armed(&self, command: &QueuedCommand) -> Result<(), Status> {
+        if self.interlock.allows(&command.contents) {
+            Ok(())
+        } else {
+            Err(Status::failed_precondition(format!(
+                "{} moves the machine, which isn't armed",
+                command.contents
+            )))
+        }
+    }
+
+    /// Runs a command through the urgent lane and waits for its response.
+    async fn execute_now(&self, command: QueuedCommand) -> Result<Vec<String>, Status> {
+        self.check_armed(&command)?;
+        self.execute_now_unchecked(command).await
+    }
+
+    /// Like `execute_now`, but allowed while disarmed, for the server's own moves.
+    async fn execute_now_unchecked(&self, command: QueuedCommand) -> Result<Vec<String>, Status> {
+        let (responder, response) = oneshot::channel();
+
+        self.urgent_buffer.lock().await.push_back(UrgentCommand {
+            command,
+            responder: Some(responder),
+        });
+        self.control_message_sender
+            .send(ControlMessage::CheckBuffer)
+            .unwrap();
+
+        response
+            .await
+            .map_err(|_| Status::aborted("Command was not executed"))?
+            .map_err(|error| Status::unavailable(format!("Serial error: {}", error)))
+    }
+
+    /// Moves the carriage relative to its current position, clamped to the
+    /// machine's bounds.
+    async fn jog_by(&self, dx: f64, dy: f64, speed: Option<f64>) -> Result<(), Status> {
+        let speed = match speed {
+            Some(speed) if speed > 0.0 => speed.min(ebb::MAX_SPEED),
+            Some(_) => return Err(Status::invalid_argument("Speed must be positive")),
+            None => TRAVEL_SPEED,
+        };
+
+        let command = {
+            let work_area = self.work_area.lock().await;
+            let plotted = self.plotted.lock().await;
+            let (x, y) = plotted.simulator().position();
+            let (dx, dy) = work_area.machine_delta((dx, dy));
+            let target = work_area.clamp(self.machine_size, (x + dx, y + dy));
+
+            plotted.simulator().move_to(target, speed)
+        };
+
+        if let Some(command) = command {
+            self.execute_now(QueuedCommand::new(command)).await?;
+        }
+
+        Ok(())
+    }
+
+    fn uploads(&self) -> Result<Arc<Uploads>, Status> {
+        self.uploads
+            .clone()
+            .ok_or_else(|| Status::failed_precondition("Uploads need --upload-dir or --data-dir"))
+    }
+
+    async fn recovery_state(&self) -> RecoveryState {
+        let recovery = self.recovery.lock().await;
+
+        let Some(recovery) = recovery.as_ref() else {
+            return RecoveryState::default();
+        };
+
+        let target = recovery.target();
+        let (x, y) = self
+            .work_area
+            .lock()
+            .await
+            .position_of(self.machine_size, target.position());
+
+        RecoveryState {
+            job_name: Some(recovery.progress.name.clone()),
+            command_count: recovery.commands.len() as u64,
+            saved_progress: recovery.progress.executed_count,
+            index: recovery.index as u64,
+            step: recovery.step as i32,
+            position: Some(Position {
+                x,
+                y,
+                pen_down: target.is_pen_down(),
+            }),
+            instructions: recovery.instructions().to_string(),
+        }
+    }
+
+    async fn position(&self) -> Position {
+        let work_area = self.work_area.lock().await;
+        let plotted = self.plotted.lock().await;
+        let (x, y) = work_area.position_of(self.machine_size, plotted.simulator().position());
+
+        Position {
+            x,
+            y,
+            pen_down: plotted.simulator().is_pen_down(),
+        }
+    }
+
+    /// Checks that the caller is an operator, recording the attempt in the
+    /// audit log either way.
+    /// Not an `async fn`, so that streaming requests aren't held across the
+    /// await.
+    fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        action: &'static str,
+    ) -> impl Future<Output = Result<(), Status>> + '_ {
+        let result = auth::require(request, Role::Operator);
+        let origin = Origin::of(request);
+
+        async move {
+            self.audit
+                .lock()
+                .await
+                .record(&origin, action, result.is_err());
+
+            result
+        }
+    }
+
+    /// Pauses the buffer so a terminal has the EBB to itself.
+    async fn open_terminal(&self) -> Result<(), Status> {
+        let mut running_status = self.running_status.lock().await;
+        let mut terminal = self.terminal.lock().await;
+
+        if terminal.is_some() {
+            return Err(Status::failed_precondition("A terminal is already open"));
+        }
+
+        *terminal = Some(std::mem::replace(
+            &mut *running_status,
+            RunningStatus::Paused,
+        ));
+
+        Ok(())
+    }
+
+    /// Restores the running status from before the terminal was opened.
+    async fn close_terminal(&self) {
+        let mut running_status = self.running_status.lock().await;
+        let previous_status = self.terminal.lock().await.take();
+
+        if previous_status == Some(RunningStatus::Running) {
+            *running_status = RunningStatus::Running;
+            self.control_message_sender
+                .send(ControlMessage::CheckBuffer)
+                .unwrap();
+        }
+    }
+
+    fn client_connected(&self, rpc: &str, origin: &Origin) {
+        let _ = self.events.send(Event::ClientConnected {
+            rpc: rpc.to_string(),
+            origin: origin.clone(),
+        });
+    }
+
+    fn client_disconnected(&self, rpc: &str, origin: &Origin) {
+        let _ = self.events.send(Event::ClientDisconnected {
+            rpc: rpc.to_string(),
+            origin: origin.clone(),
+        });
+    }
+
+    /// Applies the disconnect policy when a command stream fails partway.
+    async fn stream_interrupted(&self, status: &Status) {
+        if self.disconnect_policy == DisconnectPolicy::Continue {
+            return;
+        }
+
+        let message = format!("Command stream disconnected: {}", status.message());
+        println!("{}", message);
+
+        *self.running_status.lock().await = RunningStatus::Paused;
+        self.interlock.disarm();
+        let _ = self.events.send(Event::Error { message });
+    }
+
+    async fn check_buffer_if_running(&self) {
+        if *self.running_status.lock().await == RunningStatus::Running {
+            self.control_message_sender
+                .send(ControlMessage::CheckBuffer)
+                .unwrap();
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AxidrawOverHttp for AxidrawService {
+    type StreamWithFlowControlStream =
+        Pin<Box<dyn Stream<Item = Result<FlowControl, Status>> + Send>>;
+    type TeleoperateStream = Pin<Box<dyn Stream<Item = Result<Position, Status>> + Send>>;
+    type TerminalStream = Pin<Box<dyn Stream<Item = Result<TerminalOutput, Status>> + Send>>;
+    type WatchExecutionStream = Pin<Box<dyn Stream<Item = Result<ExecutedCommand, Status>> + Send>>;
+    type WatchEventsStream = Pin<Box<dyn Stream<Item = Result<ServerEvent, Status>> + Send>>;
+    type UploadFileStream = Pin<Box<dyn Stream<Item = Result<UploadProgress, Status>> + Send>>;
+
+    #[instrument(skip_all)]
+    async fn stream(
+        &self,
+        request: Request<tonic::Streaming<Command>>,
+    ) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "Stream opened").await?;
+
+        let origin = Origin::of(&request);
+        let mut stream = request.into_inner();
+        self.client_connected("Stream", &origin);
+
+        while let Some(command) = stream.next().await {
+            let command = match command {
+                Ok(command) => command,
+                Err(status) => {
+                    self.audit
+                        .lock()
+                        .await
+                        .record(&origin, "Stream disconnected", false);
+                    self.client_disconnected("Stream", &origin);
+                    self.stream_interrupted(&status).await;
+                    return Err(status);
+                }
+            };
+
+            if let Err(status) = self.enqueue_streamed(command).await {
+                self.client_disconnected("Stream", &origin);
+                return Err(status);
+            }
+        }
+
+        self.audit
+            .lock()
+            .await
+            .record(&origin, "Stream closed", false);
+        self.client_disconnected("Stream", &origin);
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn stream_with_flow_control(
+        &self,
+        request: Request<tonic::Streaming<Command>>,
+    ) -> Result<Response<Self::StreamWithFlowControlStream>, Status> {
+        self.authorize(&request, "StreamWithFlowControl opened")
+            .await?;
+
+        let origin = Origin::of(&request);
+        let mut stream = request.into_inner();
+        let mut throttle = self.watermarks.subscribe();
+        let (sender, receiver) = mpsc::channel(16);
+        let service = self.clone();
+        self.client_connected("StreamWithFlowControl", &origin);
+
+        tokio::spawn(async move {
+            let flow_control = |throttle: bool, buffer_length: usize| FlowControl {
+                throttle,
+                buffer_length: buffer_length as u64,
+            };
+
+            let initial = *throttle.borrow_and_update();
+            let buffer_length = service.command_buffer.lock().await.len();
+            let _ = sender.send(Ok(flow_control(initial, buffer_length))).await;
+
+            loop {
+                tokio::select! {
+                    command = stream.next() => {
+                        let result = match command {
+                            Some(Ok(command)) => service.enqueue_streamed(command).await,
+                            Some(Err(status)) => {
+                                service.audit.lock().await.record(
+                                    &origin,
+                                    "StreamWithFlowControl disconnected",
+                                    false,
+                                );
+                                service.stream_interrupted(&status).await;
+                                Err(status)
+                            }
+                            None => {
+                                service.audit.lock().await.record(
+                                    &origin,
+                                    "StreamWithFlowControl closed",
+                                    false,
+                                );
+                                break;
+                            }
+                        };
+
+                        if let Err(status) = result {
+                            let _ = sender.send(Err(status)).await;
+                            break;
+                        }
+                    }
+                    Ok(()) = throttle.changed() => {
+                        let current = *throttle.borrow_and_update();
+                        let buffer_length = service.command_buffer.lock().await.len();
+                        let _ = sender.send(Ok(flow_control(current, buffer_length))).await;
+                    }
+                }
+            }
+
+            service.client_disconnected("StreamWithFlowControl", &origin);
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    #[instrument(skip_all)]
+    async fn clear(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "Clear").await?;
+
+        let mut buffer = self.command_buffer.clone().lock_owned().await;
+        let mut jobs = self.jobs.lock().await;
+
+        for entry in buffer.drain(..) {
+            if let BufferEntry::JobEnd(id) = entry {
+                jobs.set_state(id, JobState::Cancelled);
+            }
+        }
+        jobs.awaiting_next_after = None;
+        self.watermarks.update(0);
+
+        self.plotted.lock().await.clear_paths();
+        self.layers.lock().await.pending = None;
+        *self.last_sequence_id.lock().await = None;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn clear_tag(&self, request: Request<Tag>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "ClearTag").await?;
+
+        let tag = request.into_inner().tag;
+        let mut buffer = self.command_buffer.lock().await;
+
+        buffer.retain(|entry| match entry {
+            BufferEntry::Command(command) => command.tag.as_ref() != Some(&tag),
+            _ => true,
+        });
+        self.watermarks.update(buffer.len());
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn clear_from(
+        &self,
+        request: Request<SequenceId>,
+    ) -> Result<Response<ClearedCount>, Status> {
+        self.authorize(&request, "ClearFrom").await?;
+
+        let start = request
+            .into_inner()
+            .sequence_id
+            .ok_or_else(|| Status::invalid_argument("A sequence ID is required"))?;
+        let mut buffer = self.command_buffer.lock().await;
+        let mut last_sequence_id = self.last_sequence_id.lock().await;
+
+        let count = clear_sequence_range(&mut buffer, start, None);
+        self.watermarks.update(buffer.len());
+
+        // Let the client stream replacements from the start of the range.
+        if last_sequence_id.is_some_and(|last| last >= start) {
+            *last_sequence_id = start.checked_sub(1);
+        }
+
+        Ok(Response::new(ClearedCount { count }))
+    }
+
+    #[instrument(skip_all)]
+    async fn clear_range(
+        &self,
+        request: Request<SequenceRange>,
+    ) -> Result<Response<ClearedCount>, Status> {
+        self.authorize(&request, "ClearRange").await?;
+
+        let range = request.into_inner();
+
+        if range.end <= range.start {
+            return Err(Status::invalid_argument(
+                "Range end must be after its start",
+            ));
+        }
+
+        let mut buffer = self.command_buffer.lock().await;
+
+        let count = clear_sequence_range(&mut buffer, range.start, Some(range.end));
+        self.watermarks.update(buffer.len());
+
+        Ok(Response::new(ClearedCount { count }))
+    }
+
+    #[instrument(skip_all)]
+    async fn send_urgent(&self, request: Request<CommandBatch>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "SendUrgent").await?;
+
+        // Urgent commands run straight away, from where the carriage is now.
+        let mut end = self.plotted.lock().await.simulator().clone();
+        let mut commands = Vec::new();
+        for command in request.into_inner().commands {
+            commands.push(self.accept_command(command, &mut end).await?);
+        }
+
+        for command in &commands {
+            self.check_armed(command)?;
+        }
+
+        self.urgent_buffer
+            .lock()
+            .await
+            .extend(commands.into_iter().map(|command| UrgentCommand {
+                command,
+                responder: None,
+            }));
+        self.control_message_sender
+            .send(ControlMessage::CheckBuffer)
+            .unwrap();
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn execute(
+        &self,
+        request: Request<Command>,
+    ) -> Result<Response<CommandResponse>, Status> {
+        self.authorize(&request, "Execute").await?;
+
+        let mut end = self.plotted.lock().await.simulator().clone();
+        let command = self.accept_command(request.into_inner(), &mut end).await?;
+        let response = self.execute_now(command).await?;
+
+        Ok(Response::new(CommandResponse { response }))
+    }
+
+    #[instrument(skip_all)]
+    async fn jog(&self, request: Request<JogRequest>) -> Result<Response<Position>, Status> {
+        self.authorize(&request, "Jog").await?;
+
+        let JogRequest { dx, dy, speed } = request.into_inner();
+
+        self.jog_by(dx, dy, speed).await?;
+
+        Ok(Response::new(self.position().await))
+    }
+
+    #[instrument(skip_all)]
+    async fn jog_pen(&self, request: Request<PenRequest>) -> Result<Response<Position>, Status> {
+        self.authorize(&request, "JogPen").await?;
+
+        let command = match request.into_inner().down {
+            Some(down) => self.servo_profile.lock().await.pen(down),
+            None => "TP".to_string(),
+        };
+
+        self.execute_now(QueuedCommand::new(command)).await?;
+
+        Ok(Response::new(self.position().await))
+    }
+
+    #[instrument(skip_all)]
+    async fn teleoperate(
+        &self,
+        request: Request<tonic::Streaming<TeleopDelta>>,
+    ) -> Result<Response<Self::TeleoperateStream>, Status> {
+        self.authorize(&request, "Teleoperate").await?;
+
+        let (sender, receiver) = mpsc::channel(16);
+
+        tokio::spawn(teleop::run(self.clone(), request.into_inner(), sender));
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    #[instrument(skip_all)]
+    async fn change_tool(&self, request: Request<ToolName>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "ChangeTool").await?;
+
+        let tool = request.into_inner().name;
+
+        let running_status = self.running_status.lock().await;
+        let mut buffer = self.command_buffer.lock().await;
+
+        self.tool_changes
+            .lock()
+            .await
+            .check(&tool)
+            .map_err(Status::failed_precondition)?;
+
+        // The consumer parks from wherever the current command leaves the
+        // carriage, stepping past the pause if there is one.
+        let resume = *running_status == RunningStatus::Running;
+        buffer.push_front(BufferEntry::ToolChange { tool, resume });
+        self.watermarks.update(buffer.len());
+        drop(buffer);
+        drop(running_status);
+
+        if !resume {
+            self.step.store(true, Ordering::SeqCst);
+        }
+
+        self.control_message_sender
+            .send(ControlMessage::CheckBuffer)
+            .unwrap();
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn confirm_tool_change(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "ConfirmToolChange").await?;
+
+        let servo_profile = self.servo_profile.lock().await.clone();
+        let simulator = self.plotted.lock().await.simulator().clone();
+
+        let (pending, commands) = self
+            .tool_changes
+            .lock()
+            .await
+            .finish(&simulator, &servo_profile, TRAVEL_SPEED)
+            .ok_or_else(|| Status::failed_precondition("No tool change is waiting"))?;
+
+        println!("Fitted tool {}", pending.tool);
+
+        for contents in commands {
+            self.execute_now(QueuedCommand::new(contents)).await?;
+        }
+
+        if pending.resume {
+            self.resume_plotting().await?;
+        }
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn list_tools(&self, _request: Request<Empty>) -> Result<Response<ToolList>, Status> {
+        let tool_changes = self.tool_changes.lock().await;
+
+        Ok(Response::new(ToolList {
+            tools: tool_changes
+                .tools
+                .iter()
+                .map(|(name, tool)| tool.to_proto(name))
+                .collect(),
+            active: tool_changes.active.clone(),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn terminal(
+        &self,
+        request: Request<tonic::Streaming<TerminalInput>>,
+    ) -> Result<Response<Self::TerminalStream>, Status> {
+        self.authorize(&request, "Terminal").await?;
+        self.open_terminal().await?;
+
+        let (sender, receiver) = mpsc::channel(16);
+
+        tokio::spawn(terminal::run(self.clone(), request.into_inner(), sender));
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    #[instrument(skip_all)]
+    async fn set_tool(&self, request: Request<ToolRequest>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "SetTool").await?;
+
+        *self.tool.lock().await = request.into_inner().tool();
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn set_tool_power(&self, request: Request<ToolPower>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "SetToolPower").await?;
+
+        let tool_power = request.into_inner();
+
+        if tool_power
+            .power
+            .is_some_and(|power| power > tool::MAX_POWER)
+        {
+            return Err(Status::invalid_argument(format!(
+                "Power must be at most {}",
+                tool::MAX_POWER
+            )));
+        }
+
+        let contents = tool::command(
+            *self.tool.lock().await,
+            tool_power.on,
+            tool_power.power,
+            &*self.servo_profile.lock().await,
+        );
+
+        if tool_power.queued {
+            self.enqueue_streamed(Command {
+                contents,
+                ..Default::default()
+            })
+            .await?;
+        } else {
+            let response = self.execute_now(QueuedCommand::new(contents)).await?;
+
+            if let Some(error) = response.iter().find(|line| line.starts_with('!')) {
+                return Err(Status::internal(error.clone()));
+            }
+        }
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_server_info(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<ServerInfo>, Status> {
+        Ok(Response::new(ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: env!("GIT_HASH").to_string(),
+            api_version: API_VERSION,
+            features: self.features.to_vec(),
+            uptime: Some(self.started_at.elapsed().try_into().unwrap_or_default()),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_device_info(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<DeviceInfo>, Status> {
+        let mut info = self.device_status.lock().await.to_info();
+        info.simulated_time = self
+            .dry_run_clock
+            .as_ref()
+            .map(|clock| clock.elapsed().try_into().unwrap_or_default());
+
+        Ok(Response::new(info))
+    }
+
+    #[instrument(skip_all)]
+    async fn set_nickname(&self, request: Request<Nickname>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "SetNickname").await?;
+
+        let nickname = request.into_inner().nickname;
+
+        if nickname.len() > device::MAX_NICKNAME_LENGTH || nickname.contains([',', '\r', '\n']) {
+            return Err(Status::invalid_argument(format!(
+                "Nickname must be at most {} characters without commas",
+                device::MAX_NICKNAME_LENGTH
+            )));
+        }
+
+        let response = self
+            .execute_now(QueuedCommand::new(format!("ST,{}", nickname)))
+            .await?;
+
+        if let Some(error) = response.iter().find(|line| line.starts_with('!')) {
+            return Err(Status::internal(error.clone()));
+        }
+
+        self.device_status.lock().await.nickname = (!nickname.is_empty()).then_some(nickname);
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn set_device(
+        &self,
+        request: Request<DeviceRequest>,
+    ) -> Result<Response<DeviceInfo>, Status> {
+        self.authorize(&request, "SetDevice").await?;
+
+        let device = request.into_inner().device;
+
+        let mut running_status = self.running_status.lock().await;
+        let previous_status = std::mem::replace(&mut *running_status, RunningStatus::Paused);
+        drop(running_status);
+
+        let result = self.switch_device(device).await;
+
+        *self.running_status.lock().await = previous_status;
+        self.control_message_sender
+            .send(ControlMessage::CheckBuffer)
+            .unwrap();
+
+        Ok(Response::new(result?))
+    }
+
+    #[instrument(skip_all)]
+    async fn fail_over(&self, request: Request<Empty>) -> Result<Response<DeviceInfo>, Status> {
+        self.authorize(&request, "FailOver").await?;
+
+        let Some(backup_device) = self.backup_device.clone() else {
+            return Err(Status::failed_precondition("No backup device was given"));
+        };
+
+        if *self.running_status.lock().await != RunningStatus::Paused {
+            return Err(Status::failed_precondition("Pause before failing over"));
+        }
+
+        if self.terminal.lock().await.is_some() {
+            return Err(Status::failed_precondition(
+                "Close the terminal before failing over",
+            ));
+        }
+
+        let info = self.switch_device(backup_device).await?;
+        let servo_profile = self.servo_profile.lock().await.clone();
+
+        let mut buffer = self.command_buffer.lock().await;
+        let mut plotted = self.plotted.lock().await;
+
+        // Carry on from the same position and pen state on the backup machine,
+        // whose carriage starts at home with the pen up.
+        let simulator = plotted.simulator();
+        let mut commands = vec![servo_profile.pen(false)];
+        commands.extend(simulator.travel_from_home(TRAVEL_SPEED));
+        if simulator.is_pen_down() {
+            commands.push(servo_profile.pen(true));
+        }
+        plotted.reset_position();
+        drop(plotted);
+
+        for command in commands.into_iter().rev() {
+            buffer.push_front(BufferEntry::Command(QueuedCommand::new(command)));
+        }
+
+        self.watermarks.update(buffer.len());
+        drop(buffer);
+
+        println!("Failed over to {}", info.port_name);
+
+        *self.running_status.lock().await = RunningStatus::Running;
+        self.control_message_sender
+            .send(ControlMessage::CheckBuffer)
+            .unwrap();
+
+        Ok(Response::new(info))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_audit_log(&self, request: Request<Empty>) -> Result<Response<AuditLog>, Status> {
+        auth::require(&request, Role::Operator)?;
+
+        Ok(Response::new(AuditLog {
+            entries: self.audit.lock().await.entries(),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn configure_machine(
+        &self,
+        request: Request<MachineConfig>,
+    ) -> Result<Response<MachineConfig>, Status> {
+        self.authorize(&request, "ConfigureMachine").await?;
+
+        let settings = MachineSettings::from_proto(request.into_inner());
+        settings.validate().map_err(Status::invalid_argument)?;
+
+        for command in settings.commands() {
+            let response = self.execute_now(QueuedCommand::new(command)).await?;
+
+            if let Some(error) = response.iter().find(|line| line.starts_with('!')) {
+                return Err(Status::internal(error.clone()));
+            }
+        }
+
+        let mut device_status = self.device_status.lock().await;
+        device_status.machine_settings.merge(&settings);
+
+        Ok(Response::new(device_status.machine_settings.to_proto()))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_machine_config(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<MachineConfig>, Status> {
+        Ok(Response::new(
+            self.device_status.lock().await.machine_settings.to_proto(),
+        ))
+    }
+
+    #[instrument(skip_all)]
+    async fn set_work_area(
+        &self,
+        request: Request<WorkAreaRequest>,
+    ) -> Result<Response<axidraw_over_http::WorkArea>, Status> {
+        self.authorize(&request, "SetWorkArea").await?;
+
+        let request = request.into_inner();
+
+        let mut work_area = match &request.area {
+            Some(work_area_request::Area::Preset(name)) => self
+                .work_area_presets
+                .lock()
+                .await
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Status::not_found(format!("No work area preset {}", name)))?,
+            Some(work_area_request::Area::Custom(area)) => WorkArea::from_proto(area.clone()),
+            None => return Err(Status::invalid_argument("Expected a preset or custom area")),
+        };
+
+        if request.orientation.is_some() {
+            work_area.orientation = request.orientation().into();
+        }
+
+        if request.origin.is_some() {
+            work_area.origin = request.origin().into();
+        }
+
+        if let Some(margin) = request.margin {
+            work_area.margin = margin;
+        }
+
+        work_area
+            .validate(self.machine_size)
+            .map_err(Status::invalid_argument)?;
+
+        let info = work_area.to_proto(self.machine_size);
+        *self.work_area.lock().await = work_area;
+
+        Ok(Response::new(info))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_work_area(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<axidraw_over_http::WorkArea>, Status> {
+        Ok(Response::new(
+            self.work_area.lock().await.to_proto(self.machine_size),
+        ))
+    }
+
+    #[instrument(skip_all)]
+    async fn list_work_areas(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<WorkAreaList>, Status> {
+        let presets = self.work_area_presets.lock().await;
+
+        Ok(Response::new(WorkAreaList {
+            presets: presets
+                .values()
+                .map(|area| area.to_proto(self.machine_size))
+                .collect(),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_statistics(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Statistics>, Status> {
+        Ok(Response::new(self.statistics.lock().await.to_proto()))
+    }
+
+    #[instrument(skip_all)]
+    async fn list_serial_ports(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<SerialDeviceList>, Status> {
+        Ok(Response::new(SerialDeviceList {
+            devices: device::list_serial_devices(&self.match_rules),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn pause(&self, request: Request<PauseRequest>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "Pause").await?;
+
+        let request = request.into_inner();
+        let mode = request.mode();
+
+        let duration = request
+            .duration
+            .map(Duration::try_from)
+            .transpose()
+            .map_err(|_| Status::invalid_argument("Duration must not be negative"))?;
+
+        *self.running_status.clone().lock_owned().await = RunningStatus::Paused;
+
+        let resume_at = duration.map(|duration| Instant::now() + duration);
+        *self.resume_at.lock().await = resume_at;
+
+        if let Some(resume_at) = resume_at {
+            let service = self.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep_until(resume_at.into()).await;
+
+                if *service.resume_at.lock().await != Some(resume_at) {
+                    return;
+                }
+
+                println!("Resuming after timed pause");
+
+                if let Err(status) = service.resume_plotting().await {
+                    println!("Could not resume: {}", status.message());
+                }
+            });
+        }
+
+        if mode == PauseMode::Immediate {
+            self.immediate_stop
+                .request()
+                .map_err(|error| Status::internal(format!("Could not send ES: {}", error)))?;
+
+            // Wake the consumer to read the response if it's idle.
+            self.control_message_sender
+                .send(ControlMessage::CheckBuffer)
+                .unwrap();
+        }
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn resume(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "Resume").await?;
+
+        self.resume_plotting().await?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn arm(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "Arm").await?;
+
+        self.interlock.arm();
+        self.check_buffer_if_running().await;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn reload_config(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "ReloadConfig").await?;
+
+        AxidrawService::reload_config(self).await?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn disarm(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "Disarm").await?;
+
+        self.interlock.disarm();
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn start_next_job(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "StartNextJob").await?;
+
+        let mut running_status = self.running_status.lock().await;
+        let mut jobs = self.jobs.lock().await;
+
+        if jobs.awaiting_next_after.take().is_none() {
+            return Err(Status::failed_precondition("Not waiting between jobs"));
+        }
+
+        *running_status = RunningStatus::Running;
+        self.control_message_sender
+            .send(ControlMessage::CheckBuffer)
+            .unwrap();
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn step(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "Step").await?;
+
+        if self.terminal.lock().await.is_some() {
+            return Err(Status::failed_precondition(
+                "Close the terminal before stepping",
+            ));
+        }
+
+        if *self.running_status.lock().await != RunningStatus::Paused {
+            return Err(Status::failed_precondition("Plotting isn't paused"));
+        }
+
+        self.step.store(true, Ordering::SeqCst);
+        self.control_message_sender
+            .send(ControlMessage::CheckBuffer)
+            .unwrap();
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_state(&self, _request: Request<Empty>) -> Result<Response<BufferState>, Status> {
+        // The consumer sets the breakpoint and layer while holding the running
+        // status, so the locks are taken in the same order, one at a time.
+        let status = self.running_status.lock().await;
+        let buffer = self.command_buffer.lock().await;
+        let breakpoint = self.breakpoint.lock().await;
+        let layers = self.layers.lock().await;
+
+        let tool_changes = self.tool_changes.lock().await;
+
+        let upcoming_layer = layers.pending.clone().or_else(|| {
+            buffer.iter().find_map(|entry| match entry {
+                BufferEntry::Layer(name) => Some(name.clone()),
+                _ => None,
+            })
+        });
+
+        return Ok(Response::new(BufferState {
+            buffer_length: buffer.len() as u64,
+            running_status: *status as i32,
+            breakpoint: breakpoint.clone(),
+            current_layer: layers.current.clone(),
+            upcoming_layer,
+            throttle: self.watermarks.is_throttled(),
+            urgent_buffer_length: self.urgent_buffer.lock().await.len() as u64,
+            awaiting_next_job: self.jobs.lock().await.awaiting_next_after.is_some(),
+            max_buffer_length: self
+                .max_buffer_length
+                .lock()
+                .await
+                .map(|length| length as u64),
+            overflow_policy: match self.overflow_policy {
+                OverflowPolicy::Reject => axidraw_over_http::OverflowPolicy::Reject,
+                OverflowPolicy::Block => axidraw_over_http::OverflowPolicy::Block,
+                OverflowPolicy::DropOldest => axidraw_over_http::OverflowPolicy::DropOldest,
+            } as i32,
+            speed_factor: *self.speed_factor.lock().await,
+            armed: self.interlock.is_armed(),
+            in_flight: self.in_flight.lock().await.clone(),
+            resume_in: self.resume_at.lock().await.map(|resume_at| {
+                resume_at
+                    .saturating_duration_since(Instant::now())
+                    .try_into()
+                    .unwrap_or_default()
+            }),
+            tool: *self.tool.lock().await as i32,
+            active_tool: tool_changes.active.clone(),
+            tool_change: tool_changes
+                .pending
+                .as_ref()
+                .map(|pending| pending.tool.clone()),
+        }));
+    }
+
+    #[instrument(skip_all)]
+    async fn set_speed_factor(
+        &self,
+        request: Request<SpeedFactor>,
+    ) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "SetSpeedFactor").await?;
+
+        let factor = request.into_inner().factor;
+
+        if !(MIN_SPEED_FACTOR..=MAX_SPEED_FACTOR).contains(&factor) {
+            return Err(Status::invalid_argument(format!(
+                "Speed factor must be between {} and {}",
+                MIN_SPEED_FACTOR, MAX_SPEED_FACTOR
+            )));
+        }
+
+        *self.speed_factor.lock().await = factor;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn submit_job(&self, request: Request<Job>) -> Result<Response<JobId>, Status> {
+        self.authorize(&request, "SubmitJob").await?;
+
+        let id = self.add_job(request.into_inner()).await?;
+
+        Ok(Response::new(JobId { id }))
+    }
+
+    #[instrument(skip_all)]
+    async fn load_file(&self, request: Request<FilePath>) -> Result<Response<JobId>, Status> {
+        self.authorize(&request, "LoadFile").await?;
+
+        let path = PathBuf::from(request.into_inner().path);
+        let path = match &self.watch_dir {
+            Some(watch_dir) if path.is_relative() => watch_dir.join(path),
+            _ => path,
+        };
+
+        let job = hotfolder::read_job(&path).await.map_err(|error| {
+            Status::not_found(format!("Could not read {}: {}", path.display(), error))
+        })?;
+
+        let id = self.add_job(job).await?;
+
+        Ok(Response::new(JobId { id }))
+    }
+
+    #[instrument(skip_all)]
+    async fn upload_file(
+        &self,
+        request: Request<tonic::Streaming<FileChunk>>,
+    ) -> Result<Response<Self::UploadFileStream>, Status> {
+        self.authorize(&request, "UploadFile").await?;
+
+        let uploads = self.uploads()?;
+        let (sender, receiver) = mpsc::channel(16);
+        let service = self.clone();
+
+        tokio::spawn(uploads.receive(request.into_inner(), sender, move |path| {
+            let service = service.clone();
+            async move { service.hold_upload(path).await }
+        }));
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_upload(
+        &self,
+        request: Request<UploadId>,
+    ) -> Result<Response<UploadProgress>, Status> {
+        let upload_id = request.into_inner().upload_id;
+
+        Ok(Response::new(self.uploads()?.progress(&upload_id).await?))
+    }
+
+    #[instrument(skip_all)]
+    async fn release_job(&self, request: Request<JobId>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "ReleaseJob").await?;
+
+        let id = request.into_inner().id;
+
+        let commands = match self.jobs.lock().await.get(id) {
+            Some(job) if job.state == JobState::Held => job.commands.clone(),
+            Some(_) => {
+                return Err(Status::failed_precondition(format!(
+                    "Job {} is not held",
+                    id
+                )))
+            }
+            None => return Err(Status::not_found(format!("No job {}", id))),
+        };
+
+        let mut end = self.queued_end().await;
+        let mut entries = Vec::with_capacity(commands.len());
+        for command in commands {
+            entries.push(self.accept_entry(command, Some(&mut end)).await?);
+        }
+
+        let mut buffer = self.lock_buffer_for_push(entries.len() + 2).await?;
+        let mut jobs = self.jobs.lock().await;
+
+        // Another ReleaseJob may have got there first.
+        let Some(job) = jobs.get_mut(id).filter(|job| job.state == JobState::Held) else {
+            return Err(Status::failed_precondition(format!(
+                "Job {} is not held",
+                id
+            )));
+        };
+        job.state = JobState::Queued;
+        let name = job.name.clone();
+        drop(jobs);
+        let _ = self.events.send(Event::JobQueued { id, name });
+
+        buffer.push_back(BufferEntry::JobStart(id));
+        buffer.extend(entries);
+        buffer.push_back(BufferEntry::JobEnd(id));
+        self.watermarks.update(buffer.len());
+        drop(buffer);
+
+        self.check_buffer_if_running().await;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn list_jobs(&self, _request: Request<Empty>) -> Result<Response<JobList>, Status> {
+        let jobs = self.jobs.lock().await;
+
+        Ok(Response::new(JobList {
+            jobs: jobs.iter().map(|job| job.to_info()).collect(),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn reorder_jobs(&self, request: Request<JobOrder>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "ReorderJobs").await?;
+
+        let order = request.into_inner().job_ids;
+
+        let mut buffer = self.command_buffer.lock().await;
+        let jobs = self.jobs.lock().await;
+
+        let queued = jobs
+            .iter()
+            .filter(|job| matches!(job.state, JobState::Queued | JobState::Scheduled))
+            .map(|job| job.id)
+            .collect::<Vec<_>>();
+
+        if let Some(id) = order.iter().find(|id| !queued.contains(id)) {
+            return Err(Status::failed_precondition(format!(
+                "Job {} is not waiting to start",
+                id
+            )));
+        }
+
+        reorder_job_entries(&mut buffer, &queued, &order);
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn replay_session(
+        &self,
+        request: Request<ReplayRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "ReplaySession").await?;
+
+        let request = request.into_inner();
+        let entries = session::parse(&request.session).map_err(Status::invalid_argument)?;
+
+        if !request.preserve_pacing {
+            let mut end = self.queued_end().await;
+            let mut commands = Vec::with_capacity(entries.len());
+            for entry in entries {
+                let command = Command {
+                    contents: entry.command,
+                    ..Default::default()
+                };
+
+                commands.push(self.accept_entry(command, Some(&mut end)).await?);
+            }
+
+            let mut buffer = self.lock_buffer_for_push(commands.len()).await?;
+            buffer.extend(commands);
+            self.watermarks.update(buffer.len());
+            drop(buffer);
+
+            self.check_buffer_if_running().await;
+
+            return Ok(Response::new(Empty {}));
+        }
+
+        let service = self.clone();
+
+        tokio::spawn(async move {
+            let started_at = Instant::now();
+            let first_timestamp = entries.first().map(|entry| entry.timestamp);
+
+            for entry in entries {
+                let offset = first_timestamp
+                    .and_then(|first| entry.timestamp.duration_since(first).ok())
+                    .unwrap_or_default();
+                tokio::time::sleep_until((started_at + offset).into()).await;
+
+                let command = Command {
+                    contents: entry.command,
+                    ..Default::default()
+                };
+
+                if let Err(status) = service.enqueue_streamed(command).await {
+                    println!("Replay stopped: {}", status.message());
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn export_buffer(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<BufferExport>, Status> {
+        let buffer = self.command_buffer.lock().await;
+
+        let commands = buffer
+            .iter()
+            .filter_map(|entry| match entry {
+                BufferEntry::Command(command) => Some(format!("{}\n", command.contents)),
+                _ => None,
+            })
+            .collect();
+
+        Ok(Response::new(BufferExport { commands }))
+    }
+
+    #[instrument(skip_all)]
+    async fn peek_buffer(
+        &self,
+        request: Request<BufferPageRequest>,
+    ) -> Result<Response<BufferPage>, Status> {
+        let request = request.into_inner();
+        let filter = request.filter();
+        let limit = match request.limit {
+            0 => DEFAULT_PAGE_SIZE,
+            limit => limit.min(MAX_PAGE_SIZE),
+        } as usize;
+
+        let buffer = self.command_buffer.lock().await;
+        let mut simulator = self.plotted.lock().await.simulator().clone();
+
+        let mut commands = Vec::new();
+        let mut total = 0;
+
+        let queued = buffer.iter().filter_map(|entry| match entry {
+            BufferEntry::Command(command) => Some(command),
+            _ => None,
+        });
+
+        for (position, command) in queued.enumerate() {
+            let category = if ebb::moves_carriage(&command.contents) {
+                if simulator.is_pen_down() {
+                    CommandCategory::PenDown
+                } else {
+                    CommandCategory::PenUp
+                }
+            } else {
+                CommandCategory::Config
+            };
+
+            simulator.apply(&command.contents);
+
+            if filter != CommandCategory::Any && filter != category {
+                continue;
+            }
+
+            if total >= request.offset && commands.len() < limit {
+                commands.push(BufferedCommand {
+                    position: position as u64,
+                    contents: command.contents.clone(),
+                    sequence_id: command.sequence_id,
+                    tag: command.tag.clone(),
+                    category: category as i32,
+                    label: command.label.clone(),
+                });
+            }
+
+            total += 1;
+        }
+
+        Ok(Response::new(BufferPage { commands, total }))
+    }
+
+    #[instrument(skip_all)]
+    async fn render_preview(
+        &self,
+        request: Request<PreviewRequest>,
+    ) -> Result<Response<Preview>, Status> {
+        let include_plotted = request.into_inner().include_plotted;
+        let work_area = self.work_area.lock().await.size();
+
+        let buffer = self.command_buffer.lock().await;
+        let plotted = self.plotted.lock().await;
+
+        let mut remaining = plotted.continuation();
+        for entry in buffer.iter() {
+            if let BufferEntry::Command(command) = entry {
+                remaining.trace(&command.contents);
+            }
+        }
+
+        let svg = preview::render_svg(
+            remaining.paths(),
+            if include_plotted {
+                plotted.paths()
+            } else {
+                &[]
+            },
+            work_area,
+        );
+
+        Ok(Response::new(Preview { svg }))
+    }
+
+    #[instrument(skip_all)]
+    async fn estimate_duration(
+        &self,
+        request: Request<EstimateRequest>,
+    ) -> Result<Response<Estimate>, Status> {
+        let commands = match request.into_inner().source {
+            Some(estimate_request::Source::Commands(batch)) => batch.commands,
+            Some(estimate_request::Source::JobId(id)) => self
+                .jobs
+                .lock()
+                .await
+                .get(id)
+                .map(|job| job.commands.clone())
+                .ok_or_else(|| Status::not_found(format!("No job with ID {}", id)))?,
+            None => Vec::new(),
+        };
+
+        let simulator = self.plotted.lock().await.simulator().clone();
+        let estimate = estimate::estimate(
+            commands.iter().map(|command| command.contents.as_str()),
+            simulator,
+        );
+
+        Ok(Response::new(Estimate {
+            duration: Some(estimate.duration.try_into().unwrap_or_default()),
+            pen_down_distance: estimate.pen_down_distance,
+            pen_lifts: estimate.pen_lifts,
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn register_macro(&self, request: Request<Macro>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "RegisterMacro").await?;
+
+        let r#macro = request.into_inner();
+
+        if r#macro.name.is_empty() {
+            return Err(Status::invalid_argument("Macro must have a name"));
+        }
+
+        let parameters = &r#macro.parameters;
+
+        if let Some(parameter) = parameters
+            .iter()
+            .find(|parameter| !template::is_valid_parameter(parameter))
+        {
+            return Err(Status::invalid_argument(format!(
+                "Invalid parameter name {:?}",
+                parameter
+            )));
+        }
+
+        for command in &r#macro.commands {
+            let texts = [Some(&command.contents), command.label.as_ref()];
+
+            for name in texts
+                .into_iter()
+                .flatten()
+                .flat_map(|text| template::placeholders(text))
+            {
+                if !parameters.iter().any(|parameter| parameter == name) {
+                    return Err(Status::invalid_argument(format!(
+                        "Unknown parameter {}",
+                        name
+                    )));
+                }
+            }
+
+            // Templates can only be checked once they're filled in, and
+            // moves once it's known where they start from.
+            if parameters.is_empty() {
+                self.accept_entry(command.clone(), None).await?;
+            }
+        }
+
+        self.macros
+            .lock()
+            .await
+            .insert(r#macro.name.clone(), r#macro);
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_macro(&self, request: Request<MacroName>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "DeleteMacro").await?;
+
+        let name = request.into_inner().name;
+
+        self.macros
+            .lock()
+            .await
+            .remove(&name)
+            .ok_or_else(|| Status::not_found(format!("No macro named {}", name)))?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn list_macros(&self, _request: Request<Empty>) -> Result<Response<MacroList>, Status> {
+        let macros = self.macros.lock().await;
+
+        Ok(Response::new(MacroList {
+            macros: macros.values().cloned().collect(),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn run_macro(&self, request: Request<MacroName>) -> Result<Response<Empty>, Status> {
+        self.authorize(&request, "RunMacro").await?;
+
+        let MacroName { name, arguments } = request.into_inner();
+
+        let r#macro = self
+            .macros
+            .lock()
+            .await
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("No macro named {}", name)))?;
 
-mod axidraw_over_http {
-    tonic::include_proto!("axidraw_over_http");
-}
+        for (parameter, argument) in &arguments {
+            if !r#macro.parameters.contains(parameter) {
+                return Err(Status::invalid_argument(format!(
+                    "{} has no parameter {}",
+                    name, parameter
+                )));
+            }
 
-enum ControlMessage {
-    CheckBuffer,
-}
+            if argument.contains([',', '\r', '\n']) {
+                return Err(Status::invalid_argument(format!(
+                    "Argument {} must not contain commas or line breaks",
+                    parameter
+                )));
+            }
+        }
 
-struct AxidrawService {
-    control_message_sender: UnboundedSender<ControlMessage>,
-    command_buffer: Arc<Mutex<VecDeque<String>>>,
-    running_status: Arc<Mutex<RunningStatus>>,
-}
+        let mut end = self.queued_end().await;
+        let mut entries = Vec::with_capacity(r#macro.commands.len());
+        for mut command in r#macro.commands {
+            command.contents = template::substitute(&command.contents, &arguments)
+                .map_err(Status::invalid_argument)?;
+            command.label = command
+                .label
+                .map(|label| template::substitute(&label, &arguments))
+                .transpose()
+                .map_err(Status::invalid_argument)?;
 
-#[tonic::async_trait]
-impl AxidrawOverHttp for AxidrawService {
-    async fn stream(
+            entries.push(self.accept_entry(command, Some(&mut end)).await?);
+        }
+
+        let mut buffer = self.lock_buffer_for_push(entries.len()).await?;
+        buffer.extend(entries);
+        self.watermarks.update(buffer.len());
+        drop(buffer);
+
+        self.check_buffer_if_running().await;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn resume_from(
         &self,
-        request: Request<tonic::Streaming<Command>>,
+        request: Request<ResumeFromRequest>,
     ) -> Result<Response<Empty>, Status> {
-        let mut stream = request.into_inner();
+        self.authorize(&request, "ResumeFrom").await?;
 
-        while let Some(command) = stream.next().await {
-            let command = command?.contents;
+        let ResumeFromRequest { job_id, index } = request.into_inner();
+
+        let mut buffer = self.command_buffer.clone().lock_owned().await;
+        let mut jobs = self.jobs.lock().await;
+
+        let job = jobs
+            .get_mut(job_id)
+            .ok_or_else(|| Status::not_found(format!("No job with id {}", job_id)))?;
+
+        let index = index as usize;
+        if index > job.commands.len() {
+            return Err(Status::invalid_argument(format!(
+                "Job {} only has {} commands",
+                job_id,
+                job.commands.len()
+            )));
+        }
+
+        // A job that hasn't started yet will start from here, at the front of
+        // the buffer.
+        let current = self.plotted.lock().await.simulator().clone();
+        let start = job.start.get_or_insert_with(|| current.clone());
+
+        let mut target = start.clone();
+        for command in &job.commands[..index] {
+            target.apply(&command.contents);
+        }
+
+        let servo_profile = self.servo_profile.lock().await.clone();
+
+        let mut recovery = vec![servo_profile.pen(false)];
+        recovery.extend(current.move_to(target.position(), TRAVEL_SPEED));
+        if target.is_pen_down() {
+            recovery.push(servo_profile.pen(true));
+        }
+
+        let mut entries = vec![BufferEntry::JobStart(job_id)];
+        entries.extend(
+            recovery
+                .into_iter()
+                .map(|contents| BufferEntry::Command(QueuedCommand::new(contents))),
+        );
+        // Resumed entries go to the front, so they run from the target.
+        let mut end = target;
+        for command in &job.commands[index..] {
+            entries.push(self.accept_entry(command.clone(), Some(&mut end)).await?);
+        }
+        entries.push(BufferEntry::JobEnd(job_id));
+
+        remove_job_entries(&mut buffer, job_id, job.state == JobState::Started);
+        for entry in entries.into_iter().rev() {
+            buffer.push_front(entry);
+        }
+        self.watermarks.update(buffer.len());
+
+        job.state = JobState::Queued;
+        job.executed_count = jobs::executed_before(&job.commands, index);
+        drop(jobs);
+        drop(buffer);
+
+        self.check_buffer_if_running().await;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn recover_job(
+        &self,
+        request: Request<RecoverJobRequest>,
+    ) -> Result<Response<RecoveryState>, Status> {
+        self.authorize(&request, "RecoverJob").await?;
+
+        let request = request.into_inner();
+        let mut recovery = self.recovery.lock().await;
 
-            if command.is_empty() || command.contains('\r') || command.contains('\n') {
-                return Err(Status::invalid_argument("Invalid command"));
+        let Some(current) = recovery.as_mut() else {
+            return Err(Status::failed_precondition("No interrupted job to recover"));
+        };
+
+        match (request.action(), current.step) {
+            (RecoveryAction::Start, _) => {
+                if let Some(index) = request.index {
+                    if index as usize > current.commands.len() {
+                        return Err(Status::invalid_argument(format!(
+                            "The job only has {} commands",
+                            current.commands.len()
+                        )));
+                    }
+
+                    current.index = index as usize;
+                }
+
+                *self.running_status.lock().await = RunningStatus::Paused;
+
+                let pen_up = self.servo_profile.lock().await.pen(false);
+
+                for contents in [pen_up, "EM,0,0".to_string()] {
+                    self.execute_now_unchecked(QueuedCommand::new(contents))
+                        .await?;
+                }
+
+                current.step = RecoveryStep::AwaitingHome;
             }
+            (RecoveryAction::Homed, RecoveryStep::AwaitingHome) => {
+                // The carriage is at home, so start counting from here.
+                let mut commands = vec!["CS".to_string()];
+                commands.extend(current.target().travel_from_home(TRAVEL_SPEED));
 
-            self.command_buffer
-                .clone()
-                .lock_owned()
-                .await
-                .push_back(command);
+                for contents in commands {
+                    self.execute_now(QueuedCommand::new(contents)).await?;
+                }
+
+                current.step = RecoveryStep::AwaitingPositionCheck;
+            }
+            (RecoveryAction::Finish, RecoveryStep::AwaitingPositionCheck) => {
+                if current.target().is_pen_down() {
+                    let pen_down = self.servo_profile.lock().await.pen(true);
+                    self.execute_now(QueuedCommand::new(pen_down)).await?;
+                }
+
+                let mut end = current.target();
+                let mut entries = Vec::new();
+                for command in &current.commands[current.index..] {
+                    entries.push(self.accept_entry(command.clone(), Some(&mut end)).await?);
+                }
+
+                let mut buffer = self.command_buffer.clone().lock_owned().await;
+                let mut jobs = self.jobs.lock().await;
 
-            if *self.running_status.lock().await == RunningStatus::Running {
-                self.control_message_sender
-                    .send(ControlMessage::CheckBuffer)
-                    .unwrap();
+                let name = current.progress.name.clone();
+                let id = jobs.add(name.clone(), None, current.commands.clone());
+                let job = jobs.get_mut(id).unwrap();
+                job.executed_count = jobs::executed_before(&current.commands, current.index);
+                job.start = Some(current.progress.start.clone().unwrap_or_default());
+                drop(jobs);
+                let _ = self.events.send(Event::JobQueued { id, name });
+
+                buffer.push_front(BufferEntry::JobEnd(id));
+                for entry in entries.into_iter().rev() {
+                    buffer.push_front(entry);
+                }
+                buffer.push_front(BufferEntry::JobStart(id));
+                self.watermarks.update(buffer.len());
+                drop(buffer);
+
+                println!("Recovered job {} from command {}", id, current.index);
+
+                *recovery = None;
+                drop(recovery);
+
+                self.resume_plotting().await?;
+
+                return Ok(Response::new(self.recovery_state().await));
+            }
+            (RecoveryAction::Abandon, _) => {
+                println!("Abandoned recovery of {}", current.progress.name);
+
+                *recovery = None;
+            }
+            (action, step) => {
+                return Err(Status::failed_precondition(format!(
+                    "Can't {} while {}",
+                    action.as_str_name(),
+                    step.as_str_name()
+                )));
             }
         }
 
-        Ok(Response::new(Empty {}))
+        drop(recovery);
+
+        Ok(Response::new(self.recovery_state().await))
     }
 
-    async fn clear(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        self.command_buffer.clone().lock_owned().await.clear();
+    #[instrument(skip_all)]
+    async fn get_recovery(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<RecoveryState>, Status> {
+        Ok(Response::new(self.recovery_state().await))
+    }
 
-        Ok(Response::new(Empty {}))
+    #[instrument(skip_all)]
+    async fn get_last_accepted_id(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<SequenceId>, Status> {
+        Ok(Response::new(SequenceId {
+            sequence_id: *self.last_sequence_id.lock().await,
+        }))
     }
 
-    async fn pause(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        *self.running_status.clone().lock_owned().await = RunningStatus::Paused;
+    #[instrument(skip_all)]
+    async fn watch_execution(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::WatchExecutionStream>, Status> {
+        let stream =
+            BroadcastStream::new(self.events.subscribe()).filter_map(|event| match event {
+                Ok(Event::CommandExecuted {
+                    sequence_number,
+                    sequence_id,
+                    command,
+                    response,
+                    timestamp,
+                    label,
+                }) => Some(Ok(ExecutedCommand {
+                    sequence_number,
+                    sequence_id,
+                    command,
+                    response,
+                    timestamp: Some(timestamp.into()),
+                    label,
+                })),
+                Ok(_) => None,
+                Err(BroadcastStreamRecvError::Lagged(count)) => {
+                    Some(Err(Status::data_loss(format!("Missed {} events", count))))
+                }
+            });
 
-        Ok(Response::new(Empty {}))
+        Ok(Response::new(Box::pin(stream)))
     }
 
-    async fn resume(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        let mut running_status = self.running_status.clone().lock_owned().await;
+    #[instrument(skip_all)]
+    async fn watch_events(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let stream =
+            BroadcastStream::new(self.events.subscribe()).filter_map(|event| match event {
+                Ok(event) => Some(Ok(event.to_proto())),
+                Err(BroadcastStreamRecvError::Lagged(count)) => {
+                    Some(Err(Status::data_loss(format!("Missed {} events", count))))
+                }
+            });
 
-        if *running_status == RunningStatus::Paused {
-            *running_status = RunningStatus::Running;
-            self.control_message_sender
-                .send(ControlMessage::CheckBuffer)
-                .unwrap();
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Removes the commands from the first with a sequence ID of at least `start`
+/// up to the first with one of at least `end`, keeping breakpoint, layer and
+/// job markers. Returns the number of commands removed.
+fn clear_sequence_range(buffer: &mut VecDeque<BufferEntry>, start: u64, end: Option<u64>) -> u64 {
+    let reaches = |entry: &BufferEntry, id: u64| match entry {
+        BufferEntry::Command(command) => command
+            .sequence_id
+            .is_some_and(|sequence_id| sequence_id >= id),
+        _ => false,
+    };
+
+    let Some(first) = buffer.iter().position(|entry| reaches(entry, start)) else {
+        return 0;
+    };
+    let last = end
+        .and_then(|end| {
+            buffer
+                .iter()
+                .skip(first)
+                .position(|entry| reaches(entry, end))
+        })
+        .map(|offset| first + offset)
+        .unwrap_or(buffer.len());
+
+    let mut index = 0;
+    let mut count = 0;
+
+    buffer.retain(|entry| {
+        let in_range = (first..last).contains(&index);
+        index += 1;
+
+        if in_range && matches!(entry, BufferEntry::Command(_)) {
+            count += 1;
+            false
+        } else {
+            true
         }
+    });
 
-        Ok(Response::new(Empty {}))
+    count
+}
+
+/// Removes a job's entries, including its markers, from the buffer. Entries at
+/// the front of the buffer are taken to belong to the job if it has started.
+fn remove_job_entries(buffer: &mut VecDeque<BufferEntry>, id: u64, started: bool) {
+    let mut inside = started;
+
+    buffer.retain(|entry| match entry {
+        BufferEntry::JobStart(start) if *start == id => {
+            inside = true;
+            false
+        }
+        BufferEntry::JobEnd(end) if *end == id => {
+            inside = false;
+            false
+        }
+        _ => !inside,
+    });
+}
+
+/// Rearranges the buffered jobs in `queued` so that those in `order` come first,
+/// in that order. Each job takes the place in the buffer of the one it
+/// replaces, so anything streamed between jobs stays where it was.
+fn reorder_job_entries(buffer: &mut VecDeque<BufferEntry>, queued: &[u64], order: &[u64]) {
+    let mut segments = BTreeMap::new();
+    let mut buffered = Vec::new();
+    let mut layout = Vec::new();
+    let mut current = None;
+
+    for entry in buffer.drain(..) {
+        match (&entry, current) {
+            (BufferEntry::JobStart(id), None) if queued.contains(id) => {
+                current = Some(*id);
+                buffered.push(*id);
+                layout.push(None);
+                segments.insert(*id, vec![entry]);
+            }
+            (_, Some(id)) => {
+                let ends = matches!(entry, BufferEntry::JobEnd(end) if end == id);
+                segments.get_mut(&id).unwrap().push(entry);
+
+                if ends {
+                    current = None;
+                }
+            }
+            (_, None) => layout.push(Some(entry)),
+        }
     }
 
-    async fn get_state(&self, _request: Request<Empty>) -> Result<Response<BufferState>, Status> {
-        let (buffer, status) = join![self.command_buffer.lock(), self.running_status.lock()];
+    let mut new_order = order
+        .iter()
+        .copied()
+        .chain(buffered.into_iter().filter(|id| !order.contains(id)))
+        .filter_map(|id| segments.remove(&id));
 
-        return Ok(Response::new(BufferState {
-            buffer_length: buffer.len() as u64,
-            running_status: *status as i32,
-        }));
+    for slot in layout {
+        match slot {
+            Some(entry) => buffer.push_back(entry),
+            None => buffer.extend(new_order.next().unwrap_or_default()),
+        }
+    }
+}
+
+fn to_buffer_entry(command: Command) -> Result<BufferEntry, Status> {
+    let mut markers = [
+        command.breakpoint.map(BufferEntry::Breakpoint),
+        command.layer.map(BufferEntry::Layer),
+        command
+            .tool_change
+            .map(|tool| BufferEntry::ToolChange { tool, resume: true }),
+    ]
+    .into_iter()
+    .flatten();
+
+    let marker = markers.next();
+
+    if markers.next().is_some() {
+        return Err(Status::invalid_argument(
+            "Command can only be one of a breakpoint, layer or tool change",
+        ));
+    }
+
+    if let Some(marker) = marker {
+        if !command.contents.is_empty() {
+            return Err(Status::invalid_argument("Markers must not have contents"));
+        }
+
+        return Ok(marker);
+    }
+
+    let contents = command.contents;
+
+    if contents.is_empty() || contents.contains('\r') || contents.contains('\n') {
+        return Err(Status::invalid_argument("Invalid command"));
+    }
+
+    Ok(BufferEntry::Command(QueuedCommand {
+        sequence_id: command.sequence_id,
+        tag: command.tag,
+        label: command.label,
+        max_duration: command
+            .max_duration_ms
+            .map(|milliseconds| Duration::from_millis(milliseconds.into())),
+        ..QueuedCommand::new(contents)
+    }))
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ToolKind {
+    /// The pen lift servo.
+    Pen,
+    /// An engraver, laser or airbrush trigger on the `SE` output.
+    Engraver,
+}
+
+impl From<ToolKind> for Tool {
+    fn from(kind: ToolKind) -> Tool {
+        match kind {
+            ToolKind::Pen => Tool::Pen,
+            ToolKind::Engraver => Tool::Engraver,
+        }
     }
 }
 
+/// What to do with streamed commands when the buffer is at its maximum length.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OverflowPolicy {
+    /// Fail with RESOURCE_EXHAUSTED.
+    Reject,
+    /// Wait for the buffer to drain.
+    Block,
+    /// Discard the oldest queued commands to make room.
+    DropOldest,
+}
+
+/// What to do when a client streaming commands drops without finishing.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum DisconnectPolicy {
+    /// Keep plotting what's already buffered.
+    Continue,
+    /// Pause until resumed.
+    Pause,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Save the commands left in a running server's buffer, one per line.
+    ExportBuffer {
+        #[command(flatten)]
+        connection: client::Connection,
+        /// File to write to. Defaults to stdout.
+        output: Option<PathBuf>,
+    },
+    /// Queue a command file on a running server's filesystem as a job.
+    LoadFile {
+        #[command(flatten)]
+        connection: client::Connection,
+        /// Path on the server, relative to its watch directory if it has one.
+        path: String,
+    },
+    /// Upload a local command file to a running server in chunks, where it's
+    /// held as a job until release-job. Running it again after a failure
+    /// continues the upload.
+    Upload {
+        #[command(flatten)]
+        connection: client::Connection,
+        path: PathBuf,
+    },
+    /// Queue a held job, such as an uploaded file, for plotting.
+    ReleaseJob {
+        #[command(flatten)]
+        connection: client::Connection,
+        id: u64,
+    },
+    /// Open a raw console to the EBB on a running server, reading lines from stdin.
+    Terminal {
+        #[command(flatten)]
+        connection: client::Connection,
+    },
+    /// Show a live view of a running server, with keys to pause, resume and step.
+    Monitor {
+        #[command(flatten)]
+        connection: client::Connection,
+    },
+}
+
 #[derive(Parser)]
 #[command(long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    action: Option<Action>,
     /// Port to listen on. Defaults to 7878.
     #[arg(short, long)]
     port: Option<u16>,
-    /// Serial device where the AxiDraw is connected. If none specified, will auto-detect.
+    /// Serial device where the AxiDraw is connected, or `nickname:NAME` to pick the
+    /// EBB with that nickname. If none specified, will auto-detect.
     #[arg(short, long)]
     device: Option<String>,
+    /// Run against a simulated EBB instead of a real one.
+    #[arg(long)]
+    dry_run: bool,
+    /// How many times faster than a real machine the simulated one moves, or 0 to
+    /// finish moves instantly. Defaults to 1.
+    #[arg(long, requires = "dry_run")]
+    dry_run_speed: Option<f64>,
+    /// Identical machine to carry on with, using FailOver, if the primary device
+    /// fails. Takes the same forms as --device.
+    #[arg(long)]
+    backup_device: Option<String>,
+    /// JSON file of settings that can be reloaded while running: operator_tokens,
+    /// viewer_tokens, max_buffer, high_watermark, low_watermark, webhooks,
+    /// validate_motion, max_step_rate and max_acceleration. These replace the
+    /// corresponding command line options. Logging options require a restart. work_areas maps
+    /// preset names to the work areas SetWorkArea can pick, and servo_profile
+    /// sets how the server's own pen moves drive the servo. tools maps tool
+    /// names to their pens, and tool_change_position is where the carriage
+    /// parks to change them, in mm from home.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Bearer token granting full control. Can be repeated. If no tokens are
+    /// given, no authentication is required.
+    #[arg(long = "operator-token", value_name = "TOKEN")]
+    operator_tokens: Vec<String>,
+    /// Bearer token granting read-only access to state, history and previews.
+    /// Can be repeated.
+    #[arg(long = "viewer-token", value_name = "TOKEN")]
+    viewer_tokens: Vec<String>,
+    /// Also read newline-delimited EBB commands from this file or FIFO, or from
+    /// stdin if `-`.
+    #[arg(long)]
+    input: Option<PathBuf>,
+    /// Directory to watch for command files, each of which is queued as a job
+    /// and then moved into `loaded/`.
+    #[arg(long)]
+    watch_dir: Option<PathBuf>,
+    /// Directory UploadFile saves command files to. Defaults to `uploads/` in
+    /// the data directory.
+    #[arg(long)]
+    upload_dir: Option<PathBuf>,
+    /// File to record every executed command and response to, for replaying
+    /// with ReplaySession.
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// File to log executed commands, responses and errors to.
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// Size in bytes at which the log file is rotated. Defaults to 10 MB.
+    #[arg(long)]
+    log_max_size: Option<u64>,
+    /// Hours after which the log file is rotated regardless of size.
+    #[arg(long)]
+    log_rotate_hours: Option<u64>,
+    /// Number of rotated log files to keep. Defaults to 5.
+    #[arg(long)]
+    log_keep: Option<usize>,
+    /// File to append a JSON line to for every control call.
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+    /// Hold back motion after startup and after errors until armed with Arm or
+    /// the button on the EBB.
+    #[arg(long)]
+    require_arming: bool,
+    /// What to do when a command stream disconnects unexpectedly.
+    #[arg(long, value_enum, default_value_t = DisconnectPolicy::Continue)]
+    on_stream_disconnect: DisconnectPolicy,
+    /// Seconds to wait after a job before starting the next queued job. If not
+    /// specified, waits for StartNextJob or Resume.
+    #[arg(long)]
+    job_advance_delay: Option<u64>,
+    /// Seconds the buffer can be idle before the pen is raised and the motors
+    /// turned off. They're turned back on for the next move. Checked every 10 seconds.
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// Reject moves that exceed the maximum step rate or acceleration, or have
+    /// impossible durations, when they're queued.
+    #[arg(long)]
+    validate_motion: bool,
+    /// Fastest step rate allowed by --validate-motion, in steps/s. Defaults to 25000.
+    #[arg(long)]
+    max_step_rate: Option<f64>,
+    /// Largest LM acceleration allowed by --validate-motion, in steps/s².
+    #[arg(long)]
+    max_acceleration: Option<f64>,
+    /// Tool switched by SetToolPower until changed with SetTool.
+    #[arg(long, value_enum, default_value_t = ToolKind::Pen)]
+    tool: ToolKind,
+    /// Also cut servo power when idle.
+    #[arg(long)]
+    idle_servo_off: bool,
+    /// Turn the motors off while waiting between jobs.
+    #[arg(long)]
+    motors_off_between_jobs: bool,
+    /// Directory for state kept across restarts, such as lifetime statistics and
+    /// pen heights. Nothing is persisted if not specified.
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// Rule for auto-detecting the EBB: `VID:PID` in hex, `product=SUBSTRING` or
+    /// `serial=NUMBER`. Can be repeated. Defaults to 04D8:FD92 and product=EiBotBoard.
+    #[arg(long = "match", value_name = "RULE")]
+    match_rules: Vec<MatchRule>,
+    /// List detected serial devices and exit.
+    #[arg(long)]
+    list_devices: bool,
+    /// Width of the machine's travel in mm, used to limit jogging. Defaults to the
+    /// last value saved in the data directory, or 300.
+    #[arg(long)]
+    machine_width: Option<f64>,
+    /// Height of the machine's travel in mm, used to limit jogging. Defaults to the
+    /// last value saved in the data directory, or 218.
+    #[arg(long)]
+    machine_height: Option<f64>,
+    /// Maximum number of entries in the buffer for streamed commands.
+    #[arg(long)]
+    max_buffer: Option<usize>,
+    /// What to do with streamed commands when the buffer is full.
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::Reject)]
+    overflow_policy: OverflowPolicy,
+    /// Buffer length at which flow-controlled streams are asked to throttle.
+    #[arg(long)]
+    high_watermark: Option<usize>,
+    /// Buffer length at which throttled streams may resume. Defaults to half the high watermark.
+    #[arg(long)]
+    low_watermark: Option<usize>,
+    /// Milliseconds a serial command can take to be acknowledged, beyond waiting
+    /// for earlier moves, before a warning is logged. Defaults to 250.
+    #[arg(long)]
+    slow_command_ms: Option<u64>,
+    /// Milliseconds to wait for the response to a query or other command that
+    /// doesn't move anything before pausing with an error. Waits indefinitely if
+    /// none specified.
+    #[arg(long)]
+    command_timeout_ms: Option<u64>,
+    /// Milliseconds to wait for the response to a move, after earlier moves
+    /// should have finished, before pausing with an error. Waits indefinitely if
+    /// none specified.
+    #[arg(long)]
+    motion_timeout_ms: Option<u64>,
+    /// Motor supply voltage below which plotting is paused with an error.
+    #[arg(long)]
+    low_voltage: Option<f64>,
+    /// URL to POST a JSON description of plot events to. May be given multiple times.
+    #[arg(long = "webhook")]
+    webhooks: Vec<String>,
+    /// MQTT broker (host or host:port) to publish status to. Disabled if none specified.
+    #[arg(long)]
+    mqtt_broker: Option<String>,
+    /// Prefix for published MQTT topics. Defaults to "axidraw".
+    #[arg(long)]
+    mqtt_topic_prefix: Option<String>,
+    /// Port to serve the web dashboard, its JSON API under /api and Prometheus
+    /// metrics at /metrics on. Disabled if none specified. May be the same as
+    /// --port, to serve them together with gRPC and grpc-web.
+    #[arg(long)]
+    dashboard_port: Option<u16>,
+    /// Origin allowed to call the dashboard API from a browser, or `*` for any.
+    /// Can be repeated. Cross-origin requests are refused if none specified.
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    cors_origins: Vec<String>,
+    /// Extra request header allowed in cross-origin requests. Can be repeated.
+    #[arg(long = "cors-header", value_name = "HEADER")]
+    cors_headers: Vec<String>,
+    /// Seconds browsers may cache CORS preflight responses for.
+    #[arg(long)]
+    cors_max_age: Option<u32>,
+    /// OTLP gRPC endpoint to export traces to. Tracing is disabled if none specified.
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
 }
 
 #[tokio::main]
 async fn main() {
+    let started_at = Instant::now();
     let cli = Cli::parse();
     let port_number = cli.port.unwrap_or(7878);
 
+    if let Some(action) = cli.action {
+        let result = match action {
+            Action::ExportBuffer { connection, output } => {
+                client::export_buffer(connection, output).await
+            }
+            Action::LoadFile { connection, path } => client::load_file(connection, path).await,
+            Action::Upload { connection, path } => client::upload(connection, path).await,
+            Action::ReleaseJob { connection, id } => client::release_job(connection, id).await,
+            Action::Terminal { connection } => client::terminal(connection).await,
+            Action::Monitor { connection } => monitor::run(connection).await,
+        };
+
+        if let Err(error) = result {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    let match_rules: Arc<[MatchRule]> = if cli.match_rules.is_empty() {
+        MatchRule::defaults().into()
+    } else {
+        cli.match_rules.into()
+    };
+
+    if cli.list_devices {
+        for device in device::list_serial_devices(&match_rules) {
+            let usb_ids = match (device.vid, device.pid) {
+                (Some(vid), Some(pid)) => format!(" [{:04x}:{:04x}]", vid, pid),
+                _ => String::new(),
+            };
+            let description = [device.manufacturer, device.product, device.serial_number]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            println!(
+                "{}{}{} {}",
+                if device.is_eibotboard { "* " } else { "  " },
+                device.port_name,
+                usb_ids,
+                description
+            );
+        }
+
+        return;
+    }
+
+    if let Some(endpoint) = cli.otlp_endpoint {
+        telemetry::init(endpoint);
+    }
+
+    let persistent_state = match &cli.data_dir {
+        Some(data_dir) => {
+            fs::create_dir_all(data_dir).unwrap_or_else(|error| {
+                panic!("Could not create {}: {}", data_dir.display(), error)
+            });
+            PersistentState::load(data_dir)
+        }
+        None => PersistentState::default(),
+    };
+    let machine_size = persistent_state.machine_size.unwrap_or((300.0, 218.0));
+    let machine_size = (
+        cli.machine_width.unwrap_or(machine_size.0),
+        cli.machine_height.unwrap_or(machine_size.1),
+    );
+
+    let cli_settings = Settings {
+        operator_tokens: cli.operator_tokens,
+        viewer_tokens: cli.viewer_tokens,
+        max_buffer: cli.max_buffer,
+        high_watermark: cli.high_watermark,
+        low_watermark: cli.low_watermark,
+        webhooks: cli.webhooks,
+        work_areas: BTreeMap::new(),
+        servo_profile: ServoProfile::default(),
+        tools: BTreeMap::new(),
+        tool_change_position: (0.0, 0.0),
+        validate_motion: cli.validate_motion,
+        max_step_rate: cli.max_step_rate,
+        max_acceleration: cli.max_acceleration,
+    };
+
+    let settings = match &cli.config {
+        Some(config) => cli_settings
+            .clone()
+            .with_file(config)
+            .unwrap_or_else(|error| panic!("Could not read {}: {}", config.display(), error)),
+        None => cli_settings.clone(),
+    };
+
+    let dry_run_clock = cli
+        .dry_run
+        .then(|| Arc::new(VirtualClock::new(cli.dry_run_speed.unwrap_or(1.0))));
+
     println!("Waiting for serial connection...");
-    let serial_port = get_serial_port(&cli.device);
+    let serial_port: Box<dyn SerialPort> = match &dry_run_clock {
+        Some(clock) => Box::new(VirtualEbb::new(clock.clone())),
+        None => get_serial_port(&cli.device, &match_rules),
+    };
     println!(
         "Serial connection {} opened",
         serial_port.name().unwrap_or("unknown".to_string())
     );
 
-    let (control_message_sender, mut control_message_receiver) =
-        unbounded_channel::<ControlMessage>();
+    let (pen_up_position, pen_down_position) = settings.servo_profile.positions();
+    let mut status = DeviceStatus {
+        pen_up_position: pen_up_position.or(persistent_state.pen_up_position),
+        pen_down_position: pen_down_position.or(persistent_state.pen_down_position),
+        machine_settings: persistent_state.machine_settings.clone(),
+        ..Default::default()
+    };
+    device::connect(&*serial_port, &mut status);
+    println!(
+        "Firmware: {}",
+        status.firmware_version.as_deref().unwrap_or("unknown")
+    );
+    let capabilities = status.capabilities;
+
+    let (control_message_sender, control_message_receiver) = unbounded_channel::<ControlMessage>();
     let running_status = Arc::new(Mutex::new(RunningStatus::Running));
-    let command_buffer = Arc::new(Mutex::new(VecDeque::<String>::new()));
+    let command_buffer = Arc::new(Mutex::new(VecDeque::<BufferEntry>::new()));
+    let urgent_buffer = Arc::new(Mutex::new(VecDeque::new()));
+    let breakpoint = Arc::new(Mutex::new(None));
+    let layers = Arc::new(Mutex::new(Layers::default()));
+    let jobs = Arc::new(Mutex::new(Jobs::default()));
+    let plotted = Arc::new(Mutex::new(PathTracer::default()));
+    let macros = Arc::new(Mutex::new(BTreeMap::new()));
+    let last_sequence_id = Arc::new(Mutex::new(None));
+    let device_status = Arc::new(Mutex::new(status));
+    let statistics = Arc::new(Mutex::new(PlotStatistics::new(persistent_state.statistics)));
+    let watermarks = Arc::new(Watermarks::new(
+        settings.high_watermark,
+        settings.low_watermark,
+    ));
 
-    let consumer_thread_running_status = running_status.clone();
-    let consumer_thread_command_buffer = command_buffer.clone();
+    let (events, _) = broadcast::channel(1024);
 
-    spawn(move || loop {
-        let control_message = control_message_receiver.blocking_recv().unwrap();
+    let upload_dir = cli.upload_dir.clone().or_else(|| {
+        cli.data_dir
+            .as_ref()
+            .map(|data_dir| data_dir.join("uploads"))
+    });
 
-        match control_message {
-            ControlMessage::CheckBuffer => loop {
-                let state = consumer_thread_running_status.blocking_lock();
-                let mut buffer = consumer_thread_command_buffer.clone().blocking_lock_owned();
+    let features = [
+        (
+            "auth",
+            !settings.operator_tokens.is_empty() || !settings.viewer_tokens.is_empty(),
+        ),
+        ("backup_device", cli.backup_device.is_some()),
+        ("config", cli.config.is_some()),
+        ("dry_run", cli.dry_run),
+        ("audit_log", cli.audit_log.is_some()),
+        ("dashboard", cli.dashboard_port.is_some()),
+        ("grpc_web", true),
+        ("data_dir", cli.data_dir.is_some()),
+        ("input", cli.input.is_some()),
+        ("watch_dir", cli.watch_dir.is_some()),
+        ("uploads", upload_dir.is_some()),
+        ("log_file", cli.log_file.is_some()),
+        ("mqtt", cli.mqtt_broker.is_some()),
+        ("record", cli.record.is_some()),
+        ("require_arming", cli.require_arming),
+        ("webhooks", !settings.webhooks.is_empty()),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| feature.to_string())
+    .collect::<Arc<[String]>>();
 
-                if *state != RunningStatus::Running || buffer.is_empty() {
-                    break;
-                }
+    events::watch_status(running_status.clone(), events.clone());
+    let webhooks = Arc::new(Mutex::new(settings.webhooks.clone()));
+    webhooks::spawn(webhooks.clone(), events.subscribe());
 
-                let command = buffer.pop_front().unwrap().clone();
-                drop(buffer);
-                drop(state);
+    if let Some(record) = cli.record {
+        session::record(record, events.subscribe())
+            .unwrap_or_else(|error| panic!("Could not open session recording: {}", error));
+    }
 
-                send_to_serial_and_wait_for_ok(&*serial_port, command.as_str());
-            },
+    let log_file = cli.log_file.map(|log_file| {
+        let rotation = Rotation {
+            max_size: cli.log_max_size.unwrap_or(10_000_000),
+            max_age: cli
+                .log_rotate_hours
+                .map(|hours| Duration::from_secs(hours * 60 * 60)),
+            keep: cli.log_keep.unwrap_or(5),
+        };
+
+        logfile::spawn(log_file, rotation, events.subscribe())
+            .unwrap_or_else(|error| panic!("Could not open log file: {}", error))
+    });
+
+    if let Some(broker) = cli.mqtt_broker {
+        MqttPublisher {
+            broker,
+            topic_prefix: cli.mqtt_topic_prefix.unwrap_or("axidraw".to_string()),
+            command_buffer: command_buffer.clone(),
+            running_status: running_status.clone(),
+            jobs: jobs.clone(),
+        }
+        .spawn(events.subscribe());
+    }
+
+    let immediate_stop = Arc::new(ImmediateStop::new(
+        serial_port
+            .try_clone()
+            .expect("Could not clone serial port"),
+    ));
+
+    let speed_factor = Arc::new(Mutex::new(1.0));
+    let interlock = Arc::new(Interlock::new(cli.require_arming));
+    let step = Arc::new(AtomicBool::new(false));
+    let in_flight = Arc::new(Mutex::new(None));
+
+    let servo_profile = Arc::new(Mutex::new(settings.servo_profile.clone()));
+    let tool_changes = Arc::new(Mutex::new(ToolChanges {
+        tools: settings.tools.clone(),
+        change_position: settings.tool_change_position,
+        ..Default::default()
+    }));
+
+    let consumer = Consumer {
+        serial_port,
+        command_buffer: command_buffer.clone(),
+        urgent_buffer: urgent_buffer.clone(),
+        running_status: running_status.clone(),
+        breakpoint: breakpoint.clone(),
+        layers: layers.clone(),
+        jobs: jobs.clone(),
+        plotted: plotted.clone(),
+        events: events.clone(),
+        watermarks: watermarks.clone(),
+        device_status: device_status.clone(),
+        capabilities,
+        statistics: statistics.clone(),
+        low_voltage_threshold: cli.low_voltage,
+        job_advance_delay: cli.job_advance_delay.map(Duration::from_secs),
+        motors_off_between_jobs: cli.motors_off_between_jobs,
+        control_message_sender: control_message_sender.clone(),
+        immediate_stop: immediate_stop.clone(),
+        in_flight: VecDeque::new(),
+        sequence_number: 0,
+        speed_factor: speed_factor.clone(),
+        interlock: interlock.clone(),
+        idle_timeout: cli.idle_timeout.map(Duration::from_secs),
+        idle_servo_off: cli.idle_servo_off,
+        last_activity: Instant::now(),
+        parked: None,
+        motor_mode: persistent_state
+            .machine_settings
+            .microstep_mode
+            .map(|mode| format!("EM,{},{}", mode, mode)),
+        motion_ends_at: Instant::now(),
+        slow_command_threshold: Duration::from_millis(cli.slow_command_ms.unwrap_or(250)),
+        fast_command_timeout: cli.command_timeout_ms.map(Duration::from_millis),
+        motion_command_timeout: cli.motion_timeout_ms.map(Duration::from_millis),
+        step: step.clone(),
+        current_command: in_flight.clone(),
+        servo_profile: servo_profile.clone(),
+        tool_changes: tool_changes.clone(),
+    };
+
+    spawn(move || consumer.run(control_message_receiver));
+
+    let power_check_sender = control_message_sender.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(consumer::POWER_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if power_check_sender.send(ControlMessage::CheckPower).is_err() {
+                break;
+            }
         }
     });
 
-    let service = AxidrawOverHttpServer::new(AxidrawService {
+    let tokens = Arc::new(RwLock::new(settings.tokens()));
+
+    let audit = AuditRecorder::open(cli.audit_log.as_deref())
+        .unwrap_or_else(|error| panic!("Could not open audit log: {}", error));
+
+    let work_area = Arc::new(Mutex::new(
+        persistent_state
+            .work_area
+            .clone()
+            .unwrap_or_else(|| WorkArea::machine(machine_size)),
+    ));
+
+    let recovery = cli
+        .data_dir
+        .as_ref()
+        .zip(persistent_state.job_progress.clone())
+        .and_then(|(data_dir, progress)| Recovery::load(data_dir, progress));
+
+    if let Some(recovery) = &recovery {
+        println!(
+            "Job {} was interrupted after {} of {} commands and can be recovered",
+            recovery.progress.name,
+            recovery.index,
+            recovery.commands.len()
+        );
+    }
+
+    let recovery = Arc::new(Mutex::new(recovery));
+
+    let store = cli.data_dir.map(|data_dir| Store {
+        data_dir,
+        machine_size,
+        statistics: statistics.clone(),
+        device_status: device_status.clone(),
+        work_area: work_area.clone(),
+        jobs: jobs.clone(),
+        recovery: recovery.clone(),
+        saved_job: Arc::default(),
+    });
+
+    if let Some(store) = &store {
+        store.clone().spawn();
+    }
+
+    let service = Arc::new(AxidrawService {
         control_message_sender,
         running_status,
         command_buffer,
+        urgent_buffer,
+        breakpoint,
+        layers,
+        jobs,
+        plotted,
+        macros,
+        last_sequence_id,
+        events: events.clone(),
+        watermarks,
+        machine_size,
+        device_status,
+        match_rules,
+        statistics,
+        disconnect_policy: cli.on_stream_disconnect,
+        audit: Arc::new(Mutex::new(audit)),
+        immediate_stop,
+        max_buffer_length: Arc::new(Mutex::new(settings.max_buffer)),
+        overflow_policy: cli.overflow_policy,
+        started_at,
+        features,
+        watch_dir: cli.watch_dir.clone(),
+        terminal: Arc::new(Mutex::new(None)),
+        speed_factor,
+        interlock,
+        tool: Arc::new(Mutex::new(cli.tool.into())),
+        motion_limits: Arc::new(RwLock::new(settings.motion_limits())),
+        work_area,
+        work_area_presets: Arc::new(Mutex::new(workarea::presets(&settings.work_areas))),
+        servo_profile,
+        tool_changes,
+        recovery,
+        uploads: upload_dir.map(|dir| Arc::new(Uploads::new(dir))),
+        dry_run_clock,
+        resume_at: Arc::new(Mutex::new(None)),
+        in_flight,
+        backup_device: cli.backup_device,
+        step,
+        tokens: tokens.clone(),
+        webhooks,
+        config: cli.config,
+        cli_settings,
     });
 
-    let server = Server::builder().add_service(service).serve_with_shutdown(
-        (IpAddr::from_str("::").unwrap(), port_number).into(),
-        async move {
-            tokio::signal::ctrl_c().await.unwrap();
-        },
-    );
+    if let Some(input) = cli.input {
+        input::spawn(input, service.clone());
+    }
 
-    let _ = tokio::task::spawn(server).await;
-}
+    if let Some(watch_dir) = cli.watch_dir {
+        hotfolder::spawn(watch_dir, service.clone());
+    }
 
-fn get_serial_port(device: &Option<String>) -> Box<dyn SerialPort> {
-    let port_filter = |port_info: &&SerialPortInfo| {
-        if let Some(device) = device {
-            port_info.port_name == *device
-        } else if let SerialPortType::UsbPort(usb_port_info) = &port_info.port_type {
-            usb_port_info
-                .product
-                .as_ref()
-                .unwrap_or(&"".to_string())
-                .contains("EiBotBoard")
+    let mut shared_port_routes = None;
+
+    if let Some(dashboard_port) = cli.dashboard_port {
+        let routes = dashboard::routes(
+            service.clone(),
+            tokens.clone(),
+            Cors {
+                origins: cli.cors_origins,
+                headers: cli.cors_headers,
+                max_age: cli.cors_max_age,
+            },
+            events.subscribe(),
+        );
+
+        if dashboard_port == port_number {
+            shared_port_routes = Some(routes);
         } else {
-            false
+            dashboard::spawn(dashboard_port, routes);
         }
-    };
+    }
 
-    let port_info = loop {
-        let port_info = serialport::available_ports()
-            .unwrap_or_default()
-            .iter()
-            .find(port_filter)
-            .cloned();
+    signals::spawn_hangup_handler(service.clone(), log_file);
 
-        if let Some(port_info) = port_info {
-            break port_info;
-        } else {
-            sleep(Duration::from_secs(1));
+    let shutdown_service = service.clone();
+    let service = InterceptedService::new(
+        AxidrawOverHttpServer::from_arc(service),
+        auth::interceptor(tokens),
+    );
+
+    let address = (IpAddr::from_str("::").unwrap(), port_number).into();
+
+    let _ = match shared_port_routes {
+        Some(routes) => {
+            // Browsers reach the dashboard and grpc-web over HTTP/1.1, while
+            // gRPC needs HTTP/2.
+            let server = Server::builder()
+                .accept_http1(true)
+                .layer(MultiplexLayer::new(warp::service(routes)))
+                .layer(GrpcWebLayer::new())
+                .add_service(service)
+                .serve_with_shutdown(address, signals::shutdown());
+
+            tokio::task::spawn(server).await
+        }
+        None => {
+            let server = Server::builder()
+                .accept_http1(true)
+                .layer(GrpcWebLayer::new())
+                .add_service(service)
+                .serve_with_shutdown(address, signals::shutdown());
+
+            tokio::task::spawn(server).await
         }
     };
 
-    serialport::new(&port_info.port_name, 9600)
-        .timeout(Duration::from_secs(1))
-        .open()
-        .unwrap_or_else(|_| panic!("Could not create port on {}", &port_info.port_name))
+    println!("Shutting down");
+    shutdown_service.park().await;
+
+    if let Some(store) = store {
+        store.save().await;
+    }
+
+    telemetry::shutdown();
 }
 
-fn send_to_serial_and_wait_for_ok(serial_port: &dyn SerialPort, command: &str) {
-    println!("Writing to serial port: {}", command);
+fn get_serial_port(device: &Option<String>, match_rules: &[MatchRule]) -> Box<dyn SerialPort> {
+    hotplug::wait_for(|| {
+        find_serial_port(device, match_rules).map(|serial_port| {
+            serial_port.unwrap_or_else(|error| panic!("Could not create port: {}", error))
+        })
+    })
+}
 
-    let mut serial_reader_lines = BufReader::new(serial_port.try_clone().unwrap()).lines();
+/// Opens `device`, which can be `nickname:NAME`, or the first EBB if `None`.
+/// Returns `None` if no such device is attached.
+fn find_serial_port(
+    device: &Option<String>,
+    match_rules: &[MatchRule],
+) -> Option<serialport::Result<Box<dyn SerialPort>>> {
+    let nickname = device
+        .as_deref()
+        .and_then(|device| device.strip_prefix("nickname:"));
+    let ports = device::available_ports();
 
-    let mut serial_writer = BufWriter::new(serial_port.try_clone().unwrap());
-    serial_writer
-        .write_all(format!("{}\r", command).as_bytes())
-        .unwrap();
-    serial_writer.flush().unwrap();
+    match (device, nickname) {
+        (_, Some(nickname)) => ports
+            .iter()
+            .filter(|port_info| device::is_eibotboard(port_info, match_rules))
+            .find_map(|port_info| {
+                let serial_port = open_serial_port(&port_info.port_name).ok()?;
 
-    let response = loop {
-        if let Ok(response) = serial_reader_lines.next().unwrap() {
-            break response;
-        }
-    };
+                (device::read_nickname(&*serial_port).as_deref() == Some(nickname))
+                    .then_some(Ok(serial_port))
+            }),
+        (Some(device), None) => ports
+            .iter()
+            .find(|port_info| port_info.port_name == *device)
+            .map(|port_info| open_serial_port(&port_info.port_name)),
+        (None, None) => ports
+            .iter()
+            .find(|port_info| device::is_eibotboard(port_info, match_rules))
+            .map(|port_info| open_serial_port(&port_info.port_name)),
+    }
+}
 
-    println!("Repsonse from serial port: {}", &response);
+fn open_serial_port(port_name: &str) -> serialport::Result<Box<dyn SerialPort>> {
+    serialport::new(port_name, 9600)
+        .timeout(Duration::from_secs(1))
+        .open()
 }
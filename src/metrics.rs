@@ -0,0 +1,179 @@
+use crate::axidraw_over_http::{BufferState, JobInfo, JobState, RunningStatus, Statistics};
+use std::{fmt::Write, time::Duration};
+
+/// Job states reported even when no job is in them, so that series don't
+/// disappear between scrapes.
+const JOB_STATES: [JobState; 6] = [
+    JobState::Queued,
+    JobState::Scheduled,
+    JobState::Held,
+    JobState::Started,
+    JobState::Complete,
+    JobState::Cancelled,
+];
+
+/// Renders the server's state in the Prometheus text format.
+pub fn render(
+    state: &BufferState,
+    statistics: &Statistics,
+    jobs: &[JobInfo],
+    uptime: Duration,
+) -> String {
+    let mut metrics = Metrics::default();
+
+    metrics.gauge(
+        "axidraw_uptime_seconds",
+        "Time since the server started.",
+        uptime.as_secs_f64(),
+    );
+    metrics.gauge(
+        "axidraw_buffer_length",
+        "Entries waiting in the command buffer.",
+        state.buffer_length as f64,
+    );
+    metrics.gauge(
+        "axidraw_running",
+        "1 while plotting from the buffer, 0 while paused.",
+        flag(state.running_status() == RunningStatus::Running),
+    );
+    metrics.gauge(
+        "axidraw_speed_factor",
+        "Multiplier applied to the speed of queued moves.",
+        state.speed_factor,
+    );
+
+    metrics.header("axidraw_jobs", "gauge", "Jobs in each state.");
+    for job_state in JOB_STATES {
+        let count = jobs.iter().filter(|job| job.state() == job_state).count();
+
+        metrics.sample(
+            &format!(
+                "axidraw_jobs{{state=\"{}\"}}",
+                job_state.as_str_name().to_lowercase()
+            ),
+            count as f64,
+        );
+    }
+
+    let session = statistics.session.clone().unwrap_or_default();
+
+    metrics.counter(
+        "axidraw_commands_executed_total",
+        "Commands sent from the buffer this session.",
+        session.commands_executed as f64,
+    );
+    metrics.counter(
+        "axidraw_pen_lifts_total",
+        "Times the pen was raised this session.",
+        session.pen_lifts as f64,
+    );
+    metrics.counter(
+        "axidraw_pen_down_distance_millimeters_total",
+        "Distance drawn with the pen down this session.",
+        session.pen_down_distance,
+    );
+    metrics.counter(
+        "axidraw_motor_on_seconds_total",
+        "Time the motors were on this session.",
+        session
+            .motor_on_time
+            .map(|duration| duration.seconds as f64 + duration.nanos as f64 / 1e9)
+            .unwrap_or_default(),
+    );
+
+    metrics.text
+}
+
+#[derive(Default)]
+struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, "gauge", help);
+        self.sample(name, value);
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, "counter", help);
+        self.sample(name, value);
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, series: &str, value: f64) {
+        let _ = writeln!(self.text, "{} {}", series, value);
+    }
+}
+
+fn flag(enabled: bool) -> f64 {
+    if enabled {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::axidraw_over_http::PlotCounters;
+
+    #[test]
+    fn renders_state_jobs_and_counters() {
+        let state = BufferState {
+            buffer_length: 12,
+            running_status: RunningStatus::Paused as i32,
+            speed_factor: 1.5,
+            ..Default::default()
+        };
+        let statistics = Statistics {
+            session: Some(PlotCounters {
+                commands_executed: 40,
+                pen_lifts: 3,
+                pen_down_distance: 250.5,
+                motor_on_time: Some(prost_types::Duration {
+                    seconds: 90,
+                    nanos: 500_000_000,
+                }),
+            }),
+            ..Default::default()
+        };
+        let jobs = [
+            JobInfo {
+                id: 1,
+                state: JobState::Complete as i32,
+                ..Default::default()
+            },
+            JobInfo {
+                id: 2,
+                state: JobState::Held as i32,
+                ..Default::default()
+            },
+        ];
+
+        let metrics = render(&state, &statistics, &jobs, Duration::from_secs(60));
+        let lines = metrics.lines().collect::<Vec<_>>();
+
+        for expected in [
+            "axidraw_uptime_seconds 60",
+            "axidraw_buffer_length 12",
+            "axidraw_running 0",
+            "axidraw_speed_factor 1.5",
+            "axidraw_jobs{state=\"held\"} 1",
+            "axidraw_jobs{state=\"complete\"} 1",
+            "axidraw_jobs{state=\"queued\"} 0",
+            "axidraw_commands_executed_total 40",
+            "axidraw_pen_lifts_total 3",
+            "axidraw_pen_down_distance_millimeters_total 250.5",
+            "axidraw_motor_on_seconds_total 90.5",
+            "# TYPE axidraw_pen_lifts_total counter",
+        ] {
+            assert!(lines.contains(&expected), "missing {}", expected);
+        }
+    }
+}
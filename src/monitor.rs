@@ -0,0 +1,305 @@
+use crate::{
+    axidraw_over_http::{
+        axidraw_over_http_client::AxidrawOverHttpClient, estimate_request, BufferState, Empty,
+        EstimateRequest, ExecutedCommand, JobInfo, JobState, PauseRequest,
+    },
+    client::Connection,
+};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout},
+    widgets::{Block, Borders, Gauge, List, Paragraph, Sparkline},
+    Frame, Terminal,
+};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::{self, Stdout},
+    thread,
+    time::Duration,
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tonic::{transport::Channel, Streaming};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Number of buffer length samples shown in the graph.
+const HISTORY_LENGTH: usize = 120;
+const RECENT_RESPONSES: usize = 100;
+
+#[derive(Default)]
+struct Monitor {
+    state: Option<BufferState>,
+    buffer_lengths: VecDeque<u64>,
+    current_command: Option<String>,
+    responses: VecDeque<String>,
+    job: Option<JobInfo>,
+    /// Estimated duration of the started job, with its ID.
+    job_estimate: Option<(u64, Duration)>,
+    /// Result of the last key binding.
+    message: Option<String>,
+}
+
+impl Monitor {
+    async fn poll(
+        &mut self,
+        client: &mut AxidrawOverHttpClient<Channel>,
+        connection: &Connection,
+    ) -> Result<(), Box<dyn Error>> {
+        let state = client
+            .get_state(connection.request(Empty {})?)
+            .await?
+            .into_inner();
+
+        if self.buffer_lengths.len() == HISTORY_LENGTH {
+            self.buffer_lengths.pop_front();
+        }
+
+        self.buffer_lengths.push_back(state.buffer_length);
+        self.state = Some(state);
+
+        let jobs = client
+            .list_jobs(connection.request(Empty {})?)
+            .await?
+            .into_inner()
+            .jobs;
+
+        self.job = jobs
+            .into_iter()
+            .find(|job| job.state() == JobState::Started);
+
+        if let Some(job) = &self.job {
+            if self.job_estimate.map(|(id, _)| id) != Some(job.id) {
+                let estimate = client
+                    .estimate_duration(connection.request(EstimateRequest {
+                        source: Some(estimate_request::Source::JobId(job.id)),
+                    })?)
+                    .await?
+                    .into_inner();
+
+                let duration = estimate
+                    .duration
+                    .and_then(|duration| duration.try_into().ok())
+                    .unwrap_or_default();
+
+                self.job_estimate = Some((job.id, duration));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record(&mut self, executed: ExecutedCommand) {
+        if self.responses.len() == RECENT_RESPONSES {
+            self.responses.pop_back();
+        }
+
+        let command = match &executed.label {
+            Some(label) => format!("{} ({})", executed.command, label),
+            None => executed.command,
+        };
+
+        self.responses
+            .push_front(format!("{} -> {}", command, executed.response.join(" ")));
+        self.current_command = Some(command);
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [status, graph, progress, current, responses, help] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(8),
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.size());
+
+        let status_line = match &self.state {
+            Some(state) => {
+                let mut parts = vec![
+                    format!("{:?}", state.running_status()),
+                    format!("buffer {}", state.buffer_length),
+                    format!("speed {:.2}x", state.speed_factor),
+                ];
+
+                if let Some(layer) = &state.current_layer {
+                    parts.push(format!("layer {}", layer));
+                }
+
+                if let Some(breakpoint) = &state.breakpoint {
+                    parts.push(format!("at breakpoint {}", breakpoint));
+                }
+
+                if let Some(resume_in) = state
+                    .resume_in
+                    .clone()
+                    .and_then(|resume_in| Duration::try_from(resume_in).ok())
+                {
+                    parts.push(format!("resuming in {}", format_duration(resume_in)));
+                }
+
+                if !state.armed {
+                    parts.push("disarmed".to_string());
+                }
+
+                parts.join("  |  ")
+            }
+            None => "Connecting...".to_string(),
+        };
+
+        frame.render_widget(
+            Paragraph::new(status_line).block(bordered("Status")),
+            status,
+        );
+
+        let buffer_lengths = self.buffer_lengths.iter().copied().collect::<Vec<_>>();
+
+        frame.render_widget(
+            Sparkline::default()
+                .block(bordered("Buffer depth"))
+                .data(&buffer_lengths),
+            graph,
+        );
+
+        let gauge = match &self.job {
+            Some(job) => {
+                let ratio = if job.command_count == 0 {
+                    1.0
+                } else {
+                    (job.executed_count as f64 / job.command_count as f64).min(1.0)
+                };
+
+                let mut label = format!("{}: {:.0}%", job.name, ratio * 100.0);
+
+                if let Some((_, duration)) = self.job_estimate {
+                    let remaining = duration.mul_f64(1.0 - ratio);
+                    label.push_str(&format!(", ETA {}", format_duration(remaining)));
+                }
+
+                Gauge::default().ratio(ratio).label(label)
+            }
+            None => Gauge::default().ratio(0.0).label("No job running"),
+        };
+
+        frame.render_widget(gauge.block(bordered("Progress")), progress);
+
+        frame.render_widget(
+            Paragraph::new(self.current_command.clone().unwrap_or_default())
+                .block(bordered("Current command")),
+            current,
+        );
+
+        frame.render_widget(
+            List::new(self.responses.iter().map(String::as_str)).block(bordered("Responses")),
+            responses,
+        );
+
+        let mut help_line = "p pause  r resume  s step  q quit".to_string();
+
+        if let Some(message) = &self.message {
+            help_line.push_str(&format!("  |  {}", message));
+        }
+
+        frame.render_widget(Paragraph::new(help_line), help);
+    }
+}
+
+fn bordered(title: &str) -> Block<'_> {
+    Block::default().borders(Borders::ALL).title(title)
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Shows a live view of a running server until `q` is pressed.
+pub async fn run(connection: Connection) -> Result<(), Box<dyn Error>> {
+    let mut client = AxidrawOverHttpClient::connect(connection.server.clone()).await?;
+    let executed = client
+        .watch_execution(connection.request(Empty {})?)
+        .await?
+        .into_inner();
+
+    let (key_sender, keys) = mpsc::unbounded_channel();
+
+    thread::spawn(move || loop {
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                if key_sender.send(key.code).is_err() {
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    });
+
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let result = show(&mut terminal, client, connection, executed, keys).await;
+
+    disable_raw_mode()?;
+    execute!(io::stdout(), LeaveAlternateScreen)?;
+
+    result
+}
+
+async fn show(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    mut client: AxidrawOverHttpClient<Channel>,
+    connection: Connection,
+    mut executed: Streaming<ExecutedCommand>,
+    mut keys: UnboundedReceiver<KeyCode>,
+) -> Result<(), Box<dyn Error>> {
+    let mut monitor = Monitor::default();
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        terminal.draw(|frame| monitor.draw(frame))?;
+
+        tokio::select! {
+            _ = interval.tick() => monitor.poll(&mut client, &connection).await?,
+            command = executed.message() => match command? {
+                Some(command) => monitor.record(command),
+                None => return Err("Server closed the connection".into()),
+            },
+            Some(key) = keys.recv() => {
+                let result = match key {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('p') => client
+                        .pause(connection.request(PauseRequest::default())?)
+                        .await
+                        .map(|_| "Paused"),
+                    KeyCode::Char('r') => client
+                        .resume(connection.request(Empty {})?)
+                        .await
+                        .map(|_| "Resumed"),
+                    KeyCode::Char('s') => client
+                        .step(connection.request(Empty {})?)
+                        .await
+                        .map(|_| "Stepped"),
+                    _ => continue,
+                };
+
+                monitor.message = Some(match result {
+                    Ok(message) => message.to_string(),
+                    Err(status) => status.message().to_string(),
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{axidraw_over_http::RunningStatus, events::Event, jobs::Jobs, BufferEntry};
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    Mutex,
+};
+
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct MqttPublisher {
+    pub broker: String,
+    pub topic_prefix: String,
+    pub command_buffer: Arc<Mutex<VecDeque<BufferEntry>>>,
+    pub running_status: Arc<Mutex<RunningStatus>>,
+    pub jobs: Arc<Mutex<Jobs>>,
+}
+
+impl MqttPublisher {
+    pub fn spawn(self, events: Receiver<Event>) {
+        let (host, port) = match self.broker.rsplit_once(':') {
+            Some((host, port)) => (
+                host.to_string(),
+                port.parse()
+                    .unwrap_or_else(|_| panic!("Invalid MQTT broker port {}", port)),
+            ),
+            None => (self.broker.clone(), 1883),
+        };
+
+        let mut options = MqttOptions::new("axidraw-over-http", host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+
+        let (client, mut event_loop) = AsyncClient::new(options, 64);
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(error) = event_loop.poll().await {
+                    println!("MQTT connection error: {}", error);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        });
+
+        tokio::spawn(publish_errors(
+            client.clone(),
+            format!("{}/error", self.topic_prefix),
+            events,
+        ));
+        tokio::spawn(self.publish_state(client));
+    }
+
+    async fn publish_state(self, client: AsyncClient) {
+        let mut last_published = None;
+
+        loop {
+            let running_status = *self.running_status.lock().await;
+            let buffer_length = self.command_buffer.lock().await.len();
+            let progress = self
+                .jobs
+                .lock()
+                .await
+                .current()
+                .map(|job| job.progress())
+                .unwrap_or(0.0);
+
+            let state = (
+                running_status.as_str_name().to_lowercase(),
+                buffer_length.to_string(),
+                format!("{:.1}", progress),
+            );
+
+            if last_published.as_ref() != Some(&state) {
+                for (topic, payload) in [
+                    ("status", &state.0),
+                    ("buffer_length", &state.1),
+                    ("progress", &state.2),
+                ] {
+                    let _ = client
+                        .publish(
+                            format!("{}/{}", self.topic_prefix, topic),
+                            QoS::AtLeastOnce,
+                            true,
+                            payload.clone(),
+                        )
+                        .await;
+                }
+
+                last_published = Some(state);
+            }
+
+            tokio::time::sleep(PUBLISH_INTERVAL).await;
+        }
+    }
+}
+
+async fn publish_errors(client: AsyncClient, topic: String, mut events: Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(Event::Error { message }) => {
+                let _ = client
+                    .publish(topic.clone(), QoS::AtLeastOnce, false, message)
+                    .await;
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tonic::{
+    body::BoxBody,
+    codegen::{
+        http::{header::CONTENT_TYPE, Request, Response},
+        Body as _,
+    },
+    transport::Body,
+    Status,
+};
+use tower::{Layer, Service, ServiceExt};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Lets the gRPC server share its port with another HTTP service, such as the
+/// dashboard, by passing it every request that isn't gRPC or grpc-web.
+#[derive(Clone)]
+pub struct MultiplexLayer<W> {
+    web: W,
+}
+
+impl<W> MultiplexLayer<W> {
+    pub fn new(web: W) -> Self {
+        MultiplexLayer { web }
+    }
+}
+
+impl<G, W: Clone> Layer<G> for MultiplexLayer<W> {
+    type Service = Multiplex<G, W>;
+
+    fn layer(&self, grpc: G) -> Multiplex<G, W> {
+        Multiplex {
+            grpc,
+            web: self.web.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Multiplex<G, W> {
+    grpc: G,
+    web: W,
+}
+
+impl<G, W> Service<Request<Body>> for Multiplex<G, W>
+where
+    G: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    G::Future: Send,
+    G::Error: Into<BoxError>,
+    W: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    W::Future: Send,
+    W::Error: Into<BoxError>,
+{
+    type Response = Response<BoxBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<BoxBody>, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _context: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        // Each request waits for the service it's routed to in `call`.
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let is_grpc = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/grpc"));
+
+        if is_grpc {
+            let grpc = self.grpc.clone();
+
+            Box::pin(async move { grpc.oneshot(request).await.map_err(Into::into) })
+        } else {
+            let web = self.web.clone();
+
+            Box::pin(async move {
+                web.oneshot(request)
+                    .await
+                    .map(|response| response.map(box_body))
+                    .map_err(Into::into)
+            })
+        }
+    }
+}
+
+fn box_body(body: Body) -> BoxBody {
+    body.map_err(|error| Status::from_error(error.into()))
+        .boxed_unsync()
+}
@@ -0,0 +1,116 @@
+use crate::ebb::{Point, Simulator};
+use std::fmt::Write;
+
+const REMAINING_COLOR: &str = "black";
+const PLOTTED_COLOR: &str = "#aaa";
+const MARGIN: f64 = 5.0;
+const WORK_AREA_COLOR: &str = "#4a90d9";
+
+/// Collects pen-down movements into polylines.
+#[derive(Default)]
+pub struct PathTracer {
+    simulator: Simulator,
+    paths: Vec<Vec<Point>>,
+    drawing: bool,
+}
+
+impl PathTracer {
+    pub fn trace(&mut self, command: &str) {
+        let movement = self.simulator.apply(command);
+
+        if !self.simulator.is_pen_down() {
+            self.drawing = false;
+            return;
+        }
+
+        if let Some((start, end)) = movement {
+            if !self.drawing {
+                self.paths.push(vec![start]);
+                self.drawing = true;
+            }
+
+            self.paths.last_mut().unwrap().push(end);
+        }
+    }
+
+    /// Returns a tracer starting from the same position and pen state, with no paths.
+    pub fn continuation(&self) -> PathTracer {
+        PathTracer {
+            simulator: self.simulator.clone(),
+            paths: Vec::new(),
+            drawing: false,
+        }
+    }
+
+    /// Moves the tracked position back after moves were cut short, leaving the
+    /// drawn paths as they are.
+    pub fn rewind(&mut self, steps1: i64, steps2: i64) {
+        self.simulator.offset(steps1, steps2);
+        self.drawing = false;
+    }
+
+    /// Starts tracking from home with the pen up, as after switching machines.
+    pub fn reset_position(&mut self) {
+        self.simulator = Simulator::default();
+        self.drawing = false;
+    }
+
+    pub fn clear_paths(&mut self) {
+        self.paths.clear();
+        self.drawing = false;
+    }
+
+    pub fn simulator(&self) -> &Simulator {
+        &self.simulator
+    }
+
+    pub fn paths(&self) -> &[Vec<Point>] {
+        &self.paths
+    }
+}
+
+/// Renders the paths in mm, with the outline of a `work_area` sheet of that
+/// size placed at home.
+pub fn render_svg(remaining: &[Vec<Point>], plotted: &[Vec<Point>], work_area: Point) -> String {
+    let corner = [work_area];
+    let points = || remaining.iter().chain(plotted).flatten().chain(&corner);
+    let min_x = points().map(|point| point.0).fold(0.0, f64::min) - MARGIN;
+    let min_y = points().map(|point| point.1).fold(0.0, f64::min) - MARGIN;
+    let max_x = points().map(|point| point.0).fold(0.0, f64::max) + MARGIN;
+    let max_y = points().map(|point| point.1).fold(0.0, f64::max) + MARGIN;
+    let (width, height) = (max_x - min_x, max_y - min_y);
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}mm" height="{height}mm" viewBox="{min_x} {min_y} {width} {height}">"#
+    );
+
+    write!(
+        svg,
+        r#"<rect x="0" y="0" width="{}" height="{}" fill="none" stroke="{WORK_AREA_COLOR}" stroke-width="0.3" stroke-dasharray="2 2"/>"#,
+        work_area.0, work_area.1
+    )
+    .unwrap();
+
+    for (paths, color) in [(plotted, PLOTTED_COLOR), (remaining, REMAINING_COLOR)] {
+        write!(
+            svg,
+            r#"<g fill="none" stroke="{color}" stroke-width="0.3" stroke-linecap="round" stroke-linejoin="round">"#
+        )
+        .unwrap();
+
+        for path in paths {
+            svg.push_str(r#"<polyline points=""#);
+            for (index, (x, y)) in path.iter().enumerate() {
+                let separator = if index == 0 { "" } else { " " };
+                write!(svg, "{separator}{x:.3},{y:.3}").unwrap();
+            }
+            svg.push_str(r#""/>"#);
+        }
+
+        svg.push_str("</g>");
+    }
+
+    svg.push_str("</svg>");
+
+    svg
+}
@@ -0,0 +1,258 @@
+use crate::{
+    axidraw_over_http::{self, Command, RecoveryStep},
+    ebb::Simulator,
+    jobs::{self, Job},
+};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fs, io, path::Path};
+
+const JOB_FILE: &str = "job.pb";
+
+/// How far the started job got, saved so it can be recovered after the
+/// server or the machine loses power.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JobProgress {
+    pub id: u64,
+    pub name: String,
+    pub executed_count: u64,
+    /// SHA-256 of the job file written for this job.
+    #[serde(default)]
+    pub checksum: String,
+    /// Where the carriage was when the job started.
+    #[serde(default)]
+    pub start: Option<Simulator>,
+}
+
+impl JobProgress {
+    /// Progress of `job`, whose job file has `checksum`.
+    pub fn of(job: &Job, checksum: String) -> JobProgress {
+        JobProgress {
+            id: job.id,
+            name: job.name.clone(),
+            executed_count: job.executed_count,
+            checksum,
+            start: job.start.clone(),
+        }
+    }
+}
+
+/// The contents of the job file.
+#[derive(Clone, PartialEq, Message)]
+struct SavedJob {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(message, optional, tag = "2")]
+    job: Option<axidraw_over_http::Job>,
+}
+
+/// Writes the commands of the started job to `data_dir`, returning the
+/// checksum of the file.
+pub fn save_job(data_dir: &Path, job: &Job) -> io::Result<String> {
+    let contents = SavedJob {
+        id: job.id,
+        job: Some(axidraw_over_http::Job {
+            name: job.name.clone(),
+            commands: job.commands.clone(),
+            start_at: None,
+        }),
+    }
+    .encode_to_vec();
+    let temporary_path = data_dir.join(format!("{}.tmp", JOB_FILE));
+
+    fs::write(&temporary_path, &contents)?;
+    fs::rename(temporary_path, data_dir.join(JOB_FILE))?;
+
+    Ok(checksum(&contents))
+}
+
+/// Loads the job file, with its checksum.
+fn load_job(data_dir: &Path) -> io::Result<(SavedJob, String)> {
+    let contents = fs::read(data_dir.join(JOB_FILE))?;
+    let job = SavedJob::decode(contents.as_slice())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+    Ok((job, checksum(&contents)))
+}
+
+fn checksum(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// A job interrupted before it finished, and how far the operator has got
+/// with recovering it.
+pub struct Recovery {
+    pub progress: JobProgress,
+    pub commands: Vec<Command>,
+    /// The command plotting carries on from.
+    pub index: usize,
+    pub step: RecoveryStep,
+}
+
+impl Recovery {
+    /// Loads the job that was interrupted, if `progress` was saved while one
+    /// was running and the job file is the one saved with it.
+    pub fn load(data_dir: &Path, progress: JobProgress) -> Option<Recovery> {
+        let job = match load_job(data_dir) {
+            Ok((saved, checksum)) if saved.id == progress.id && checksum == progress.checksum => {
+                saved.job.unwrap_or_default()
+            }
+            Ok(_) => {
+                println!("Not recovering: the job file doesn't match the saved progress");
+                return None;
+            }
+            Err(error) => {
+                println!("Could not load the interrupted job: {}", error);
+                return None;
+            }
+        };
+
+        Some(Recovery {
+            index: jobs::index_after(&job.commands, progress.executed_count),
+            progress,
+            commands: job.commands,
+            step: RecoveryStep::NotStarted,
+        })
+    }
+
+    /// The position and pen state where plotting carries on.
+    pub fn target(&self) -> Simulator {
+        let mut target = self.progress.start.clone().unwrap_or_default();
+
+        for command in &self.commands[..self.index] {
+            target.apply(&command.contents);
+        }
+
+        target
+    }
+
+    pub fn instructions(&self) -> &'static str {
+        match self.step {
+            RecoveryStep::NotStarted => {
+                "Start the recovery to raise the pen and turn the motors off"
+            }
+            RecoveryStep::AwaitingHome => {
+                "Push the carriage to the home corner by hand, then confirm it's homed"
+            }
+            RecoveryStep::AwaitingPositionCheck => {
+                "Check the pen is over where the job stopped, jogging it if not, then finish"
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::axidraw_over_http::JobState;
+    use std::process;
+
+    fn command(contents: &str) -> Command {
+        Command {
+            contents: contents.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn layer(name: &str) -> Command {
+        Command {
+            layer: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn load_skips_markers_when_finding_where_to_carry_on() {
+        let data_dir = std::env::temp_dir().join(format!("axidraw-recovery-{}", process::id()));
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let job = Job {
+            id: 1,
+            name: "Drawing".to_string(),
+            state: JobState::Started,
+            start_at: None,
+            commands: vec![
+                layer("Outline"),
+                command("SM,100,200,0"),
+                layer("Fill"),
+                command("SM,100,0,300"),
+                command("SM,100,-200,0"),
+            ],
+            executed_count: 2,
+            start: None,
+        };
+        let checksum = save_job(&data_dir, &job).unwrap();
+
+        let recovery = Recovery::load(&data_dir, JobProgress::of(&job, checksum));
+        fs::remove_dir_all(&data_dir).unwrap();
+        let recovery = recovery.unwrap();
+
+        let mut expected = Simulator::default();
+        expected.apply("SM,100,200,0");
+        expected.apply("SM,100,0,300");
+
+        assert_eq!(recovery.index, 4);
+        assert_eq!(recovery.target().position(), expected.position());
+        assert_eq!(jobs::executed_before(&recovery.commands, recovery.index), 2);
+    }
+
+    #[test]
+    fn target_carries_on_from_where_the_job_started() {
+        let mut start = Simulator::default();
+        start.apply("SM,100,1000,400");
+
+        let recovery = Recovery {
+            progress: JobProgress {
+                id: 1,
+                name: "Drawing".to_string(),
+                executed_count: 1,
+                checksum: String::new(),
+                start: Some(start.clone()),
+            },
+            commands: vec![command("SM,100,200,0"), command("SM,100,0,300")],
+            index: 1,
+            step: RecoveryStep::NotStarted,
+        };
+
+        let mut expected = start;
+        expected.apply("SM,100,200,0");
+
+        assert_eq!(recovery.target(), expected);
+    }
+
+    #[test]
+    fn load_rejects_a_job_file_from_a_different_job() {
+        let data_dir =
+            std::env::temp_dir().join(format!("axidraw-recovery-other-{}", process::id()));
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let job = Job {
+            id: 1,
+            name: "Drawing".to_string(),
+            state: JobState::Started,
+            start_at: None,
+            commands: vec![command("SM,100,200,0")],
+            executed_count: 1,
+            start: None,
+        };
+        let checksum = save_job(&data_dir, &job).unwrap();
+        let other_job = JobProgress {
+            id: 2,
+            ..JobProgress::of(&job, checksum.clone())
+        };
+        let other_file = JobProgress::of(&job, "0".repeat(64));
+
+        let recovered_other_job = Recovery::load(&data_dir, other_job).is_some();
+        let recovered_other_file = Recovery::load(&data_dir, other_file).is_some();
+        let recovered = Recovery::load(&data_dir, JobProgress::of(&job, checksum)).is_some();
+        fs::remove_dir_all(&data_dir).unwrap();
+
+        assert!(!recovered_other_job);
+        assert!(!recovered_other_file);
+        assert!(recovered);
+    }
+}
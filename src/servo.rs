@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+/// Lowest and highest `SC,4` and `SC,5` positions of the stock pen-lift
+/// servo, in units of 83.3 ns.
+const STOCK_MIN_POSITION: i64 = 9855;
+const STOCK_MAX_POSITION: i64 = 27831;
+
+/// How the pen-lift servo is wired and driven, for servos other than the
+/// stock one. The server's own pen moves use it; commands from clients are
+/// sent as they are.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServoProfile {
+    /// Port B pin the servo is connected to, given to `SP`. The EBB's default
+    /// is used if unset.
+    pub pin: Option<u8>,
+    /// Pulse widths at 0% and 100%, in units of 83.3 ns.
+    pub min_position: i64,
+    pub max_position: i64,
+    /// Pen heights, from 0% to 100% of the range. Heights set by clients
+    /// with `SC,4` and `SC,5` are kept if unset.
+    pub pen_up_percent: Option<f64>,
+    pub pen_down_percent: Option<f64>,
+    /// How long the EBB waits after raising or lowering the pen before the
+    /// next move.
+    pub raise_delay_ms: Option<u32>,
+    pub lower_delay_ms: Option<u32>,
+}
+
+impl Default for ServoProfile {
+    fn default() -> Self {
+        ServoProfile {
+            pin: None,
+            min_position: STOCK_MIN_POSITION,
+            max_position: STOCK_MAX_POSITION,
+            pen_up_percent: None,
+            pen_down_percent: None,
+            raise_delay_ms: None,
+            lower_delay_ms: None,
+        }
+    }
+}
+
+impl ServoProfile {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pin.is_some_and(|pin| pin > 7) {
+            return Err("Servo pin must be from 0 to 7".to_string());
+        }
+
+        if !(1..self.max_position).contains(&self.min_position)
+            || self.max_position > u16::MAX as i64
+        {
+            return Err(format!(
+                "Servo positions must satisfy 1 <= min < max <= {}",
+                u16::MAX
+            ));
+        }
+
+        for percent in [self.pen_up_percent, self.pen_down_percent]
+            .into_iter()
+            .flatten()
+        {
+            if !(0.0..=100.0).contains(&percent) {
+                return Err("Pen heights must be from 0 to 100%".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the `SP` command raising or lowering the pen.
+    pub fn pen(&self, down: bool) -> String {
+        let state = if down { 0 } else { 1 };
+        let delay = if down {
+            self.lower_delay_ms
+        } else {
+            self.raise_delay_ms
+        };
+
+        match (delay, self.pin) {
+            (delay, Some(pin)) => format!("SP,{},{},{}", state, delay.unwrap_or(0), pin),
+            (Some(delay), None) => format!("SP,{},{}", state, delay),
+            (None, None) => format!("SP,{}", state),
+        }
+    }
+
+    /// `SC,4` and `SC,5` positions for the pen heights that are set.
+    pub fn positions(&self) -> (Option<i64>, Option<i64>) {
+        let position = |percent: f64| {
+            self.min_position
+                + ((self.max_position - self.min_position) as f64 * percent / 100.0).round() as i64
+        };
+
+        (
+            self.pen_up_percent.map(position),
+            self.pen_down_percent.map(position),
+        )
+    }
+
+    /// The EBB commands that set the pen heights.
+    pub fn commands(&self) -> Vec<String> {
+        let (up, down) = self.positions();
+
+        [
+            up.map(|position| format!("SC,4,{}", position)),
+            down.map(|position| format!("SC,5,{}", position)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
@@ -0,0 +1,59 @@
+use crate::events::Event;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    thread,
+    time::SystemTime,
+};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+/// One line of a recorded session file.
+#[derive(Serialize, Deserialize)]
+pub struct SessionEntry {
+    pub timestamp: SystemTime,
+    pub command: String,
+    pub response: Vec<String>,
+}
+
+/// Appends every executed command and its response to `path` as JSON lines.
+pub fn record(path: PathBuf, mut events: Receiver<Event>) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+    thread::spawn(move || loop {
+        let entry = match events.blocking_recv() {
+            Ok(Event::CommandExecuted {
+                command,
+                response,
+                timestamp,
+                ..
+            }) => SessionEntry {
+                timestamp,
+                command,
+                response,
+            },
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+
+        let line = serde_json::to_string(&entry).unwrap_or_default();
+
+        if let Err(error) = writeln!(file, "{}", line) {
+            println!("Failed to record session to {}: {}", path.display(), error);
+        }
+    });
+
+    Ok(())
+}
+
+pub fn parse(session: &str) -> Result<Vec<SessionEntry>, String> {
+    session
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|error| format!("Line {}: {}", index + 1, error))
+        })
+        .collect()
+}
@@ -0,0 +1,51 @@
+use crate::{logfile::LogFileHandle, AxidrawService};
+use std::sync::Arc;
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+pub async fn shutdown() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Could not handle SIGTERM");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap();
+}
+
+/// Reopens log files and reloads the config file on SIGHUP.
+#[cfg(unix)]
+pub fn spawn_hangup_handler(service: Arc<AxidrawService>, log_file: Option<LogFileHandle>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("Could not handle SIGHUP");
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            println!("Reopening log files");
+
+            if let Some(log_file) = &log_file {
+                log_file.reopen();
+            }
+
+            if let Err(error) = service.audit.lock().await.reopen() {
+                println!("Could not reopen audit log: {}", error);
+            }
+
+            if service.config.is_some() {
+                if let Err(status) = service.reload_config().await {
+                    println!("Could not reload config: {}", status.message());
+                }
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_hangup_handler(_service: Arc<AxidrawService>, _log_file: Option<LogFileHandle>) {}
@@ -0,0 +1,166 @@
+use crate::{
+    axidraw_over_http::{PlotCounters, SerialLatency, Statistics},
+    ebb::{self, Simulator},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    ops::Add,
+    time::{Duration, Instant},
+};
+
+/// Number of recent round trips percentiles are taken over.
+const LATENCY_SAMPLES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    /// In mm.
+    pub pen_down_distance: f64,
+    pub pen_lifts: u64,
+    pub motor_on_time: Duration,
+    pub commands_executed: u64,
+}
+
+impl Add for Counters {
+    type Output = Counters;
+
+    fn add(self, other: Counters) -> Counters {
+        Counters {
+            pen_down_distance: self.pen_down_distance + other.pen_down_distance,
+            pen_lifts: self.pen_lifts + other.pen_lifts,
+            motor_on_time: self.motor_on_time + other.motor_on_time,
+            commands_executed: self.commands_executed + other.commands_executed,
+        }
+    }
+}
+
+impl Counters {
+    fn to_proto(self) -> PlotCounters {
+        PlotCounters {
+            pen_down_distance: self.pen_down_distance,
+            pen_lifts: self.pen_lifts,
+            motor_on_time: Some(self.motor_on_time.try_into().unwrap_or_default()),
+            commands_executed: self.commands_executed,
+        }
+    }
+}
+
+/// Serial round-trip times, for spotting a failing connection.
+#[derive(Default)]
+struct Latency {
+    recent: VecDeque<Duration>,
+    max: Duration,
+    count: u64,
+}
+
+impl Latency {
+    fn record(&mut self, latency: Duration) {
+        if self.recent.len() == LATENCY_SAMPLES {
+            self.recent.pop_front();
+        }
+
+        self.recent.push_back(latency);
+        self.max = self.max.max(latency);
+        self.count += 1;
+    }
+
+    fn to_proto(&self) -> SerialLatency {
+        let mut sorted = self.recent.iter().copied().collect::<Vec<_>>();
+        sorted.sort();
+
+        let percentile = |percent: usize| {
+            let index = (sorted.len() * percent / 100).min(sorted.len().saturating_sub(1));
+            sorted
+                .get(index)
+                .map(|latency| (*latency).try_into().unwrap_or_default())
+        };
+
+        SerialLatency {
+            p50: percentile(50),
+            p95: percentile(95),
+            max: Some(self.max.try_into().unwrap_or_default()),
+            count: self.count,
+        }
+    }
+}
+
+/// Wear-related totals, counted from the commands the consumer executes.
+#[derive(Default)]
+pub struct PlotStatistics {
+    /// Totals from before this session.
+    previous: Counters,
+    session: Counters,
+    simulator: Simulator,
+    motors_on_since: Option<Instant>,
+    latency: Latency,
+}
+
+impl PlotStatistics {
+    pub fn new(previous: Counters) -> Self {
+        PlotStatistics {
+            previous,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, command: &str) {
+        let (name, arguments) = ebb::parse(command);
+
+        match name.as_str() {
+            "EM" => self.set_motors_on(arguments.iter().any(|&mode| mode != 0)),
+            "SM" | "XM" | "LM" | "L3" | "HM" => self.set_motors_on(true),
+            _ => {}
+        }
+
+        let was_pen_down = self.simulator.is_pen_down();
+        let movement = self.simulator.apply(command);
+
+        if let (Some((start, end)), true) = (movement, self.simulator.is_pen_down()) {
+            self.session.pen_down_distance += (end.0 - start.0).hypot(end.1 - start.1);
+        }
+
+        if was_pen_down && !self.simulator.is_pen_down() {
+            self.session.pen_lifts += 1;
+        }
+
+        self.session.commands_executed += 1;
+    }
+
+    pub fn record_latency(&mut self, latency: Duration) {
+        self.latency.record(latency);
+    }
+
+    pub fn session(&self) -> Counters {
+        let mut session = self.session;
+
+        if let Some(motors_on_since) = self.motors_on_since {
+            session.motor_on_time += motors_on_since.elapsed();
+        }
+
+        session
+    }
+
+    pub fn lifetime(&self) -> Counters {
+        self.previous + self.session()
+    }
+
+    pub fn to_proto(&self) -> Statistics {
+        Statistics {
+            session: Some(self.session().to_proto()),
+            lifetime: Some(self.lifetime().to_proto()),
+            latency: Some(self.latency.to_proto()),
+        }
+    }
+
+    fn set_motors_on(&mut self, on: bool) {
+        match (self.motors_on_since, on) {
+            (None, true) => self.motors_on_since = Some(Instant::now()),
+            (Some(motors_on_since), false) => {
+                self.session.motor_on_time += motors_on_since.elapsed();
+                self.motors_on_since = None;
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    device::{DeviceStatus, MachineSettings},
+    ebb::Point,
+    jobs::Jobs,
+    recovery::{self, JobProgress, Recovery},
+    statistics::{Counters, PlotStatistics},
+    workarea::WorkArea,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::Mutex;
+
+const STATE_FILE: &str = "state.json";
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Everything kept across restarts.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PersistentState {
+    /// Lifetime totals.
+    pub statistics: Counters,
+    /// Servo positions last set with `SC,4` and `SC,5`.
+    pub pen_up_position: Option<i64>,
+    pub pen_down_position: Option<i64>,
+    pub machine_size: Option<Point>,
+    /// Options set with ConfigureMachine.
+    pub machine_settings: MachineSettings,
+    /// The last work area set with SetWorkArea.
+    pub work_area: Option<WorkArea>,
+    /// The job that was running, or is waiting to be recovered.
+    pub job_progress: Option<JobProgress>,
+}
+
+impl PersistentState {
+    pub fn load(data_dir: &Path) -> PersistentState {
+        let path = data_dir.join(STATE_FILE);
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|error| {
+                println!("Ignoring unreadable {}: {}", path.display(), error);
+                PersistentState::default()
+            }),
+            Err(_) => PersistentState::default(),
+        }
+    }
+
+    pub fn save(&self, data_dir: &Path) -> io::Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        let temporary_path = data_dir.join(format!("{}.tmp", STATE_FILE));
+
+        // Replace the file atomically so a crash mid-write can't lose it.
+        fs::write(&temporary_path, contents)?;
+        fs::rename(temporary_path, data_dir.join(STATE_FILE))
+    }
+}
+
+/// Writes the persistent state to `--data-dir` whenever it changes.
+#[derive(Clone)]
+pub struct Store {
+    pub data_dir: PathBuf,
+    pub machine_size: Point,
+    pub statistics: Arc<Mutex<PlotStatistics>>,
+    pub device_status: Arc<Mutex<DeviceStatus>>,
+    pub work_area: Arc<Mutex<WorkArea>>,
+    pub jobs: Arc<Mutex<Jobs>>,
+    pub recovery: Arc<Mutex<Option<Recovery>>>,
+    /// ID and checksum of the job last written to the job file.
+    pub saved_job: Arc<Mutex<Option<(u64, String)>>>,
+}
+
+impl Store {
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAVE_INTERVAL);
+            let mut saved = PersistentState::load(&self.data_dir);
+
+            loop {
+                interval.tick().await;
+
+                self.write_job().await;
+                let state = self.snapshot().await;
+
+                if state != saved && self.write(&state) {
+                    saved = state;
+                }
+            }
+        });
+    }
+
+    pub async fn save(&self) {
+        self.write_job().await;
+        let state = self.snapshot().await;
+        self.write(&state);
+    }
+
+    /// Saves the commands of the started job, if there is one that hasn't been
+    /// saved yet.
+    async fn write_job(&self) {
+        let jobs = self.jobs.lock().await;
+        let Some(job) = jobs.current() else {
+            return;
+        };
+
+        let mut saved_job = self.saved_job.lock().await;
+        if saved_job.as_ref().is_some_and(|(id, _)| *id == job.id) {
+            return;
+        }
+
+        match recovery::save_job(&self.data_dir, job) {
+            Ok(checksum) => *saved_job = Some((job.id, checksum)),
+            Err(error) => println!("Failed to save job {}: {}", job.id, error),
+        }
+    }
+
+    async fn snapshot(&self) -> PersistentState {
+        let device_status = self.device_status.lock().await;
+        let saved_job = self.saved_job.lock().await.clone();
+        let current_job = self.jobs.lock().await.current().map(|job| {
+            let checksum = saved_job
+                .filter(|(id, _)| *id == job.id)
+                .map(|(_, checksum)| checksum)
+                .unwrap_or_default();
+
+            JobProgress::of(job, checksum)
+        });
+        let job_progress = match current_job {
+            Some(progress) => Some(progress),
+            None => self
+                .recovery
+                .lock()
+                .await
+                .as_ref()
+                .map(|recovery| recovery.progress.clone()),
+        };
+
+        PersistentState {
+            statistics: self.statistics.lock().await.lifetime(),
+            pen_up_position: device_status.pen_up_position,
+            pen_down_position: device_status.pen_down_position,
+            machine_size: Some(self.machine_size),
+            machine_settings: device_status.machine_settings.clone(),
+            work_area: Some(self.work_area.lock().await.clone()),
+            job_progress,
+        }
+    }
+
+    fn write(&self, state: &PersistentState) -> bool {
+        match state.save(&self.data_dir) {
+            Ok(()) => true,
+            Err(error) => {
+                println!(
+                    "Failed to save state to {}: {}",
+                    self.data_dir.display(),
+                    error
+                );
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Installs a tracing subscriber that exports spans over OTLP to `endpoint`.
+pub fn init(endpoint: String) {
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                "axidraw-over-http",
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+        .unwrap_or_else(|e| panic!("Could not create OTLP exporter {:?}", e));
+
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .init();
+}
+
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,71 @@
+use crate::{
+    axidraw_over_http::{Position, TeleopDelta},
+    AxidrawService, QueuedCommand,
+};
+use std::{collections::VecDeque, future::Future, pin::Pin};
+use tokio::sync::mpsc::Sender;
+use tokio_stream::StreamExt;
+use tonic::{Status, Streaming};
+
+type Move = Pin<Box<dyn Future<Output = Result<Position, Status>> + Send>>;
+
+/// Executes teleoperation deltas as they arrive. Deltas received while a move
+/// is in flight are merged, so a device that falls behind catches up in one
+/// move rather than replaying every stale delta. Pen changes are never merged
+/// across, so strokes keep their shape.
+pub async fn run(
+    service: AxidrawService,
+    mut deltas: Streaming<TeleopDelta>,
+    positions: Sender<Result<Position, Status>>,
+) {
+    let mut pending = VecDeque::<TeleopDelta>::new();
+    let mut in_flight: Option<Move> = None;
+    let mut input_closed = false;
+
+    loop {
+        if in_flight.is_none() {
+            if let Some(delta) = pending.pop_front() {
+                in_flight = Some(Box::pin(execute(service.clone(), delta)));
+            } else if input_closed {
+                break;
+            }
+        }
+
+        tokio::select! {
+            delta = deltas.next(), if !input_closed => match delta {
+                Some(Ok(delta)) => coalesce(&mut pending, delta),
+                Some(Err(_)) | None => input_closed = true,
+            },
+            result = async { in_flight.as_mut().unwrap().await }, if in_flight.is_some() => {
+                in_flight = None;
+
+                let failed = result.is_err();
+                if positions.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn coalesce(pending: &mut VecDeque<TeleopDelta>, delta: TeleopDelta) {
+    match pending.back_mut() {
+        Some(last) if delta.pen_down.is_none() || delta.pen_down == last.pen_down => {
+            last.dx += delta.dx;
+            last.dy += delta.dy;
+            last.speed = delta.speed.or(last.speed);
+        }
+        _ => pending.push_back(delta),
+    }
+}
+
+async fn execute(service: AxidrawService, delta: TeleopDelta) -> Result<Position, Status> {
+    if let Some(pen_down) = delta.pen_down {
+        let command = service.servo_profile.lock().await.pen(pen_down);
+        service.execute_now(QueuedCommand::new(command)).await?;
+    }
+
+    service.jog_by(delta.dx, delta.dy, delta.speed).await?;
+
+    Ok(service.position().await)
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+/// Returns the names of the `{name}` placeholders in `text`.
+pub fn placeholders(text: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start + 1..].find('}') else {
+            break;
+        };
+
+        names.push(&rest[start + 1..start + 1 + length]);
+        rest = &rest[start + length + 2..];
+    }
+
+    names
+}
+
+/// Replaces each `{name}` placeholder in `text` with its argument, in one
+/// pass so that braces in arguments are left as they are.
+pub fn substitute(text: &str, arguments: &HashMap<String, String>) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start + 1..].find('}') else {
+            break;
+        };
+
+        let name = &rest[start + 1..start + 1 + length];
+        let argument = arguments
+            .get(name)
+            .ok_or_else(|| format!("Missing argument {}", name))?;
+
+        result.push_str(&rest[..start]);
+        result.push_str(argument);
+        rest = &rest[start + length + 2..];
+    }
+
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Whether `name` can be used as a parameter.
+pub fn is_valid_parameter(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || character == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn fills_in_every_placeholder() {
+        let arguments = arguments(&[("x", "100"), ("y", "-50")]);
+
+        assert_eq!(
+            substitute("SM,500,{x},{y}", &arguments).unwrap(),
+            "SM,500,100,-50"
+        );
+        assert_eq!(
+            substitute("SM,500,{x},{x}", &arguments).unwrap(),
+            "SM,500,100,100"
+        );
+    }
+
+    #[test]
+    fn leaves_placeholders_in_arguments_alone() {
+        let arguments = arguments(&[("label", "{y}"), ("y", "10")]);
+
+        assert_eq!(
+            substitute("{label} at {y}", &arguments).unwrap(),
+            "{y} at 10"
+        );
+    }
+
+    #[test]
+    fn reports_missing_arguments() {
+        assert_eq!(
+            substitute("SM,500,{x},0", &HashMap::new()),
+            Err("Missing argument x".to_string())
+        );
+    }
+}
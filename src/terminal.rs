@@ -0,0 +1,35 @@
+use crate::{
+    axidraw_over_http::{TerminalInput, TerminalOutput},
+    AxidrawService, QueuedCommand,
+};
+use tokio::sync::mpsc::Sender;
+use tokio_stream::StreamExt;
+use tonic::{Status, Streaming};
+
+/// Executes console lines one at a time through the urgent lane, replying with
+/// each response, then hands the EBB back to the buffer.
+pub async fn run(
+    service: AxidrawService,
+    mut lines: Streaming<TerminalInput>,
+    output: Sender<Result<TerminalOutput, Status>>,
+) {
+    while let Some(Ok(input)) = lines.next().await {
+        let line = input.line.trim().to_string();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let result = service
+            .execute_now(QueuedCommand::new(line.clone()))
+            .await
+            .map(|response| TerminalOutput { line, response });
+
+        let failed = result.is_err();
+        if output.send(result).await.is_err() || failed {
+            break;
+        }
+    }
+
+    service.close_terminal().await;
+}
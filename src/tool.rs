@@ -0,0 +1,14 @@
+use crate::{axidraw_over_http::Tool, servo::ServoProfile};
+
+/// Highest power `SE` accepts.
+pub const MAX_POWER: u32 = 1023;
+
+/// Returns the EBB command switching `tool` on or off. Engraver commands go
+/// through the motion FIFO, so they happen between the moves either side.
+pub fn command(tool: Tool, on: bool, power: Option<u32>, servo_profile: &ServoProfile) -> String {
+    match tool {
+        Tool::Pen => servo_profile.pen(on),
+        Tool::Engraver if on => format!("SE,1,{},1", power.unwrap_or(MAX_POWER)),
+        Tool::Engraver => "SE,0,0,1".to_string(),
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{
+    axidraw_over_http::ToolInfo,
+    ebb::{Point, Simulator},
+    servo::ServoProfile,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A pen that plots can switch to with a tool change.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolDefinition {
+    /// Pen heights, as a percentage of the servo profile's range. The
+    /// current heights are kept if unset.
+    pub pen_up_percent: Option<f64>,
+    pub pen_down_percent: Option<f64>,
+    /// Fastest the pen may move while down, in mm/s. Faster `SM` and `XM`
+    /// moves are slowed down to it.
+    pub max_speed: Option<f64>,
+    /// Where the tip is relative to the carriage's tracked position, in mm.
+    pub offset: Point,
+}
+
+impl ToolDefinition {
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .max_speed
+            .is_some_and(|speed| speed.is_nan() || speed <= 0.0)
+        {
+            return Err("Tool speed limits must be positive".to_string());
+        }
+
+        ServoProfile {
+            pen_up_percent: self.pen_up_percent,
+            pen_down_percent: self.pen_down_percent,
+            ..ServoProfile::default()
+        }
+        .validate()
+    }
+
+    pub fn to_proto(&self, name: &str) -> ToolInfo {
+        ToolInfo {
+            name: name.to_string(),
+            pen_up_percent: self.pen_up_percent,
+            pen_down_percent: self.pen_down_percent,
+            max_speed: self.max_speed,
+            offset_x: self.offset.0,
+            offset_y: self.offset.1,
+        }
+    }
+}
+
+/// A tool change waiting for the operator to fit the new tool.
+#[derive(Clone, Debug)]
+pub struct PendingChange {
+    pub tool: String,
+    /// Where the carriage was, and whether the pen was down, before parking.
+    pub return_to: Point,
+    pub pen_down: bool,
+    /// Whether plotting carries on once the change is confirmed.
+    pub resume: bool,
+}
+
+#[derive(Default)]
+pub struct ToolChanges {
+    pub tools: BTreeMap<String, ToolDefinition>,
+    /// Where the carriage waits while the tool is changed, in mm from home.
+    pub change_position: Point,
+    pub active: Option<String>,
+    pub pending: Option<PendingChange>,
+}
+
+impl ToolChanges {
+    pub fn active_tool(&self) -> Option<&ToolDefinition> {
+        self.tools.get(self.active.as_ref()?)
+    }
+
+    /// Checks that a change to `tool` can start.
+    pub fn check(&self, tool: &str) -> Result<(), String> {
+        if self.pending.is_some() {
+            return Err("A tool change is already waiting to be confirmed".to_string());
+        }
+
+        if !self.tools.contains_key(tool) {
+            return Err(format!("No tool named {}", tool));
+        }
+
+        Ok(())
+    }
+
+    /// Starts a change to `tool`, returning the commands that raise the pen
+    /// and park the carriage from where `simulator` is.
+    pub fn start(
+        &mut self,
+        tool: &str,
+        simulator: &Simulator,
+        servo_profile: &ServoProfile,
+        speed: f64,
+        resume: bool,
+    ) -> Result<Vec<String>, String> {
+        self.check(tool)?;
+
+        self.pending = Some(PendingChange {
+            tool: tool.to_string(),
+            return_to: simulator.position(),
+            pen_down: simulator.is_pen_down(),
+            resume,
+        });
+
+        let mut commands = vec![servo_profile.pen(false)];
+        commands.extend(simulator.move_to(self.change_position, speed));
+
+        Ok(commands)
+    }
+
+    /// Finishes the pending change, returning it along with the commands that
+    /// set the new tool's pen heights and take it back to where plotting
+    /// stopped, allowing for the difference in offsets.
+    pub fn finish(
+        &mut self,
+        simulator: &Simulator,
+        servo_profile: &ServoProfile,
+        speed: f64,
+    ) -> Option<(PendingChange, Vec<String>)> {
+        let pending = self.pending.take()?;
+        let tool = self.tools.get(&pending.tool).cloned().unwrap_or_default();
+        let previous_offset = self.active_tool().map_or((0.0, 0.0), |tool| tool.offset);
+
+        let mut commands = ServoProfile {
+            pen_up_percent: tool.pen_up_percent,
+            pen_down_percent: tool.pen_down_percent,
+            ..servo_profile.clone()
+        }
+        .commands();
+
+        let target = (
+            pending.return_to.0 + previous_offset.0 - tool.offset.0,
+            pending.return_to.1 + previous_offset.1 - tool.offset.1,
+        );
+        commands.extend(simulator.move_to(target, speed));
+
+        if pending.pen_down {
+            commands.push(servo_profile.pen(true));
+        }
+
+        self.active = Some(pending.tool.clone());
+
+        Some((pending, commands))
+    }
+}
@@ -0,0 +1,557 @@
+use crate::axidraw_over_http::{Command, FileChunk, UploadProgress};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    future::Future,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc::Sender,
+};
+use tokio_stream::StreamExt;
+use tonic::{Status, Streaming};
+
+/// Details of an upload from its first chunk, saved next to the partial file
+/// so the upload can be continued after a dropped connection or a restart.
+#[derive(Serialize, Deserialize)]
+struct UploadInfo {
+    name: String,
+    total_size: u64,
+    sha256: String,
+    /// Where the file was saved once complete.
+    path: Option<PathBuf>,
+    command_count: u64,
+    /// The held job made from the saved file.
+    job_id: Option<u64>,
+}
+
+/// Command files uploaded in chunks, kept in a directory until they're
+/// queued with LoadFile.
+pub struct Uploads {
+    dir: PathBuf,
+    /// Uploads with a stream open, so the same one can't be written twice at
+    /// once.
+    active: Mutex<HashSet<String>>,
+}
+
+impl Uploads {
+    pub fn new(dir: PathBuf) -> Uploads {
+        Uploads {
+            dir,
+            active: Mutex::default(),
+        }
+    }
+
+    /// Progress of an upload, for continuing it from where it stopped.
+    pub async fn progress(&self, upload_id: &str) -> Result<UploadProgress, Status> {
+        check_id(upload_id)?;
+
+        let info = self
+            .read_info(upload_id)
+            .await?
+            .ok_or_else(|| Status::not_found(format!("No upload {}", upload_id)))?;
+
+        self.progress_of(upload_id, &info).await
+    }
+
+    async fn progress_of(
+        &self,
+        upload_id: &str,
+        info: &UploadInfo,
+    ) -> Result<UploadProgress, Status> {
+        let received = match &info.path {
+            Some(_) => info.total_size,
+            None => file_size(&self.part_path(upload_id)).await?,
+        };
+
+        Ok(UploadProgress {
+            upload_id: upload_id.to_string(),
+            received,
+            total_size: info.total_size,
+            complete: info.path.is_some(),
+            path: info
+                .path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            command_count: info.command_count,
+            job_id: info.job_id,
+        })
+    }
+
+    /// Writes chunks as they arrive, acknowledging each with the progress so
+    /// far, until the upload is complete or the stream ends. A complete
+    /// upload is passed to `hold`, which returns the ID of the job made from
+    /// it.
+    pub async fn receive<F, Fut>(
+        self: Arc<Self>,
+        mut chunks: Streaming<FileChunk>,
+        progress: Sender<Result<UploadProgress, Status>>,
+        hold: F,
+    ) where
+        F: Fn(PathBuf) -> Fut,
+        Fut: Future<Output = Result<u64, Status>>,
+    {
+        let mut upload_id = None;
+
+        while let Some(Ok(chunk)) = chunks.next().await {
+            if upload_id.as_ref() != Some(&chunk.upload_id) {
+                if let Some(previous) = upload_id.take() {
+                    self.active.lock().unwrap().remove(&previous);
+                }
+
+                if !self.active.lock().unwrap().insert(chunk.upload_id.clone()) {
+                    let _ = progress
+                        .send(Err(Status::aborted(format!(
+                            "Upload {} is already in progress",
+                            chunk.upload_id
+                        ))))
+                        .await;
+                    return;
+                }
+
+                upload_id = Some(chunk.upload_id.clone());
+            }
+
+            let result = match self.write_chunk(chunk).await {
+                Ok(upload) if upload.complete && upload.job_id.is_none() => {
+                    self.hold(upload, &hold).await
+                }
+                result => result,
+            };
+            let failed = result.is_err();
+
+            if progress.send(result).await.is_err() || failed {
+                break;
+            }
+        }
+
+        if let Some(upload_id) = upload_id {
+            self.active.lock().unwrap().remove(&upload_id);
+        }
+    }
+
+    /// Makes the held job for a complete upload, recording its ID so that
+    /// it's only made once.
+    async fn hold<F, Fut>(
+        &self,
+        mut upload: UploadProgress,
+        hold: &F,
+    ) -> Result<UploadProgress, Status>
+    where
+        F: Fn(PathBuf) -> Fut,
+        Fut: Future<Output = Result<u64, Status>>,
+    {
+        let mut info = self
+            .read_info(&upload.upload_id)
+            .await?
+            .ok_or_else(|| Status::not_found(format!("No upload {}", upload.upload_id)))?;
+        let path = info
+            .path
+            .clone()
+            .ok_or_else(|| Status::failed_precondition("Upload isn't complete"))?;
+
+        let job_id = hold(path).await?;
+        info.job_id = Some(job_id);
+        self.write_info(&upload.upload_id, &info).await?;
+
+        upload.job_id = Some(job_id);
+        Ok(upload)
+    }
+
+    async fn write_chunk(&self, chunk: FileChunk) -> Result<UploadProgress, Status> {
+        check_id(&chunk.upload_id)?;
+
+        let info = match self.read_info(&chunk.upload_id).await? {
+            Some(info) => info,
+            None if chunk.offset == 0 => self.start(&chunk).await?,
+            None => return Err(Status::not_found(format!("No upload {}", chunk.upload_id))),
+        };
+
+        if info.path.is_some() {
+            return self.progress_of(&chunk.upload_id, &info).await;
+        }
+
+        let part_path = self.part_path(&chunk.upload_id);
+        let received = file_size(&part_path).await?;
+
+        if chunk.offset != received {
+            return Err(Status::out_of_range(format!(
+                "Expected offset {}, got {}",
+                received, chunk.offset
+            )));
+        }
+
+        if received + chunk.data.len() as u64 > info.total_size {
+            return Err(Status::out_of_range(format!(
+                "Upload is longer than its {} bytes",
+                info.total_size
+            )));
+        }
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&part_path)
+            .await
+            .map_err(internal)?;
+        file.write_all(&chunk.data).await.map_err(internal)?;
+        file.sync_data().await.map_err(internal)?;
+
+        if received + chunk.data.len() as u64 == info.total_size {
+            let info = self.finish(&chunk.upload_id, info).await?;
+            return self.progress_of(&chunk.upload_id, &info).await;
+        }
+
+        self.progress_of(&chunk.upload_id, &info).await
+    }
+
+    /// Begins an upload from its first chunk.
+    async fn start(&self, chunk: &FileChunk) -> Result<UploadInfo, Status> {
+        let name = chunk.name.trim();
+
+        if name.is_empty()
+            || name.starts_with('.')
+            || name.contains(['/', '\\'])
+            || name.contains(std::path::MAIN_SEPARATOR)
+        {
+            return Err(Status::invalid_argument("Invalid file name"));
+        }
+
+        if chunk.total_size == 0 {
+            return Err(Status::invalid_argument("Uploads must not be empty"));
+        }
+
+        if fs::metadata(self.dir.join(name)).await.is_ok() {
+            return Err(already_exists(name));
+        }
+
+        if chunk.sha256.len() != 64 || !chunk.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Status::invalid_argument(
+                "Expected a hex SHA-256 of the whole file",
+            ));
+        }
+
+        let info = UploadInfo {
+            name: name.to_string(),
+            total_size: chunk.total_size,
+            sha256: chunk.sha256.to_ascii_lowercase(),
+            path: None,
+            command_count: 0,
+            job_id: None,
+        };
+
+        fs::create_dir_all(&self.dir).await.map_err(internal)?;
+        File::create(self.part_path(&chunk.upload_id))
+            .await
+            .map_err(internal)?;
+        self.write_info(&chunk.upload_id, &info).await?;
+
+        Ok(info)
+    }
+
+    /// Checks a complete upload against its checksum and moves it into place.
+    async fn finish(&self, upload_id: &str, mut info: UploadInfo) -> Result<UploadInfo, Status> {
+        let part_path = self.part_path(upload_id);
+
+        let checksum = sha256(&part_path).await.map_err(internal)?;
+        if checksum != info.sha256 {
+            // Start again rather than keep a corrupt file.
+            File::create(&part_path).await.map_err(internal)?;
+
+            return Err(Status::data_loss(format!(
+                "Checksum mismatch: expected {}, got {}",
+                info.sha256, checksum
+            )));
+        }
+
+        let command_count = match count_commands(&part_path).await {
+            Ok(command_count) => command_count,
+            Err(error) if error.kind() == ErrorKind::InvalidData => {
+                let _ = fs::remove_file(&part_path).await;
+                let _ = fs::remove_file(self.info_path(upload_id)).await;
+
+                return Err(Status::invalid_argument("Command files must be UTF-8 text"));
+            }
+            Err(error) => return Err(internal(error)),
+        };
+
+        // Linking fails rather than replacing a file with the same name that
+        // was saved since the upload started.
+        let path = self.dir.join(&info.name);
+        if let Err(error) = fs::hard_link(&part_path, &path).await {
+            if error.kind() != ErrorKind::AlreadyExists {
+                return Err(internal(error));
+            }
+
+            let _ = fs::remove_file(&part_path).await;
+            let _ = fs::remove_file(self.info_path(upload_id)).await;
+
+            return Err(already_exists(&info.name));
+        }
+        fs::remove_file(&part_path).await.map_err(internal)?;
+
+        info.command_count = command_count;
+        info.path = Some(fs::canonicalize(&path).await.unwrap_or(path));
+        self.write_info(upload_id, &info).await?;
+
+        println!(
+            "Received upload {} as {} ({} commands)",
+            upload_id, info.name, info.command_count
+        );
+
+        Ok(info)
+    }
+
+    fn part_path(&self, upload_id: &str) -> PathBuf {
+        self.dir.join(format!(".{}.part", upload_id))
+    }
+
+    fn info_path(&self, upload_id: &str) -> PathBuf {
+        self.dir.join(format!(".{}.json", upload_id))
+    }
+
+    async fn read_info(&self, upload_id: &str) -> Result<Option<UploadInfo>, Status> {
+        match fs::read_to_string(self.info_path(upload_id)).await {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|error| Status::internal(error.to_string())),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(internal(error)),
+        }
+    }
+
+    async fn write_info(&self, upload_id: &str, info: &UploadInfo) -> Result<(), Status> {
+        let contents =
+            serde_json::to_string(info).map_err(|error| Status::internal(error.to_string()))?;
+
+        fs::write(self.info_path(upload_id), contents)
+            .await
+            .map_err(internal)
+    }
+}
+
+fn check_id(upload_id: &str) -> Result<(), Status> {
+    let valid = !upload_id.is_empty()
+        && upload_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Status::invalid_argument(
+            "Upload IDs must be letters, digits, '-' and '_'",
+        ))
+    }
+}
+
+async fn file_size(path: &Path) -> Result<u64, Status> {
+    fs::metadata(path)
+        .await
+        .map(|metadata| metadata.len())
+        .map_err(internal)
+}
+
+/// Counts the commands in a file, a line at a time so that large files
+/// aren't read into memory. Fails with `InvalidData` if it isn't UTF-8.
+async fn count_commands(path: &Path) -> io::Result<u64> {
+    let mut lines = BufReader::new(File::open(path).await?).lines();
+    let mut count = 0;
+
+    while let Some(line) = lines.next_line().await? {
+        if !line.trim().is_empty() {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Reads the commands from a saved upload, one per non-empty line.
+pub async fn read_commands(path: &Path) -> io::Result<Vec<Command>> {
+    let mut lines = BufReader::new(File::open(path).await?).lines();
+    let mut commands = Vec::new();
+
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+
+        if !line.is_empty() {
+            commands.push(Command {
+                contents: line.to_string(),
+                ..Default::default()
+            });
+        }
+    }
+
+    Ok(commands)
+}
+
+pub async fn sha256(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let length = file.read(&mut buffer).await?;
+        if length == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..length]);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn already_exists(name: &str) -> Status {
+    Status::already_exists(format!("There's already a file called {}", name))
+}
+
+fn internal(error: io::Error) -> Status {
+    Status::internal(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    const CONTENTS: &[u8] = b"SP,0\nSM,100,200,0\n\nSP,1\n";
+
+    fn uploads(test: &str) -> Uploads {
+        let dir = std::env::temp_dir().join(format!("axidraw-upload-{}-{}", test, process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        Uploads::new(dir)
+    }
+
+    fn chunk(name: &str, contents: &[u8], offset: usize, length: usize) -> FileChunk {
+        FileChunk {
+            upload_id: "test".to_string(),
+            name: name.to_string(),
+            total_size: contents.len() as u64,
+            sha256: Sha256::digest(contents)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            offset: offset as u64,
+            data: contents[offset..offset + length].to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_must_carry_on_from_what_was_received() {
+        let uploads = uploads("offset");
+
+        let progress = uploads
+            .write_chunk(chunk("drawing.txt", CONTENTS, 0, 10))
+            .await
+            .unwrap();
+        assert_eq!(progress.received, 10);
+        assert!(!progress.complete);
+
+        let error = uploads
+            .write_chunk(chunk("drawing.txt", CONTENTS, 5, 5))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), tonic::Code::OutOfRange);
+
+        let progress = uploads
+            .write_chunk(chunk("drawing.txt", CONTENTS, 10, CONTENTS.len() - 10))
+            .await
+            .unwrap();
+        let saved = std::fs::read(uploads.dir.join("drawing.txt"));
+        std::fs::remove_dir_all(&uploads.dir).unwrap();
+
+        assert!(progress.complete);
+        assert_eq!(progress.received, CONTENTS.len() as u64);
+        assert_eq!(progress.command_count, 3);
+        assert_eq!(saved.unwrap(), CONTENTS);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_starts_the_upload_again() {
+        let uploads = uploads("checksum");
+
+        let mut first = chunk("drawing.txt", CONTENTS, 0, CONTENTS.len());
+        first.sha256 = "0".repeat(64);
+
+        let error = uploads.write_chunk(first).await.unwrap_err();
+        let progress = uploads.progress("test").await;
+        let saved = uploads.dir.join("drawing.txt").exists();
+        std::fs::remove_dir_all(&uploads.dir).unwrap();
+
+        assert_eq!(error.code(), tonic::Code::DataLoss);
+        assert_eq!(progress.unwrap().received, 0);
+        assert!(!saved);
+    }
+
+    #[tokio::test]
+    async fn files_that_are_not_utf8_are_rejected() {
+        let uploads = uploads("utf8");
+        let contents = b"SP,0\n\xff\xfe\n";
+
+        let error = uploads
+            .write_chunk(chunk("drawing.txt", contents, 0, contents.len()))
+            .await;
+        let progress = uploads.progress("test").await;
+        std::fs::remove_dir_all(&uploads.dir).unwrap();
+
+        assert_eq!(error.unwrap_err().code(), tonic::Code::InvalidArgument);
+        assert_eq!(progress.unwrap_err().code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn saved_files_are_read_a_command_per_line() {
+        let uploads = uploads("commands");
+
+        uploads
+            .write_chunk(chunk("drawing.txt", CONTENTS, 0, CONTENTS.len()))
+            .await
+            .unwrap();
+        let commands = read_commands(&uploads.dir.join("drawing.txt")).await;
+        std::fs::remove_dir_all(&uploads.dir).unwrap();
+
+        let commands = commands.unwrap();
+        assert_eq!(
+            commands
+                .iter()
+                .map(|command| command.contents.as_str())
+                .collect::<Vec<_>>(),
+            ["SP,0", "SM,100,200,0", "SP,1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_uploads_are_rejected() {
+        let uploads = uploads("empty");
+
+        let error = uploads.write_chunk(chunk("drawing.txt", b"", 0, 0)).await;
+
+        assert_eq!(error.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn existing_files_are_not_replaced() {
+        let uploads = uploads("existing");
+        std::fs::create_dir_all(&uploads.dir).unwrap();
+        std::fs::write(uploads.dir.join("drawing.txt"), "SP,1\n").unwrap();
+
+        let error = uploads
+            .write_chunk(chunk("drawing.txt", CONTENTS, 0, CONTENTS.len()))
+            .await;
+        let saved = std::fs::read_to_string(uploads.dir.join("drawing.txt"));
+        std::fs::remove_dir_all(&uploads.dir).unwrap();
+
+        assert_eq!(error.unwrap_err().code(), tonic::Code::AlreadyExists);
+        assert_eq!(saved.unwrap(), "SP,1\n");
+    }
+}
@@ -0,0 +1,108 @@
+use crate::ebb::{self, TICKS_PER_SECOND};
+
+/// Longest duration `SM` and `XM` accept, in milliseconds.
+const MAX_DURATION: i64 = 16_777_215;
+
+/// Limits that moves are checked against before they're queued.
+pub struct MotionLimits {
+    /// Fastest either motor may step, in steps per second.
+    pub max_step_rate: f64,
+    /// Largest `LM` acceleration, in steps per second squared.
+    pub max_acceleration: Option<f64>,
+}
+
+impl MotionLimits {
+    /// Checks an `SM`, `XM` or `LM` move, describing what's wrong with it.
+    pub fn check(&self, command: &str) -> Result<(), String> {
+        let (name, arguments) = ebb::parse(command);
+
+        match name.as_str() {
+            "SM" | "XM" => {
+                let Some((duration, steps1, steps2)) = ebb::motor_move(command) else {
+                    return Ok(());
+                };
+
+                if duration <= 0 {
+                    return Err(format!("{}: duration must be positive", command));
+                }
+
+                if duration > MAX_DURATION {
+                    return Err(format!(
+                        "{}: duration must be at most {} ms",
+                        command, MAX_DURATION
+                    ));
+                }
+
+                for (motor, steps) in [(1, steps1), (2, steps2)] {
+                    self.check_rate(
+                        command,
+                        motor,
+                        steps.abs() as f64 * 1000.0 / duration as f64,
+                    )?;
+                }
+            }
+            "LM" if arguments.len() >= 6 => {
+                for (motor, axis) in [(1, &arguments[0..3]), (2, &arguments[3..6])] {
+                    self.check_low_level_axis(command, motor, axis[0], axis[1], axis[2])?;
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn check_rate(&self, command: &str, motor: u8, steps_per_second: f64) -> Result<(), String> {
+        if steps_per_second > self.max_step_rate {
+            return Err(format!(
+                "{}: motor {} would step at {:.0} steps/s, above the maximum of {:.0}",
+                command, motor, steps_per_second, self.max_step_rate
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks one axis of an `LM`, whose rate is added to a 31-bit accumulator
+    /// every tick and acceleration to the rate.
+    fn check_low_level_axis(
+        &self,
+        command: &str,
+        motor: u8,
+        rate: i64,
+        steps: i64,
+        acceleration: i64,
+    ) -> Result<(), String> {
+        if steps == 0 {
+            return Ok(());
+        }
+
+        let to_steps = TICKS_PER_SECOND / 2f64.powi(31);
+        let (rate, acceleration) = (rate as f64, acceleration as f64);
+        let final_rate_squared =
+            rate * rate + 2.0 * acceleration * steps.unsigned_abs() as f64 * 2f64.powi(31);
+
+        if rate <= 0.0 && acceleration <= 0.0 || final_rate_squared <= 0.0 {
+            return Err(format!(
+                "{}: motor {} would stop before finishing its {} steps",
+                command, motor, steps
+            ));
+        }
+
+        self.check_rate(command, motor, rate * to_steps)?;
+        self.check_rate(command, motor, final_rate_squared.sqrt() * to_steps)?;
+
+        let steps_per_second_squared = acceleration.abs() * TICKS_PER_SECOND * to_steps;
+
+        if let Some(max_acceleration) = self.max_acceleration {
+            if steps_per_second_squared > max_acceleration {
+                return Err(format!(
+                    "{}: motor {} would accelerate at {:.0} steps/s², above the maximum of {:.0}",
+                    command, motor, steps_per_second_squared, max_acceleration
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
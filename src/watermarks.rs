@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::watch::{self, Receiver, Sender};
+
+/// Tracks whether producers should throttle: set when the buffer reaches the
+/// high watermark and cleared once it drains to the low watermark.
+pub struct Watermarks {
+    high: AtomicUsize,
+    low: AtomicUsize,
+    throttle: Sender<bool>,
+    /// Latest buffer length, for producers blocked on a full buffer.
+    length: Sender<usize>,
+}
+
+impl Watermarks {
+    pub fn new(high: Option<usize>, low: Option<usize>) -> Self {
+        let (high, low) = levels(high, low);
+
+        Watermarks {
+            high: AtomicUsize::new(high),
+            low: AtomicUsize::new(low),
+            throttle: watch::channel(false).0,
+            length: watch::channel(0).0,
+        }
+    }
+
+    /// Changes the watermarks, updating the throttle for the current length.
+    pub fn set_levels(&self, high: Option<usize>, low: Option<usize>) {
+        let (high, low) = levels(high, low);
+
+        self.high.store(high, Ordering::SeqCst);
+        self.low.store(low, Ordering::SeqCst);
+        self.update(*self.length.borrow());
+    }
+
+    pub fn update(&self, buffer_length: usize) {
+        self.length
+            .send_if_modified(|length| std::mem::replace(length, buffer_length) != buffer_length);
+
+        self.throttle.send_if_modified(|throttle| {
+            let updated = if buffer_length >= self.high.load(Ordering::SeqCst) {
+                true
+            } else if buffer_length <= self.low.load(Ordering::SeqCst) {
+                false
+            } else {
+                *throttle
+            };
+
+            std::mem::replace(throttle, updated) != updated
+        });
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        *self.throttle.borrow()
+    }
+
+    pub fn subscribe(&self) -> Receiver<bool> {
+        self.throttle.subscribe()
+    }
+
+    pub fn subscribe_length(&self) -> Receiver<usize> {
+        self.length.subscribe()
+    }
+}
+
+fn levels(high: Option<usize>, low: Option<usize>) -> (usize, usize) {
+    let high = high.unwrap_or(usize::MAX);
+
+    (high, low.unwrap_or(high / 2).min(high))
+}
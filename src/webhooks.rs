@@ -0,0 +1,49 @@
+use crate::events::Event;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    Mutex,
+};
+
+/// Longest to wait for a webhook to respond.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts events to each of `urls`, which can be changed while running. Each
+/// delivery runs on its own, so a slow endpoint doesn't hold up the others.
+pub fn spawn(urls: Arc<Mutex<Vec<String>>>, mut events: Receiver<Event>) {
+    let client = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+        Ok(client) => client,
+        Err(error) => {
+            println!("Could not create webhook client: {}", error);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(Event::CommandExecuted { .. }) => continue,
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            let urls = urls.lock().await.clone();
+
+            for url in urls {
+                let request = client.post(&url).json(&event);
+
+                tokio::spawn(async move {
+                    let result = request
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status());
+
+                    if let Err(error) = result {
+                        println!("Webhook to {} failed: {}", url, error);
+                    }
+                });
+            }
+        }
+    });
+}
@@ -0,0 +1,409 @@
+use crate::{
+    axidraw_over_http::{self, OriginCorner, PaperOrientation},
+    ebb::Point,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Where work area coordinates are measured from, inside the margin.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    #[default]
+    Landscape,
+    Portrait,
+}
+
+impl From<OriginCorner> for Origin {
+    fn from(corner: OriginCorner) -> Self {
+        match corner {
+            OriginCorner::TopLeft => Origin::TopLeft,
+            OriginCorner::TopRight => Origin::TopRight,
+            OriginCorner::BottomLeft => Origin::BottomLeft,
+            OriginCorner::BottomRight => Origin::BottomRight,
+        }
+    }
+}
+
+impl From<Origin> for OriginCorner {
+    fn from(origin: Origin) -> Self {
+        match origin {
+            Origin::TopLeft => OriginCorner::TopLeft,
+            Origin::TopRight => OriginCorner::TopRight,
+            Origin::BottomLeft => OriginCorner::BottomLeft,
+            Origin::BottomRight => OriginCorner::BottomRight,
+        }
+    }
+}
+
+impl From<PaperOrientation> for Orientation {
+    fn from(orientation: PaperOrientation) -> Self {
+        match orientation {
+            PaperOrientation::Landscape => Orientation::Landscape,
+            PaperOrientation::Portrait => Orientation::Portrait,
+        }
+    }
+}
+
+impl From<Orientation> for PaperOrientation {
+    fn from(orientation: Orientation) -> Self {
+        match orientation {
+            Orientation::Landscape => PaperOrientation::Landscape,
+            Orientation::Portrait => PaperOrientation::Portrait,
+        }
+    }
+}
+
+/// A sheet placed against the machine's home corner, which positions are
+/// given relative to. Sizes are in mm.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkArea {
+    #[serde(default)]
+    pub name: String,
+    pub width: f64,
+    pub height: f64,
+    #[serde(default)]
+    pub orientation: Orientation,
+    #[serde(default)]
+    pub origin: Origin,
+    #[serde(default)]
+    pub margin: f64,
+}
+
+/// Slack for positions rounded to whole motor steps, in mm.
+const POSITION_TOLERANCE: f64 = 0.05;
+
+/// Built-in presets, in portrait dimensions.
+const PAPER_SIZES: &[(&str, f64, f64)] = &[
+    ("a5", 148.0, 210.0),
+    ("a4", 210.0, 297.0),
+    ("a3", 297.0, 420.0),
+    ("letter", 215.9, 279.4),
+    ("legal", 215.9, 355.6),
+    ("tabloid", 279.4, 431.8),
+];
+
+/// The built-in presets, followed by `custom` ones, which replace built-ins
+/// of the same name.
+pub fn presets(custom: &BTreeMap<String, WorkArea>) -> BTreeMap<String, WorkArea> {
+    let mut presets = PAPER_SIZES
+        .iter()
+        .map(|&(name, width, height)| {
+            let area = WorkArea {
+                name: name.to_string(),
+                width,
+                height,
+                orientation: Orientation::Portrait,
+                origin: Origin::TopLeft,
+                margin: 0.0,
+            };
+
+            (name.to_string(), area)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    for (name, area) in custom {
+        presets.insert(
+            name.clone(),
+            WorkArea {
+                name: name.clone(),
+                ..area.clone()
+            },
+        );
+    }
+
+    presets
+}
+
+impl WorkArea {
+    /// The machine's whole travel, as used when no work area is set.
+    pub fn machine(size: Point) -> WorkArea {
+        WorkArea {
+            name: "machine".to_string(),
+            width: size.0,
+            height: size.1,
+            orientation: if size.0 >= size.1 {
+                Orientation::Landscape
+            } else {
+                Orientation::Portrait
+            },
+            origin: Origin::TopLeft,
+            margin: 0.0,
+        }
+    }
+
+    pub fn from_proto(area: axidraw_over_http::WorkArea) -> WorkArea {
+        WorkArea {
+            orientation: area.orientation().into(),
+            origin: area.origin().into(),
+            name: area.name,
+            width: area.width,
+            height: area.height,
+            margin: area.margin,
+        }
+    }
+
+    pub fn to_proto(&self, machine_size: Point) -> axidraw_over_http::WorkArea {
+        let (min, max) = self.drawable(machine_size);
+
+        axidraw_over_http::WorkArea {
+            name: self.name.clone(),
+            width: self.width,
+            height: self.height,
+            orientation: PaperOrientation::from(self.orientation) as i32,
+            origin: OriginCorner::from(self.origin) as i32,
+            margin: self.margin,
+            drawable_width: max.0 - min.0,
+            drawable_height: max.1 - min.1,
+        }
+    }
+
+    pub fn validate(&self, machine_size: Point) -> Result<(), String> {
+        if !(self.width > 0.0 && self.height > 0.0) {
+            return Err("Work area width and height must be positive".to_string());
+        }
+
+        if self.margin.is_nan() || self.margin < 0.0 {
+            return Err("Work area margin can't be negative".to_string());
+        }
+
+        let (min, max) = self.drawable(machine_size);
+
+        if min.0 >= max.0 || min.1 >= max.1 {
+            return Err("Work area margin leaves no room to draw".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Width and height along the machine's x and y axes.
+    pub fn size(&self) -> Point {
+        let (short, long) = (self.width.min(self.height), self.width.max(self.height));
+
+        match self.orientation {
+            Orientation::Landscape => (long, short),
+            Orientation::Portrait => (short, long),
+        }
+    }
+
+    /// Corners of the area inside the margin that the machine can reach, in
+    /// machine coordinates.
+    pub fn drawable(&self, machine_size: Point) -> (Point, Point) {
+        let (width, height) = self.size();
+
+        (
+            (self.margin, self.margin),
+            (
+                width.min(machine_size.0) - self.margin,
+                height.min(machine_size.1) - self.margin,
+            ),
+        )
+    }
+
+    /// Checks a machine position the carriage would move to, which must be
+    /// within the machine's travel, and inside the drawable area while the pen
+    /// is down.
+    pub fn check_position(
+        &self,
+        machine_size: Point,
+        (x, y): Point,
+        pen_down: bool,
+    ) -> Result<(), String> {
+        let (min, max) = if pen_down {
+            self.drawable(machine_size)
+        } else {
+            ((0.0, 0.0), machine_size)
+        };
+
+        let inside = |value: f64, min: f64, max: f64| {
+            value >= min - POSITION_TOLERANCE && value <= max + POSITION_TOLERANCE
+        };
+
+        if inside(x, min.0, max.0) && inside(y, min.1, max.1) {
+            Ok(())
+        } else if pen_down {
+            Err(format!(
+                "({:.2}, {:.2}) mm is outside the drawable area of {}",
+                x, y, self.name
+            ))
+        } else {
+            Err(format!(
+                "({:.2}, {:.2}) mm is beyond the machine's travel",
+                x, y
+            ))
+        }
+    }
+
+    /// Clamps a machine position to the drawable area.
+    pub fn clamp(&self, machine_size: Point, (x, y): Point) -> Point {
+        let (min, max) = self.drawable(machine_size);
+
+        (x.clamp(min.0, max.0), y.clamp(min.1, max.1))
+    }
+
+    /// Converts a machine position to work area coordinates.
+    pub fn position_of(&self, machine_size: Point, (x, y): Point) -> Point {
+        let (min, max) = self.drawable(machine_size);
+        let (left, top) = self.origin_sides();
+
+        (
+            if left { x - min.0 } else { max.0 - x },
+            if top { y - min.1 } else { max.1 - y },
+        )
+    }
+
+    /// Converts a movement in the work area to one in machine coordinates.
+    pub fn machine_delta(&self, (dx, dy): Point) -> Point {
+        let (left, top) = self.origin_sides();
+
+        (if left { dx } else { -dx }, if top { dy } else { -dy })
+    }
+
+    /// Whether the origin is on the left and on the top.
+    fn origin_sides(&self) -> (bool, bool) {
+        match self.origin {
+            Origin::TopLeft => (true, true),
+            Origin::TopRight => (false, true),
+            Origin::BottomLeft => (true, false),
+            Origin::BottomRight => (false, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACHINE_SIZE: Point = (300.0, 218.0);
+
+    fn a4(orientation: Orientation, origin: Origin, margin: f64) -> WorkArea {
+        WorkArea {
+            name: "a4".to_string(),
+            width: 210.0,
+            height: 297.0,
+            orientation,
+            origin,
+            margin,
+        }
+    }
+
+    #[test]
+    fn orientation_picks_the_long_side() {
+        assert_eq!(
+            a4(Orientation::Landscape, Origin::TopLeft, 0.0).size(),
+            (297.0, 210.0)
+        );
+        assert_eq!(
+            a4(Orientation::Portrait, Origin::TopLeft, 0.0).size(),
+            (210.0, 297.0)
+        );
+    }
+
+    #[test]
+    fn drawable_area_is_inside_the_margin_and_the_machine() {
+        let area = a4(Orientation::Portrait, Origin::TopLeft, 10.0);
+
+        assert_eq!(area.drawable(MACHINE_SIZE), ((10.0, 10.0), (200.0, 208.0)));
+        assert_eq!(area.clamp(MACHINE_SIZE, (0.0, 250.0)), (10.0, 208.0));
+    }
+
+    #[test]
+    fn pen_down_positions_must_be_inside_the_margin() {
+        let area = a4(Orientation::Portrait, Origin::TopLeft, 10.0);
+
+        assert!(area
+            .check_position(MACHINE_SIZE, (10.0, 208.0), true)
+            .is_ok());
+        assert!(area
+            .check_position(MACHINE_SIZE, (5.0, 50.0), true)
+            .is_err());
+        assert!(area
+            .check_position(MACHINE_SIZE, (250.0, 50.0), true)
+            .is_err());
+    }
+
+    #[test]
+    fn pen_up_positions_may_use_the_whole_machine() {
+        let area = a4(Orientation::Portrait, Origin::TopLeft, 10.0);
+
+        assert!(area.check_position(MACHINE_SIZE, (0.0, 0.0), false).is_ok());
+        assert!(area
+            .check_position(MACHINE_SIZE, (250.0, 50.0), false)
+            .is_ok());
+        assert!(area
+            .check_position(MACHINE_SIZE, (-1.0, 50.0), false)
+            .is_err());
+        assert!(area
+            .check_position(MACHINE_SIZE, (50.0, 220.0), false)
+            .is_err());
+    }
+
+    #[test]
+    fn positions_are_measured_from_the_origin_corner() {
+        let machine_position = (50.0, 30.0);
+
+        let top_left = a4(Orientation::Landscape, Origin::TopLeft, 10.0);
+        assert_eq!(
+            top_left.position_of(MACHINE_SIZE, machine_position),
+            (40.0, 20.0)
+        );
+
+        let bottom_right = a4(Orientation::Landscape, Origin::BottomRight, 10.0);
+        assert_eq!(
+            bottom_right.position_of(MACHINE_SIZE, machine_position),
+            (237.0, 170.0)
+        );
+    }
+
+    #[test]
+    fn deltas_are_flipped_away_from_the_origin() {
+        let delta = (5.0, -3.0);
+
+        assert_eq!(
+            a4(Orientation::Landscape, Origin::TopLeft, 0.0).machine_delta(delta),
+            (5.0, -3.0)
+        );
+        assert_eq!(
+            a4(Orientation::Landscape, Origin::TopRight, 0.0).machine_delta(delta),
+            (-5.0, -3.0)
+        );
+        assert_eq!(
+            a4(Orientation::Landscape, Origin::BottomLeft, 0.0).machine_delta(delta),
+            (5.0, 3.0)
+        );
+    }
+
+    #[test]
+    fn validate_rejects_margins_that_leave_no_room() {
+        let area = a4(Orientation::Landscape, Origin::TopLeft, 105.0);
+
+        assert!(area.validate(MACHINE_SIZE).is_err());
+        assert!(a4(Orientation::Landscape, Origin::TopLeft, 5.0)
+            .validate(MACHINE_SIZE)
+            .is_ok());
+    }
+
+    #[test]
+    fn custom_presets_replace_built_ins() {
+        let custom = BTreeMap::from([(
+            "a4".to_string(),
+            a4(Orientation::Landscape, Origin::BottomLeft, 5.0),
+        )]);
+
+        let presets = presets(&custom);
+
+        assert_eq!(presets["a4"].origin, Origin::BottomLeft);
+        assert_eq!(presets["a3"].orientation, Orientation::Portrait);
+    }
+}